@@ -1,15 +1,63 @@
+from ._internal import ApplierContext  # type: ignore
 from ._internal import PyEGraph  # type: ignore
 from ._internal import vars  # type: ignore
+from ._internal import dedup_rewrites  # type: ignore
 from ._internal import PyId as Id  # type: ignore
 from ._internal import PyPattern as Pattern  # type: ignore
 from ._internal import PyRewrite as Rewrite  # type: ignore
+from ._internal import PyRuleSet as RuleSet  # type: ignore
+from ._internal import PyRunResult as RunResult  # type: ignore
+from ._internal import PyRunSteps as RunSteps  # type: ignore
+from ._internal import PySearchIter as SearchIter  # type: ignore
+from ._internal import PyStopReason as StopReason  # type: ignore
 from ._internal import PyVar as Var  # type: ignore
 
 
 class EGraph(PyEGraph):
-    def extract(self, expr):
-        result = super().extract(expr)
+    def extract(self, *exprs, **kwargs):
+        result = super().extract(*exprs, **kwargs)
         if len(result) == 1:
             return result[0]
         else:
             return result
+
+    def extract_tree(self, structure, **kwargs):
+        """`structure` with each expression in it replaced by its best term.
+        Lists, dicts (their values) and plain tuples are structure, anything
+        else is an expression; the `extract` options apply to all of them."""
+        exprs = []
+
+        def collect(item):
+            if isinstance(item, dict):
+                for value in item.values():
+                    collect(value)
+            elif isinstance(item, list) or type(item) is tuple:
+                for value in item:
+                    collect(value)
+            else:
+                exprs.append(item)
+
+        collect(structure)
+        terms = iter(super().extract(*exprs, **kwargs))
+
+        def rebuild(item):
+            if isinstance(item, dict):
+                return {key: rebuild(value) for key, value in item.items()}
+            elif isinstance(item, list):
+                return [rebuild(value) for value in item]
+            elif type(item) is tuple:
+                return tuple(rebuild(value) for value in item)
+            else:
+                return next(terms)
+
+        return rebuild(structure)
+
+
+def optimize(expr, rewrites, cost=None, **limits):
+    """Add `expr` to a fresh e-graph, run `rewrites` with the given `run()`
+    limits and return `(best, cost, stop_reason)` for its cheapest term."""
+    egraph = EGraph()
+    egraph.add(expr)
+    egraph.run(rewrites, **limits)
+    best, best_cost, _ = egraph.extract_with_cost(expr, cost=cost)
+    return best, best_cost, egraph.stop_reason
@@ -1,46 +1,364 @@
-from collections.abc import Callable, Hashable, Iterable
-from typing import Dict, Optional, Protocol, Union
+import logging
+import os
+from collections.abc import Callable, Hashable, Iterable, Iterator
+from typing import Any, Dict, Literal, Optional, Protocol, TypeVar, Union, overload
 
 from typing_extensions import final
 
 _Expr = Hashable
+_Self = TypeVar("_Self", bound="EGraph")
+_CostModel = Union[str, Callable[[object], float]]
 
 @final
 class Id: ...
 
 @final
 class Var:
-    def __init__(self, name: str) -> None: ...
+    def __init__(
+        self,
+        name: str,
+        kind: Optional[Union[type, Callable[[object], bool]]] = None,
+        structural: bool = False,
+    ) -> None: ...
+    @property
+    def kind(self) -> Optional[Union[type, Callable[[object], bool]]]: ...
+    @property
+    def structural(self) -> bool: ...
 
 class _CallableApplier(Protocol):
     def __call__(self, **substiution: Dict[str, _Expr]) -> _Expr: ...
     
+@final
+class ApplierContext:
+    @property
+    def eclass(self) -> Id: ...
+    def __getitem__(self, var: Union[Var, str]) -> Id: ...
+    def data(self, target: Union[Id, Var, str]) -> object: ...
+    def add(self, expr: Union[_Expr, Id]) -> Id: ...
+    def lookup(self, expr: Union[_Expr, Id]) -> Optional[Id]: ...
+    def union(self, a: Union[_Expr, Id], b: Union[_Expr, Id]) -> bool: ...
+
 @final
 class Rewrite:
-    def __init__(self, lhs: _Expr, rhs: Union[_Expr, _CallableApplier], name: str = "") -> None: ...
+    def __init__(
+        self,
+        lhs: _Expr,
+        rhs: Union[_Expr, _CallableApplier, Callable[[ApplierContext], object]],
+        name: str = "",
+        context: bool = False,
+        priority: int = 0,
+        metadata: Optional[Dict[str, object]] = None,
+    ) -> None: ...
     @property
     def name(self) -> str: ...
+    @property
+    def priority(self) -> int: ...
+    @property
+    def metadata(self) -> Dict[str, object]: ...
+    def used_vars(self) -> list[str]: ...
+    def unused_vars(self) -> list[str]: ...
+
+@final
+class RuleSet:
+    def __init__(
+        self,
+        rewrites: Iterable[
+            Union[
+                Rewrite,
+                tuple[str, _Expr, Union[_Expr, _CallableApplier]],
+                tuple[Iterable[Union[Rewrite, tuple[str, _Expr, Union[_Expr, _CallableApplier]]]], dict[str, object]],
+            ]
+        ],
+    ) -> None: ...
+    def __len__(self) -> int: ...
+    @property
+    def rewrites(self) -> list[Rewrite]: ...
+
+@final
+class SearchIter(Iterator[tuple[Id, Dict[str, Id]]]):
+    def __next__(self) -> tuple[Id, Dict[str, Id]]: ...
+
+@final
+class RunSteps(Iterator[RunResult]):
+    def __next__(self) -> RunResult: ...
+
+@final
+class StopReason:
+    @property
+    def kind(self) -> str: ...
+    @property
+    def value(self) -> Optional[float]: ...
+    @property
+    def message(self) -> Optional[str]: ...
+    @property
+    def partial_iteration(self) -> bool: ...
+
+@final
+class RunResult:
+    @property
+    def stop_reason(self) -> StopReason: ...
+    @property
+    def iterations(self) -> int: ...
+    @property
+    def nodes(self) -> int: ...
+    @property
+    def classes(self) -> int: ...
+    @property
+    def applied(self) -> Dict[str, int]: ...
+    @property
+    def metadata(self) -> Dict[str, Dict[str, object]]: ...
+    @property
+    def search_time(self) -> float: ...
+    @property
+    def apply_time(self) -> float: ...
+    @property
+    def rebuild_time(self) -> float: ...
+    def to_json(self, path: Union[str, os.PathLike[str]]) -> None: ...
 
 @final
 class Pattern:
     def __init__(self, tree: _Expr) -> None: ...
+    def to_sexpr(self) -> str: ...
+    def __eq__(self, other: object) -> bool: ...
+    def __hash__(self) -> int: ...
 
 @final
 class EGraph:
     def __init__(
-        self, eval: Optional[Callable[[type, Iterable[_Expr]], object]] = None
+        self,
+        eval: Optional[Callable[[type, Iterable[_Expr]], object]] = None,
+        key: Optional[Callable[[object], Hashable]] = None,
+        commutative_ops: Optional[Iterable[type]] = None,
+        analysis: Optional[str] = None,
+        max_depth: Optional[int] = None,
+        max_arity: Optional[int] = None,
+        capacity: Optional[int] = None,
+        canonical_leaf: Optional[Callable[[object], Hashable]] = None,
     ) -> None: ...
+    @property
+    def commutative_ops(self) -> list[type]: ...
+    @commutative_ops.setter
+    def commutative_ops(self, ops: Iterable[type]) -> None: ...
     def add(self, expr: _Expr) -> Id: ...
-    def union(self, *exprs: _Expr) -> bool: ...
-    def equiv(self, *exprs: _Expr) -> bool: ...
+    def add_dict(self, expr: object, ops: Optional[Dict[str, type]] = None) -> Id: ...
+    def add_expr(self, expr: str, ops: Optional[Dict[str, type]] = None) -> Id: ...
+    def load_equalities(self, path: str, ops: Optional[Dict[str, type]] = None) -> int: ...
+    def from_nodes(self, nodes: Iterable[tuple[object, list[int]]]) -> Id: ...
+    def union(self, *exprs: Union[_Expr, Id]) -> bool: ...
+    def set_constant(self, expr: Union[_Expr, Id], value: object) -> Id: ...
+    def equiv(self, *exprs: Union[_Expr, Id]) -> bool: ...
+    def all_equal(self, *ids: Id) -> bool: ...
+    def find(self, id: Id) -> Id: ...
+    def resolve(self, expr: Union[_Expr, Id]) -> Id: ...
     def rebuild(self) -> int: ...
+    def check_invariants(self) -> bool: ...
+    def freeze(self) -> None: ...
+    @property
+    def frozen(self) -> bool: ...
+    def copy(self: _Self) -> _Self: ...
+    def subgraph(self: _Self, roots: list[Id]) -> tuple[_Self, Dict[Id, Id]]: ...
+    def set_eval(
+        self,
+        eval: Optional[Callable[[type, Iterable[_Expr]], object]],
+        reanalyze: bool = True,
+    ) -> None: ...
+    @overload
+    def run(
+        self,
+        rewrites: Union[RuleSet, Iterable[
+            Union[
+                Rewrite,
+                tuple[str, _Expr, Union[_Expr, _CallableApplier]],
+                tuple[Iterable[Union[Rewrite, tuple[str, _Expr, Union[_Expr, _CallableApplier]]]], dict[str, object]],
+            ]
+        ]],
+        iter_limit: int = 10,
+        time_limit: float = 10.0,
+        node_limit: int = 100000,
+        validate: bool = False,
+        hook: Optional[Callable[[int], Optional[list[object]]]] = None,
+        ordered: bool = False,
+        on_union: Optional[Callable[[Id, Id], object]] = None,
+        growth_factor: Optional[float] = None,
+        log: Optional[logging.Logger] = None,
+        log_level: int = logging.INFO,
+        on_node_limit: str = "stop",
+        until: Optional[Callable[["EGraph"], bool]] = None,
+        apply_limit: Optional[int] = None,
+        guard: Optional[Callable[[tuple[list[str], object], tuple[list[str], object]], bool]] = None,
+        *,
+        yield_each_iteration: Literal[False] = False,
+    ) -> RunResult: ...
+    @overload
     def run(
         self,
-        rewrites: list[Rewrite],
+        rewrites: Union[RuleSet, Iterable[
+            Union[
+                Rewrite,
+                tuple[str, _Expr, Union[_Expr, _CallableApplier]],
+                tuple[Iterable[Union[Rewrite, tuple[str, _Expr, Union[_Expr, _CallableApplier]]]], dict[str, object]],
+            ]
+        ]],
+        iter_limit: int = 10,
+        time_limit: float = 10.0,
+        node_limit: int = 100000,
+        validate: bool = False,
+        hook: Optional[Callable[[int], Optional[list[object]]]] = None,
+        ordered: bool = False,
+        on_union: Optional[Callable[[Id, Id], object]] = None,
+        growth_factor: Optional[float] = None,
+        log: Optional[logging.Logger] = None,
+        log_level: int = logging.INFO,
+        on_node_limit: str = "stop",
+        until: Optional[Callable[["EGraph"], bool]] = None,
+        apply_limit: Optional[int] = None,
+        guard: Optional[Callable[[tuple[list[str], object], tuple[list[str], object]], bool]] = None,
+        *,
+        yield_each_iteration: Literal[True],
+    ) -> RunSteps: ...
+    def search(
+        self,
+        pattern: _Expr,
+        limit: Optional[int] = None,
+        where: Optional[Callable[[Id], bool]] = None,
+    ) -> list[tuple[Id, Dict[str, Id]]]: ...
+    def iter_search(self, pattern: _Expr) -> SearchIter: ...
+    def count_matches(
+        self,
+        rewrites: Iterable[Union[Rewrite, tuple[str, _Expr, Union[_Expr, _CallableApplier]]]],
+    ) -> dict[str, int]: ...
+    def assert_equiv_after(
+        self,
+        lhs: _Expr,
+        rhs: _Expr,
+        rewrites: Iterable[Union[Rewrite, tuple[str, _Expr, Union[_Expr, _CallableApplier]]]],
         iter_limit: int = 10,
         time_limit: float = 10.0,
         node_limit: int = 100000,
+        in_place: bool = False,
+    ) -> tuple[bool, StopReason]: ...
+    def set_deadline(self, budget: Optional[float]) -> None: ...
+    @property
+    def remaining_time(self) -> Optional[float]: ...
+    @property
+    def stop_reason(self) -> Optional[StopReason]: ...
+    def last_run_pruned(self) -> Optional[tuple[int, Dict[Id, Id]]]: ...
+    def last_run_passes(self) -> Optional[int]: ...
+    def last_run_iterations(self) -> list[Dict[str, Any]]: ...
+    def last_run_timing(self) -> Optional[Dict[str, float]]: ...
+    def set_cost_model(
+        self, model: Union[_CostModel, Callable[[object, object], float]], with_data: bool = False
     ) -> None: ...
-    def extract(self, expr: _Expr) -> _Expr: ...
+    def extract(
+        self,
+        expr: _Expr,
+        tie_break: Optional[Callable[[_Expr, _Expr], float]] = None,
+        cost: Optional[_CostModel] = None,
+        forbid_ops: Optional[list[Union[type, str]]] = None,
+        decode: Optional[Callable[[object], object]] = None,
+        prefer_original: bool = False,
+        maximize: bool = False,
+        value_fn: Optional[Callable[[object], float]] = None,
+        max_nodes: Optional[int] = None,
+    ) -> _Expr: ...
+    def extract_tree(
+        self,
+        structure: object,
+        tie_break: Optional[Callable[[_Expr, _Expr], float]] = None,
+        cost: Optional[_CostModel] = None,
+        forbid_ops: Optional[list[Union[type, str]]] = None,
+        decode: Optional[Callable[[object], object]] = None,
+        prefer_original: bool = False,
+        maximize: bool = False,
+        value_fn: Optional[Callable[[object], float]] = None,
+        max_nodes: Optional[int] = None,
+    ) -> object: ...
+    def extract_depth(
+        self,
+        expr: _Expr,
+        tie_break: Optional[Callable[[_Expr, _Expr], float]] = None,
+        cost: Optional[_CostModel] = None,
+    ) -> int: ...
+    def extract_with_cost(
+        self,
+        expr: _Expr,
+        tie_break: Optional[Callable[[_Expr, _Expr], float]] = None,
+        cost: Optional[_CostModel] = None,
+    ) -> tuple[_Expr, float, int]: ...
+    def reinsert_best(
+        self, expr: _Expr, into: "EGraph", cost: Optional[_CostModel] = None
+    ) -> Id: ...
+    def compare_extractions(
+        self,
+        expr: _Expr,
+        cost_a: _CostModel,
+        cost_b: _CostModel,
+        tie_break: Optional[Callable[[_Expr, _Expr], float]] = None,
+    ) -> tuple[_Expr, float, _Expr, float, bool]: ...
+    def extract_sampled(
+        self,
+        expr: _Expr,
+        samples: int = 10,
+        seed: Optional[int] = None,
+        cost: Optional[_CostModel] = None,
+    ) -> _Expr: ...
+    def extract_op_bag(self, expr: _Expr, cost: Optional[_CostModel] = None) -> dict[str, int]: ...
+    def has_term_under_cost(
+        self, expr: Union[_Expr, Id], max_cost: float, cost: Optional[_CostModel] = None
+    ) -> bool: ...
+    def best_spine(self, expr: Union[_Expr, Id], cost: Optional[_CostModel] = None) -> list[str]: ...
+    def smallest_term_with_op(
+        self, op: Union[type, str], cost: Optional[_CostModel] = None
+    ) -> Optional[_Expr]: ...
+    def extract_plan(
+        self,
+        expr: _Expr,
+        tie_break: Optional[Callable[[_Expr, _Expr], float]] = None,
+        cost: Optional[_CostModel] = None,
+    ) -> dict[Id, int]: ...
+    def extract_within_size(
+        self, expr: _Expr, max_size: int, cost: Optional[_CostModel] = None
+    ) -> tuple[_Expr, bool]: ...
+    def extract_forest(
+        self, exprs: list[_Expr], cost: Optional[_CostModel] = None
+    ) -> list[_Expr]: ...
+    def operator_classes(self, names: bool = False) -> list[Union[type, str]]: ...
+    def reference_count(self, id: Id) -> int: ...
+    def insertion_stats(self) -> tuple[int, int]: ...
+    def class_provenance(self, id: Id) -> set[str]: ...
+    def class_data(self, id: Id) -> object: ...
+    def class_has_node(self, id: Id, op_name: Union[type, str], child_ids: list[Id]) -> bool: ...
+    def class_node_structure(self, id: Id) -> list[tuple[str, list[Id]]]: ...
+    def find_structural_duplicates(self, expr: _Expr) -> list[Id]: ...
+    def relabel_op(self, from_name: Union[type, str], to_name: Union[type, str]) -> tuple[int, int]: ...
+    def op_label_id(self, label: Union[Id, str]) -> int: ...
+    def op_label_name(self, id: int) -> str: ...
+    def all_terms(self, id: Id, max_size: int = 10, max_count: int = 100) -> list[_Expr]: ...
+    def enumerate_forms(
+        self, id: Id, count: int = 10, cost: Optional[_CostModel] = None
+    ) -> list[_Expr]: ...
+    def best_costs(self, cost: Optional[_CostModel] = None) -> Dict[Id, float]: ...
+    def cost_histogram(
+        self, buckets: int = 10, cost: Optional[_CostModel] = None
+    ) -> list[tuple[float, float, int]]: ...
+    def top_classes(
+        self, n: int, by: str = "size", cost: Optional[_CostModel] = None
+    ) -> list[Id]: ...
+    def orphan_classes(self, roots: list[Id], all_nodes: bool = False) -> list[Id]: ...
+    def to_egraph_serialize(self, roots: Optional[list[Id]] = None) -> str: ...
+    def diff(self, other: EGraph) -> Dict[str, list[Any]]: ...
 
-def vars(vars: str) -> tuple[Var, ...] | Var: ...
+def vars(
+    vars: str, kind: Optional[Union[type, Callable[[object], bool]]] = None
+) -> tuple[Var, ...] | Var: ...
+def dedup_rewrites(
+    rewrites: Iterable[Union[Rewrite, tuple[str, _Expr, Union[_Expr, _CallableApplier]]]],
+) -> list[Rewrite]: ...
+def optimize(
+    expr: _Expr,
+    rewrites: Iterable[Union[Rewrite, tuple[str, _Expr, Union[_Expr, _CallableApplier]]]],
+    cost: Optional[_CostModel] = None,
+    iter_limit: int = 10,
+    time_limit: float = 10.0,
+    node_limit: int = 100000,
+) -> tuple[_Expr, float, StopReason]: ...
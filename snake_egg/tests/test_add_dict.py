@@ -0,0 +1,103 @@
+import os
+import tempfile
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+def test_add_dict_with_class_op():
+    egraph = EGraph()
+    root = egraph.add_dict({"op": Add, "children": [1, {"op": Add, "children": ["x", 2]}]})
+    assert root == egraph.add(Add(1, Add("x", 2)))
+    assert egraph.extract(Add(1, Add("x", 2))) == Add(1, Add("x", 2))
+
+
+def test_add_dict_with_named_op():
+    egraph = EGraph()
+    root = egraph.add_dict({"op": "add", "children": ["x", "y"]}, ops={"add": Add})
+    assert root == egraph.add(Add("x", "y"))
+
+
+def test_add_dict_unknown_op():
+    egraph = EGraph()
+    try:
+        egraph.add_dict({"op": "sub", "children": []})
+    except ValueError:
+        pass
+    else:
+        assert False, "expected ValueError"
+
+
+def test_from_nodes():
+    egraph = EGraph()
+    root = egraph.from_nodes([("x", []), (2, []), (Add, [0, 1]), (Add, [2, 0])])
+    assert root == egraph.add(Add(Add("x", 2), "x"))
+
+
+def test_from_nodes_rejects_forward_references():
+    egraph = EGraph()
+    for nodes in [[(Add, [0, 0])], [("x", []), (Add, [0, 5])], [], [("x", []), ("y", [0])]]:
+        try:
+            egraph.from_nodes(nodes)
+        except ValueError:
+            pass
+        else:
+            assert False, nodes
+
+
+class Mul(NamedTuple):
+    x: Any
+    y: Any
+
+
+OPS = {"Add": Add, "Mul": Mul}
+
+
+def test_add_expr():
+    egraph = EGraph()
+    root = egraph.add_expr('(Add x (Mul 2 (Add 1.5 "3")))', ops=OPS)
+    assert root == egraph.add(Add("x", Mul(2, Add(1.5, "3"))))
+    assert egraph.add_expr("x") == egraph.add("x")
+    for bad in ["(Add x", "(Sub x y)", "(Add x y))", "()"]:
+        try:
+            egraph.add_expr(bad, ops=OPS)
+        except ValueError:
+            pass
+        else:
+            assert False, bad
+
+
+def test_load_equalities():
+    egraph = EGraph()
+    with tempfile.TemporaryDirectory() as tmp:
+        path = os.path.join(tmp, "equalities.txt")
+        with open(path, "w") as f:
+            f.write("# known facts\n(Mul x 2) ; (Add x x)\n\n(Add x 0) ; x\n(Add x x) ; (Mul x 2)\n")
+        assert egraph.load_equalities(path, ops=OPS) == 2
+    assert egraph.equiv(Mul("x", 2), Add("x", "x"))
+    assert egraph.equiv(Add("x", 0), "x")
+
+
+def test_load_equalities_conflicting_data():
+    def fold(op, args):
+        if isinstance(op, int):
+            return op
+        return args[0] + args[1] if op is Add else None
+
+    egraph = EGraph(eval=fold)
+    with tempfile.TemporaryDirectory() as tmp:
+        path = os.path.join(tmp, "equalities.txt")
+        with open(path, "w") as f:
+            f.write("(Add 1 2) ; 3\n(Add 1 2) ; 4\n")
+        try:
+            egraph.load_equalities(path, ops=OPS)
+        except ValueError as err:
+            assert str(err).startswith(path + ":2: ")
+        else:
+            assert False
+    egraph.add("z")
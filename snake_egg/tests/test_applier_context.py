@@ -0,0 +1,119 @@
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph, Rewrite, vars
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+class Mul(NamedTuple):
+    x: Any
+    y: Any
+
+
+a, b = vars("a b")  # type: ignore
+
+
+def test_context_applier():
+    egraph = EGraph()
+    root = egraph.add(Add("x", "x"))
+    x = egraph.add("x")
+    seen = []
+
+    def double(ctx):
+        seen.append((ctx.eclass, ctx[a], ctx["a"]))
+        assert ctx.lookup(Mul(a, 2)) is None
+        assert ctx.lookup(Add(a, a)) == ctx.eclass
+        two = ctx.add(Mul(a, 2))
+        assert ctx.lookup(Mul("x", 2)) == two
+        assert ctx.union(ctx.eclass, two)
+        assert not ctx.union(Add(a, a), two)
+
+    egraph.run([Rewrite(Add(a, a), double, name="double", context=True)], iter_limit=1)
+    assert seen == [(root, x, x)]
+    assert egraph.equiv(Add("x", "x"), Mul("x", 2))
+    assert egraph.last_run_iterations()[0]["applied"] == {"double": 1}
+
+
+def test_context_applier_return_value():
+    egraph = EGraph()
+    egraph.add(Add("x", 0))
+
+    def simplify(ctx):
+        try:
+            ctx[b]
+        except KeyError:
+            pass
+        else:
+            assert False
+        return a
+
+    egraph.run([Rewrite(Add(a, 0), simplify, context=True)])
+    assert egraph.equiv(Add("x", 0), "x")
+
+
+def test_context_is_invalid_after_callback():
+    egraph = EGraph()
+    egraph.add(Add("x", 0))
+    contexts = []
+    egraph.run([Rewrite(Add(a, 0), contexts.append, context=True)], iter_limit=1)
+    try:
+        contexts[0].add("y")
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_context_applier_exception():
+    egraph = EGraph()
+    for name in "pqr":
+        egraph.add(Add(name, 0))
+    calls = []
+
+    def missing(ctx):
+        calls.append(ctx.eclass)
+        return ctx[b]
+
+    try:
+        egraph.run([Rewrite(Add(a, 0), missing, context=True)])
+    except KeyError:
+        pass
+    else:
+        assert False
+    # no more matches are applied once one raises
+    assert len(calls) == 1
+    try:
+        Rewrite(Add(a, 0), a, context=True)
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_context_data():
+    def eval_const(op, args):
+        if isinstance(op, int):
+            return op
+        if op is Add:
+            return args[0] + args[1]
+        return None
+
+    egraph = EGraph(eval=eval_const)
+    egraph.add(Mul(Add(1, 2), "y"))
+    egraph.add(Mul(Add("x", 2), "y"))
+    seen = []
+
+    def constant_last(ctx):
+        seen.append((ctx.data(a), ctx.data(ctx.eclass), ctx.data("b")))
+        # only fires where the analysis knows a is a constant
+        value = ctx.data(a)
+        if value is not None:
+            ctx.union(ctx.eclass, Mul(b, value))
+        return None
+
+    egraph.run([Rewrite(Mul(a, b), constant_last, name="constant-last", context=True)], iter_limit=1)
+    assert sorted(seen, key=str) == [(3, None, None), (None, None, None)]
+    assert egraph.equiv(Mul(Add(1, 2), "y"), Mul("y", 3))
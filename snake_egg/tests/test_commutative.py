@@ -0,0 +1,51 @@
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph, Rewrite, vars
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+class Mul(NamedTuple):
+    x: Any
+    y: Any
+
+
+class Sub(NamedTuple):
+    x: Any
+    y: Any
+
+
+a, b, c = vars("a b c")  # type: ignore
+
+
+def test_commutative_matches_swapped_arguments():
+    egraph = EGraph(commutative_ops=[Add])
+    egraph.add(Add(0, "x"))
+    egraph.run([Rewrite(Add(a, 0), a, "add-0")])
+    assert egraph.equiv(Add(0, "x"), "x")
+
+
+def test_commutative_ops_only_for_declared_ops():
+    egraph = EGraph(commutative_ops={Add})
+    egraph.add(Sub(0, "x"))
+    egraph.run([Rewrite(Sub(a, 0), a, "sub-0")])
+    assert not egraph.equiv(Sub(0, "x"), "x")
+    assert egraph.commutative_ops == [Add]
+
+
+def test_commutative_nested():
+    egraph = EGraph()
+    egraph.commutative_ops = [Add, Mul]
+    egraph.add(Add("z", Mul(2, "y")))
+    egraph.run([Rewrite(Add(Mul(a, 2), b), Add(b, Add(a, a)), "double")])
+    assert egraph.equiv(Add("z", Mul(2, "y")), Add("z", Add("y", "y")))
+
+
+def test_commutative_off_by_default():
+    egraph = EGraph()
+    egraph.add(Add(0, "x"))
+    egraph.run([Rewrite(Add(a, 0), a, "add-0")])
+    assert not egraph.equiv(Add(0, "x"), "x")
@@ -58,3 +58,63 @@ def test_simple_3():
 test_simple_1()
 test_simple_2()
 test_simple_3()
+
+
+class Node:
+    __match_args__ = ("child",)
+
+    def __init__(self):
+        self.child = self
+
+
+def raises_value_error(add):
+    try:
+        add()
+    except ValueError:
+        return True
+    return False
+
+
+def test_cyclic_object_raises():
+    assert raises_value_error(lambda: EGraph().add(Node()))
+
+
+def test_build_limits():
+    term = "x"
+    for _ in range(20):
+        term = Add(term, 0)
+    assert raises_value_error(lambda: EGraph(max_depth=20).add(term))
+    EGraph(max_depth=21).add(term)
+    assert raises_value_error(lambda: EGraph(max_arity=3).add((1, 2, 3, 4)))
+    egraph = EGraph(max_arity=3)
+    egraph.add((1, 2, 3))
+    assert raises_value_error(lambda: egraph.copy().add((1, 2, 3, 4)))
+
+
+def test_build_limits_on_every_path():
+    deep, nested, sexpr = "x", "x", "x"
+    for _ in range(20):
+        deep = Add(deep, 0)
+        nested = {"op": Add, "children": [nested, 0]}
+        sexpr = f"(Add {sexpr} 0)"
+    assert raises_value_error(lambda: EGraph(max_depth=20).add_dict(nested))
+    EGraph(max_depth=21).add_dict(nested)
+    assert raises_value_error(lambda: EGraph(max_depth=20).add_expr(sexpr, ops={"Add": Add}))
+    EGraph(max_depth=21).add_expr(sexpr, ops={"Add": Add})
+    assert raises_value_error(lambda: EGraph(max_arity=1).add_expr("(Add x 0)", ops={"Add": Add}))
+    # terms made from analysis data and by rule callbacks are limited too
+    fold = lambda op, args: deep if op == "y" else None
+    assert raises_value_error(lambda: EGraph(eval=fold, max_depth=20).add("y"))
+    egraph = EGraph(max_depth=20)
+    egraph.add(Mul("x", 1))
+    (a,) = vars("a")
+    rule = Rewrite(Mul(a, 1), lambda a: deep, name="deepen")
+    assert raises_value_error(lambda: egraph.run([rule]))
+
+
+def test_capacity_hint():
+    hinted, plain = EGraph(capacity=1000), EGraph()
+    for egraph in [hinted, plain]:
+        egraph.add(Add("x", Add("x", 0)))
+        egraph.add(Add("x", 0))
+    assert hinted.insertion_stats() == plain.insertion_stats() == (8, 4)
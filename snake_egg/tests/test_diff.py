@@ -0,0 +1,61 @@
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph, Rewrite, vars
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+class Mul(NamedTuple):
+    x: Any
+    y: Any
+
+
+a, b = vars("a b")  # type: ignore
+
+
+def test_copy_is_independent():
+    egraph = EGraph()
+    egraph.add(Add("x", 0))
+    snapshot = egraph.copy()
+    assert isinstance(snapshot, EGraph)
+    egraph.union(Add("x", 0), "x")
+    assert egraph.equiv(Add("x", 0), "x")
+    assert not snapshot.equiv(Add("x", 0), "x")
+
+
+def test_subgraph():
+    egraph = EGraph()
+    root = egraph.add(Add("x", 0))
+    egraph.add(Mul("y", 2))
+    egraph.union(root, "x")
+    sub, ids = egraph.subgraph([root])
+    assert isinstance(sub, EGraph)
+    # the class of x and Add(x, 0), and 0
+    assert len(sub.class_ids()) == 2
+    assert set(ids) == {egraph.find(root), egraph.add(0)}
+    assert sub.add("x") == ids[egraph.find(root)]
+    assert sub.equiv(Add("x", 0), "x")
+    assert sub.extract(Add("x", 0)) == "x"
+
+
+def test_diff_after_run():
+    egraph = EGraph()
+    x = egraph.add("x")
+    root = egraph.add(Add("x", 0))
+    before = egraph.copy()
+    egraph.run([Rewrite(Add(a, 0), Mul(a, 1), "add-0"), Rewrite(Mul(a, 1), a, "mul-1")])
+
+    diff = egraph.diff(before)
+    assert diff["merged"] == [(egraph.add("x"), sorted([x, root]))]
+    assert sorted(label for _, label in diff["new_nodes"]) == ["1", "Mul"]
+    assert diff["unmatched"] == []
+
+
+def test_diff_unchanged():
+    egraph = EGraph()
+    egraph.add(Add("x", Mul("y", 2)))
+    diff = egraph.diff(egraph.copy())
+    assert diff == {"merged": [], "new_nodes": [], "unmatched": []}
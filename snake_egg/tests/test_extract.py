@@ -0,0 +1,496 @@
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph, Rewrite, vars
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+class Mul(NamedTuple):
+    x: Any
+    y: Any
+
+
+a, b = vars("a b")  # type: ignore
+
+commute = [Rewrite(Add(a, b), Add(b, a), name="commute-add")]
+
+
+def commuted_egraph(expr):
+    egraph = EGraph()
+    egraph.add(expr)
+    egraph.run(commute)
+    return egraph
+
+
+def test_ties_are_lexical_by_default():
+    for expr in [Add(1, 2), Add(2, 1)]:
+        egraph = commuted_egraph(expr)
+        assert egraph.extract(expr) == Add(1, 2)
+
+
+def test_prefer_original():
+    egraph = commuted_egraph(Mul(Add(2, 1), "x"))
+    assert egraph.extract(Mul(Add(2, 1), "x")) == Mul(Add(1, 2), "x")
+    assert egraph.extract(Mul(Add(2, 1), "x"), prefer_original=True) == Mul(Add(2, 1), "x")
+    # a term added later doesn't change which enode came first
+    egraph.add(Add(1, 2))
+    assert egraph.extract(Add(1, 2), prefer_original=True) == Add(2, 1)
+
+
+def test_tie_break_callback():
+    def larger_first(x, y):
+        return -1 if x.x > y.x else 1
+
+    egraph = commuted_egraph(Add(1, 2))
+    assert egraph.extract(Add(1, 2), tie_break=larger_first) == Add(2, 1)
+
+
+def test_all_terms():
+    egraph = commuted_egraph(Add(1, Add(2, 3)))
+    root = egraph.add(Add(1, Add(2, 3)))
+    terms = egraph.all_terms(root)
+    assert len(terms) == 4
+    assert set(terms) == {
+        Add(1, Add(2, 3)),
+        Add(1, Add(3, 2)),
+        Add(Add(2, 3), 1),
+        Add(Add(3, 2), 1),
+    }
+    assert len(egraph.all_terms(root, max_count=2)) == 2
+    assert egraph.all_terms(root, max_size=4) == []
+
+
+def test_all_terms_cyclic_class():
+    egraph = EGraph()
+    egraph.union("x", Mul("x", 1))
+    egraph.rebuild()
+    terms = egraph.all_terms(egraph.add("x"), max_size=5)
+    assert terms == ["x", Mul("x", 1), Mul(Mul("x", 1), 1)]
+
+
+def test_enumerate_forms():
+    egraph = commuted_egraph(Add(1, Add(2, 3)))
+    root = egraph.add(Add(1, Add(2, 3)))
+    # only four forms, however many are asked for
+    assert len(egraph.enumerate_forms(root, count=10)) == 4
+    egraph.union("x", Mul("x", 1))
+    x = egraph.add("x")
+    # x has forms of every size, so the count is what bounds them
+    assert egraph.enumerate_forms(x, count=4) == [
+        "x",
+        Mul("x", 1),
+        Mul(Mul("x", 1), 1),
+        Mul(Mul(Mul("x", 1), 1), 1),
+    ]
+
+
+def test_best_costs():
+    egraph = EGraph()
+    egraph.union(Add("x", Mul("y", 1)), Mul("z", 0))
+    egraph.rebuild()
+    costs = egraph.best_costs()
+    assert len(costs) == len(egraph.class_ids())
+    assert costs[egraph.add("x")] == 1.0
+    assert costs[egraph.add(Mul("y", 1))] == 3.0
+    assert costs[egraph.add(Mul("z", 0))] == 3.0
+
+
+def mul_is_expensive(op):
+    return 10.0 if op is Mul else 1.0
+
+
+def test_cost_histogram():
+    egraph = EGraph()
+    assert egraph.cost_histogram() == []
+    egraph.add(Add(Add("x", "y"), Add(Add("x", "y"), "z")))
+    # costs: x, y, z 1; Add(x, y) 3; Add(Add(x, y), z) 5; the root 9
+    assert egraph.cost_histogram(buckets=4) == [(1.0, 3.0, 3), (3.0, 5.0, 1), (5.0, 7.0, 1), (7.0, 9.0, 1)]
+    assert egraph.cost_histogram(buckets=1) == [(1.0, 9.0, 6)]
+    assert egraph.cost_histogram(buckets=2, cost=lambda op: 1.0 if op is Add else 0.0) == [
+        (0.0, 2.0, 4),
+        (2.0, 4.0, 2),
+    ]
+
+
+def test_top_classes():
+    egraph = EGraph()
+    root = egraph.add(Add(Mul("x", 2), "y"))
+    egraph.union(Mul("x", 2), Add("x", "x"))
+    double = egraph.find(egraph.add(Mul("x", 2)))
+    assert egraph.top_classes(1) == [double]
+    assert egraph.top_classes(1, by="cost") == [root]
+    assert len(egraph.top_classes(10)) == 5
+    try:
+        egraph.top_classes(1, by="depth")
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_set_cost_model():
+    egraph = EGraph()
+    egraph.union(Mul("x", 2), Add("x", "x"))
+    assert egraph.extract(Mul("x", 2)) == Add("x", "x")
+    egraph.set_cost_model(mul_is_expensive)
+    assert egraph.extract(Mul("x", 2)) == Add("x", "x")
+    assert egraph.best_costs()[egraph.add("x")] == 1.0
+    assert egraph.best_costs()[egraph.add(Mul("x", 2))] == 3.0
+
+    egraph.union(Mul("y", 2), Add("y", Add("y", 0)))
+    root = egraph.add(Add(Mul("y", 2), 1))
+    # class_enodes rebuilds children with the model too
+    assert egraph.class_enodes(root) == [Add(Add("y", Add("y", 0)), 1)]
+
+
+def test_cost_argument_overrides_model():
+    egraph = EGraph()
+    egraph.union(Mul(Mul("x", "x"), "x"), Add("x", "y"))
+    egraph.set_cost_model(mul_is_expensive)
+    assert egraph.extract(Add("x", "y")) == Add("x", "y")
+    assert egraph.extract(Add("x", "y"), cost=lambda op: 1.0) == Add("x", "y")
+    assert egraph.extract(Add("x", "y"), cost=lambda op: 10.0 if op is Add else 1.0) == Mul(
+        Mul("x", "x"), "x"
+    )
+
+
+def test_enumerate_forms_cost_order():
+    egraph = EGraph()
+    egraph.union(Mul("x", 2), Add("x", "x"))
+    root = egraph.add(Mul("x", 2))
+    assert egraph.enumerate_forms(root, cost=mul_is_expensive) == [Add("x", "x"), Mul("x", 2)]
+    assert egraph.enumerate_forms(root, cost=lambda op: 10.0 if op is Add else 1.0) == [
+        Mul("x", 2),
+        Add("x", "x"),
+    ]
+
+
+def test_cost_called_once_per_op():
+    calls = []
+
+    def cost(op):
+        calls.append(op)
+        return 10.0 if op is Mul else 1.0
+
+    egraph = EGraph()
+    term = "x"
+    for i in range(200):
+        term = Add(Mul(term, i % 2), "x")
+    egraph.add(term)
+    egraph.extract(term, cost=cost)
+    # Add, Mul, "x", 0 and 1, for 403 enodes
+    assert len(calls) == 5
+    calls.clear()
+    egraph.set_cost_model(cost)
+    egraph.to_egraph_serialize()
+    assert len(calls) == 5
+
+
+def test_depth_cost_model():
+    egraph = EGraph()
+    egraph.union(Add(Add(Add("x", "y"), "z"), "w"), Add(Add("x", "y"), Add("z", "w")))
+    egraph.set_cost_model("depth")
+    assert egraph.extract(Add(Add(Add("x", "y"), "z"), "w")) == Add(
+        Add("x", "y"), Add("z", "w")
+    )
+    assert egraph.best_costs()[egraph.add(Add("z", "w"))] == 2.0
+
+
+def test_zero_cost_cycle():
+    class W(NamedTuple):
+        x: Any
+
+    class V(NamedTuple):
+        x: Any
+
+    egraph = EGraph()
+    egraph.union("a", W(V("a")))
+    # W(V(...)) costs as little as "a", but only by looping back to it
+    free = lambda op: 0.0 if op in (W, V) else 1.0
+    assert egraph.extract("a", cost=free) == "a"
+    assert egraph.extract(V("a"), cost=free) == V("a")
+
+
+def test_bad_cost_model():
+    egraph = EGraph()
+    egraph.add("x")
+    try:
+        egraph.set_cost_model("nodes")
+    except ValueError:
+        pass
+    else:
+        assert False
+    try:
+        egraph.extract("x", cost=lambda op: -1.0)
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_extract_tree():
+    egraph = EGraph()
+    egraph.union(Add("x", 0), "x")
+    egraph.union(Mul("y", 1), "y")
+    structure = {"pair": (Add("x", 0), [Mul("y", 1), "z"]), "single": Add(Add("x", 0), 1)}
+    assert egraph.extract_tree(structure) == {"pair": ("x", ["y", "z"]), "single": Add("x", 1)}
+    assert egraph.extract_tree([]) == []
+    assert egraph.extract_tree(Mul("y", 1)) == "y"
+    assert egraph.extract_tree([Add("x", 0)], decode=str.upper) == ["X"]
+
+
+def test_extract_depth():
+    egraph = EGraph()
+    egraph.add("x")
+    assert egraph.extract_depth("x") == 1
+    egraph.union(Add(Add(Add("x", "y"), "z"), "w"), Add(Add("x", "y"), Add("z", "w")))
+    # both terms have size 7; the lexical tie-break picks the nested one
+    assert egraph.extract_depth(Add(Add("x", "y"), Add("z", "w"))) == 4
+    assert egraph.extract_depth(Add(Add("x", "y"), Add("z", "w")), cost="depth") == 3
+
+
+def test_extract_with_cost():
+    egraph = EGraph()
+    egraph.union(Mul("x", 2), Add("x", "x"))
+    assert egraph.extract_with_cost(Mul("x", 2)) == (Add("x", "x"), 3.0, 2)
+    term, cost, depth = egraph.extract_with_cost(Mul("x", 2), cost=lambda op: 2.0)
+    assert (cost, depth) == (6.0, 2)
+
+
+def test_reinsert_best():
+    egraph = EGraph()
+    egraph.union(Mul("x", 2), Add("x", "x"))
+    other = EGraph()
+    root = egraph.reinsert_best(Mul(Mul("x", 2), Mul("x", 2)), into=other)
+    assert root == other.add(Mul(Add("x", "x"), Add("x", "x")))
+    assert len(other.class_ids()) == 3
+    assert other.extract(root) == Mul(Add("x", "x"), Add("x", "x"))
+    try:
+        egraph.reinsert_best("x", into=egraph)
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_compare_extractions():
+    egraph = EGraph()
+    egraph.union(Mul("x", 2), Add("x", "x"))
+    no_add = lambda op: 10.0 if op is Add else 1.0
+    assert egraph.compare_extractions(Mul("x", 2), "size", no_add) == (
+        Add("x", "x"),
+        3.0,
+        Mul("x", 2),
+        3.0,
+        True,
+    )
+    assert egraph.compare_extractions(Mul("x", 2), "size", "depth")[4] is False
+
+
+def test_extract_plan():
+    egraph = EGraph()
+    egraph.union(Mul("x", 2), Add("x", "x"))
+    root = egraph.add(Mul("x", 2))
+    x = egraph.add("x")
+    plan = egraph.extract_plan(Mul("x", 2))
+    # the literal 2 isn't part of the best term
+    assert set(plan) == {root, x}
+    assert egraph.class_enodes(root)[plan[root]] == Add("x", "x")
+    assert egraph.class_enodes(x)[plan[x]] == "x"
+
+
+def test_extract_op_bag():
+    egraph = EGraph()
+    egraph.union(Mul(Add("x", 1), 2), Add(Add("x", 1), Add("x", 1)))
+    assert egraph.extract_op_bag(Mul(Add("x", 1), 2)) == {"Mul": 1, "Add": 1, "x": 1, "1": 1, "2": 1}
+    # the shared subterm counts at both of its occurrences
+    assert egraph.extract_op_bag(Mul(Add("x", 1), 2), cost=mul_is_expensive) == {
+        "Add": 3,
+        "x": 2,
+        "1": 2,
+    }
+
+
+def test_best_spine():
+    egraph = EGraph()
+    root = egraph.add(Add(Mul(Add("x", "y"), "z"), Mul("w", 0)))
+    egraph.union(Mul("w", 0), 0)
+    # Add's cheaper child is 0, Mul's cheaper child "z"
+    assert egraph.best_spine(root) == ["Add", "0"]
+    assert egraph.best_spine(Mul(Add("x", "y"), "z")) == ["Mul", "z"]
+    assert egraph.best_spine(Add("x", "y")) == ["Add", "x"]
+
+
+def test_has_term_under_cost():
+    egraph = EGraph()
+    root = egraph.add(Mul(Add("x", "y"), Add("x", "y")))
+    assert not egraph.has_term_under_cost(root, 6)
+    assert egraph.has_term_under_cost(root, 7)
+    egraph.union(Add("x", "y"), "z")
+    assert egraph.has_term_under_cost(root, 3)
+    assert not egraph.has_term_under_cost(root, 2.5)
+    assert egraph.has_term_under_cost(root, 2, cost="depth")
+    assert not egraph.has_term_under_cost(root, 1, cost="depth")
+
+
+def test_extract_max_nodes():
+    egraph = EGraph()
+    term = "x"
+    for _ in range(12):
+        term = Add(term, term)
+    root = egraph.add(term)
+    # 2**13 - 1 enodes as a tree, from 13 e-classes
+    try:
+        egraph.extract(root, max_nodes=1000)
+    except ValueError:
+        pass
+    else:
+        assert False
+    try:
+        egraph.class_enodes(root, max_nodes=1000)
+    except ValueError:
+        pass
+    else:
+        assert False
+    small = egraph.add(Add("x", "x"))
+    assert egraph.extract(small, max_nodes=3) == Add("x", "x")
+    assert egraph.class_enodes(small, max_nodes=3) == [Add("x", "x")]
+
+
+def test_extract_within_size():
+    egraph = EGraph()
+    spread = Add(Add("x", "y"), Add("y", "x"))
+    root = egraph.add(spread)
+    egraph.union(root, Mul("x", "y"))
+    egraph.set_cost_model(lambda op: 10.0 if op is Mul else 1.0)
+    assert egraph.extract(root) == spread
+    assert egraph.extract_within_size(root, max_size=7) == (spread, True)
+    assert egraph.extract_within_size(root, max_size=6) == (Mul("x", "y"), True)
+    assert egraph.extract_within_size(root, max_size=2) == (Mul("x", "y"), False)
+    # far more room than any term needs
+    assert egraph.extract_within_size(root, max_size=10**15) == (spread, True)
+
+
+def test_extract_forest():
+    egraph = EGraph()
+    shared = Add("u", "w")
+    egraph.union(Add("x", "y"), Mul(shared, shared))
+    roots = [Add("x", "y"), shared]
+    assert [egraph.extract(root) for root in roots] == roots
+    # Reusing `shared` costs one Mul instead of Add, x and y.
+    assert egraph.extract_forest(roots) == [Mul(shared, shared), shared]
+    assert egraph.extract_forest([Add("x", "y")]) == [Add("x", "y")]
+    try:
+        egraph.extract_forest(roots, cost="depth")
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_smallest_term_with_op():
+    egraph = EGraph()
+    egraph.add(Add(Mul(Add("x", "y"), 3), Mul("z", 2)))
+    egraph.union("z", Add("y", 1))
+    # the cheaper of the two Mul terms
+    assert egraph.smallest_term_with_op(Mul) == Mul("z", 2)
+    # equally cheap terms are ordered by repr
+    assert egraph.smallest_term_with_op("Add") == Add("x", "y")
+    assert egraph.smallest_term_with_op("Add", cost=lambda op: 5.0 if op == "x" else 1.0) == Add("y", 1)
+    assert egraph.smallest_term_with_op("Sub") is None
+
+
+def test_extract_sampled():
+    egraph = commuted_egraph(Add(1, Add(2, 3)))
+    terms = set(egraph.all_terms(egraph.add(Add(1, Add(2, 3)))))
+    sampled = egraph.extract_sampled(Add(1, Add(2, 3)), seed=3)
+    assert sampled in terms
+    assert egraph.extract_sampled(Add(1, Add(2, 3)), seed=3) == sampled
+    assert egraph.extract_sampled(Add(1, Add(2, 3)), samples=1) in terms
+
+    egraph = EGraph()
+    egraph.union(Mul("x", 2), Add(Add("x", "x"), 0))
+    egraph.union("x", Mul("x", 1))
+    egraph.rebuild()
+    assert egraph.extract_sampled("x") == "x"
+    assert egraph.extract_sampled(Mul("x", 2), cost=mul_is_expensive) == Add(Add("x", "x"), 0)
+
+def test_forbid_ops():
+    egraph = EGraph()
+    egraph.union(Mul("x", 2), Add(Add("x", "x"), 0))
+    assert egraph.extract(Add("x", "x")) == Add("x", "x")
+    assert egraph.extract(Mul("x", 2)) == Mul("x", 2)
+    assert egraph.extract(Mul("x", 2), forbid_ops=[Mul]) == Add(Add("x", "x"), 0)
+    assert egraph.extract(Mul("x", 2), forbid_ops=["Mul"]) == Add(Add("x", "x"), 0)
+
+
+def test_forbid_ops_without_alternative():
+    egraph = EGraph()
+    egraph.add(Add("x", Mul("y", 2)))
+    try:
+        egraph.extract(Add("x", Mul("y", 2)), forbid_ops=[Mul])
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_extract_maximize():
+    egraph = EGraph()
+    egraph.union(Mul("x", 2), Add("x", "x"))
+    egraph.union("x", Mul("x", 1))
+    egraph.union(2, Add(1, 1))
+
+    def accelerated(op):
+        return 5 if op is Mul else 1
+
+    best = egraph.extract(Add("x", "x"), maximize=True, value_fn=accelerated)
+    assert best == Mul("x", Add(1, 1))
+    # the largest term, but each class's term can't contain the class itself
+    assert egraph.extract("x", maximize=True) == "x"
+    assert egraph.extract(2, maximize=True) == Add(1, 1)
+    assert egraph.extract(2) == 2
+
+
+def test_extract_value_fn_needs_maximize():
+    egraph = EGraph()
+    egraph.add("x")
+    try:
+        egraph.extract("x", value_fn=lambda op: 1)
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_cost_model_with_data():
+    def eval(op, args):
+        if op is Add and all(isinstance(arg, int) for arg in args):
+            return args[0] + args[1]
+        if isinstance(op, int):
+            return op
+        return None
+
+    egraph = EGraph(eval)
+    egraph.add(Mul("x", Add(1, 2)))
+    seen = []
+
+    def cost(op, data):
+        seen.append(data)
+        return 0.0 if data is not None else 1.0
+
+    egraph.set_cost_model(cost, with_data=True)
+    assert egraph.extract_with_cost(Mul("x", Add(1, 2)))[1] == 2.0
+    assert 3 in seen
+
+
+def test_decode_leaves():
+    names = {0: "x", 1: "y"}
+    egraph = EGraph()
+    egraph.add(Add(0, Mul(1, 0)))
+    assert egraph.extract(Add(0, Mul(1, 0)), decode=names.get) == Add("x", Mul("y", "x"))
+    assert egraph.extract(Add(0, Mul(1, 0))) == Add(0, Mul(1, 0))
+    assert egraph.class_enodes(egraph.add(Mul(1, 0)), decode=names.get) == [Mul("y", "x")]
@@ -0,0 +1,135 @@
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph, vars
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+def test_equal_leaves_share_a_class():
+    # two equal but distinct objects intern to the same leaf
+    big_a = int("12345678901234567890")
+    big_b = int("12345678901234567890")
+    assert big_a is not big_b
+
+    egraph = EGraph()
+    assert egraph.add(big_a) == egraph.add(big_b)
+
+
+def test_identical_ops_hash_cons():
+    egraph = EGraph()
+    term = "x"
+    for _ in range(100):
+        term = Add(Add("x", "y"), term)
+    egraph.add(term)
+    egraph.rebuild()
+    # x, y, Add(x, y) and one class per level of the spine
+    assert len(egraph.class_ids()) == 3 + 100
+
+
+def test_leaf_key():
+    def case_insensitive(leaf):
+        return leaf.lower() if isinstance(leaf, str) else leaf
+
+    egraph = EGraph(key=case_insensitive)
+    assert egraph.add("X") == egraph.add("x")
+    assert egraph.add(Add("X", 1)) == egraph.add(Add("x", 1))
+    # the first object added under a key is the one that gets extracted
+    assert egraph.extract("x") == "X"
+
+    plain = EGraph()
+    assert plain.add("X") != plain.add("x")
+
+
+def test_callable_leaf_uses_key():
+    def case_insensitive(leaf):
+        return leaf.lower() if isinstance(leaf, str) else leaf
+
+    egraph = EGraph(key=case_insensitive)
+    x, y = vars("x y")
+    term = egraph.add(Add("a", "b"))
+    # the returned string is a leaf enode, interned under the key
+    egraph.run([("concat", Add(x, y), lambda x, y: (x + y).upper())], iter_limit=1)
+    assert egraph.add("ab") == term
+    assert egraph.extract(term) == "AB"
+
+
+
+def test_key_on_every_leaf_path():
+    def case_insensitive(leaf):
+        return leaf.lower() if isinstance(leaf, str) else leaf
+
+    egraph = EGraph(key=case_insensitive)
+    x = egraph.add("x")
+    assert egraph.add_dict({"op": Add, "children": ["X", 1]}) == egraph.add(Add("x", 1))
+    (a,) = vars("a")
+    # a leaf written in a rule's right-hand side is interned like any other
+    term = egraph.add(Add("y", 0))
+    egraph.run([("to-x", Add(a, 0), Add("X", a))], iter_limit=1)
+    assert egraph.equiv(term, Add(x, "y"))
+    assert len(egraph.class_ids()) == 6
+
+
+def test_raising_key():
+    def no_floats(leaf):
+        if isinstance(leaf, float):
+            raise ValueError("no floats")
+        return leaf
+
+    egraph = EGraph(key=no_floats)
+    egraph.add(Add("x", 1))
+    try:
+        egraph.add(Add("x", 1.5))
+    except ValueError as e:
+        assert str(e) == "no floats"
+    else:
+        assert False
+    (a,) = vars("a")
+    try:
+        egraph.run([("halve", Add(a, 1), Add(a, 0.5))])
+    except ValueError as e:
+        assert str(e) == "no floats"
+    else:
+        assert False
+
+
+class Attr:
+    def __init__(self, name):
+        self.name = name
+
+
+def test_canonical_leaf():
+    def by_name(leaf):
+        return f"attr:{leaf.name}" if isinstance(leaf, Attr) else leaf
+
+    egraph = EGraph(canonical_leaf=by_name)
+    assert egraph.add(Add(Attr("w"), 1)) == egraph.add(Add(Attr("w"), 1))
+    # the representative is what's stored, rather than the first object seen
+    assert egraph.extract(Attr("w")) == "attr:w"
+    assert egraph.copy().add(Attr("w")) == egraph.add(Attr("w"))
+
+    plain = EGraph()
+    assert plain.add(Attr("w")) != plain.add(Attr("w"))
+
+    (a,) = vars("a")
+    # leaves added by a rule's right-hand side or callback get mapped too
+    root = egraph.add(Add("v", 0))
+    egraph.run([("attr", Add(a, 0), Add(a, Attr("w")))], iter_limit=1)
+    egraph.run([("attr2", Add(a, 1), lambda a: Attr("w"))], iter_limit=1)
+    assert egraph.equiv(root, Add("v", "attr:w"))
+    assert egraph.equiv(Add(Attr("w"), 1), "attr:w")
+
+    def no_floats(leaf):
+        if isinstance(leaf, float):
+            raise ValueError("no floats")
+        return leaf
+
+    strict = EGraph(canonical_leaf=no_floats)
+    try:
+        strict.add(Add("x", 1.5))
+    except ValueError as e:
+        assert str(e) == "no floats"
+    else:
+        assert False
@@ -0,0 +1,214 @@
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph, vars
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+class Neg(NamedTuple):
+    x: Any
+
+
+def test_operator_classes():
+    egraph = EGraph()
+    assert egraph.operator_classes() == []
+    egraph.add(Neg(Add("x", Add(1, 2))))
+    assert egraph.operator_classes() == [Add, Neg]
+    assert egraph.operator_classes(names=True) == ["Add", "Neg"]
+
+
+def test_reference_count():
+    egraph = EGraph()
+    x = egraph.add("x")
+    root = egraph.add(Add(Neg("x"), Add("x", "x")))
+    assert egraph.reference_count(x) == 2
+    assert egraph.reference_count(root) == 0
+    egraph.union(Neg("x"), Add("x", "x"))
+    egraph.rebuild()
+    assert egraph.reference_count(egraph.add(Neg("x"))) == 1
+
+
+def test_op_label_ids():
+    egraph = EGraph()
+    root = egraph.add(Add("x", Neg("x")))
+    add = egraph.op_label_id(root)
+    assert add == egraph.op_label_id("Add")
+    neg = egraph.op_label_id(egraph.add(Neg("x")))
+    assert neg != add
+    assert egraph.op_label_name(add) == "Add"
+    assert egraph.op_label_name(neg) == "Neg"
+    assert egraph.copy().op_label_id("Neg") == neg
+
+    egraph.union(root, "x")
+    egraph.rebuild()
+    # the class now holds both "Add" and "x"
+    try:
+        egraph.op_label_id(root)
+    except ValueError:
+        pass
+    else:
+        assert False
+    try:
+        egraph.op_label_name(17)
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_insertion_stats():
+    egraph = EGraph()
+    assert egraph.insertion_stats() == (0, 0)
+    egraph.add(Add("x", Neg("x")))
+    assert egraph.insertion_stats() == (4, 3)
+    egraph.add(Neg("x"))
+    assert egraph.insertion_stats() == (6, 3)
+    x = egraph.add("x")
+    egraph.add(Add(x, 1))
+    assert egraph.insertion_stats() == (9, 5)
+    assert egraph.copy().insertion_stats() == (9, 5)
+
+
+def test_class_provenance():
+    egraph = EGraph()
+    root = egraph.add(Add("x", Neg(Neg("x"))))
+    assert egraph.class_provenance(root) == set()
+    x = vars("x")[0]
+    egraph.run(
+        [
+            ("comm", Add(x, Neg(Neg(x))), Add(Neg(Neg(x)), x)),
+            ("double-neg", Neg(Neg(x)), x),
+            ("fold", Add(x, Neg(Neg(x))), lambda x: Add(x, x)),
+        ]
+    )
+    assert egraph.class_provenance(root) == {"comm", "fold"}
+    # double-neg only unioned existing classes
+    assert egraph.class_provenance(egraph.add("x")) == set()
+
+
+class Plus(NamedTuple):
+    x: Any
+    y: Any
+
+
+def test_relabel_op():
+    egraph = EGraph()
+    root = egraph.add(Neg(Add("x", "y")))
+    egraph.add(Plus("x", "y"))
+    egraph.add(Neg(Plus("x", "y")))
+    assert egraph.relabel_op("Add", "Plus") == (1, 2)
+    assert egraph.equiv(root, Neg(Plus("x", "y")))
+    assert egraph.operator_classes() == [Add, Neg, Plus]
+    assert egraph.relabel_op(Plus, Add) == (1, 0)
+    # sized like the same graph with the two operators unioned by hand
+    same = EGraph()
+    same.add(Neg(Add("x", "y")))
+    same.add(Neg(Plus("x", "y")))
+    same.union(Add("x", "y"), Plus("x", "y"))
+    for graph in (egraph, same):
+        graph.run([], iter_limit=1)
+    size = same.last_run_iterations()[0]["egraph_nodes"]
+    assert egraph.last_run_iterations()[0]["egraph_nodes"] == size
+    assert egraph.check_invariants()
+    try:
+        egraph.relabel_op("Add", "Mul")
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_class_has_node():
+    egraph = EGraph()
+    x, y = egraph.add("x"), egraph.add("y")
+    root = egraph.add(Add("x", "y"))
+    assert egraph.class_has_node(root, Add, [x, y])
+    assert egraph.class_has_node(root, "Add", [x, y])
+    assert not egraph.class_has_node(root, Add, [y, x])
+    assert not egraph.class_has_node(root, Neg, [x])
+    # children are compared by e-class
+    egraph.union("y", Neg("x"))
+    assert egraph.class_has_node(root, Add, [x, egraph.add(Neg("x"))])
+
+
+def test_class_node_structure():
+    egraph = EGraph()
+    x, y = egraph.add("x"), egraph.add("y")
+    root = egraph.add(Add("x", "y"))
+    egraph.union(root, Neg("x"))
+    assert sorted(egraph.class_node_structure(root)) == [("Add", [x, y]), ("Neg", [x])]
+    assert egraph.class_node_structure(x) == [("x", [])]
+    egraph.union("x", "y")
+    assert ("Add", [egraph.find(x), egraph.find(x)]) in egraph.class_node_structure(root)
+
+
+def test_find_structural_duplicates():
+    egraph = EGraph()
+    ints = egraph.add(Neg(Add("x", 1)))
+    strs = egraph.add(Neg(Add("x", "1")))
+    egraph.add(Neg(Add("x", 2)))
+    assert egraph.find_structural_duplicates(Neg(Add("x", 1))) == [ints, strs]
+    a = vars("a")[0]
+    assert len(egraph.find_structural_duplicates(Neg(Add("x", a)))) == 3
+    before = egraph.insertion_stats()
+    assert egraph.find_structural_duplicates(Neg("y")) == []
+    assert egraph.insertion_stats() == before
+
+
+def test_freeze():
+    a = vars("a")[0]
+    egraph = EGraph()
+    root = egraph.add(Neg(Add("x", 0)))
+    egraph.freeze()
+    assert egraph.frozen
+    for mutate in [
+        lambda: egraph.add("y"),
+        lambda: egraph.union(Add("x", 0), "x"),
+        lambda: egraph.run([("add-0", Add(a, 0), a)]),
+        lambda: egraph.set_eval(None),
+    ]:
+        try:
+            mutate()
+        except RuntimeError as e:
+            assert str(e) == "e-graph is frozen"
+        else:
+            assert False
+    assert egraph.extract(root) == Neg(Add("x", 0))
+    assert len(egraph.search(Add(a, 0))) == 1
+    copy = egraph.copy()
+    assert not copy.frozen
+    copy.union(Add("x", 0), "x")
+    assert not egraph.equiv(Add("x", 0), "x")
+    # queries look expressions up instead of adding them
+    classes = len(egraph.class_ids())
+    assert egraph.extract(Add("x", 0)) == Add("x", 0)
+    for query in [
+        lambda: egraph.equiv(Add("x", 1), "x"),
+        lambda: egraph.extract(Neg("y")),
+        lambda: egraph.resolve("y"),
+    ]:
+        try:
+            query()
+        except ValueError:
+            pass
+        else:
+            assert False
+    assert len(egraph.class_ids()) == classes
+
+
+def test_freeze_raises_merge_errors():
+    egraph = EGraph(analysis="const_int")
+    egraph.union(Neg("x"), 1)
+    egraph.union(Neg("y"), 2)
+    # Neg("x") and Neg("y") only merge, with unequal data, when rebuilding
+    egraph.union("x", "y")
+    try:
+        egraph.freeze()
+    except ValueError:
+        pass
+    else:
+        assert False
+    assert not egraph.frozen
@@ -0,0 +1,34 @@
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph, Rewrite, vars
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+class Neg(NamedTuple):
+    x: Any
+
+
+a, b = vars("a b")  # type: ignore
+
+
+def test_applier_returns_several_unions():
+    egraph = EGraph()
+    egraph.add(Add("x", "y"))
+    right = egraph.add("r")
+    # the matched class equals both orders; `a` is also unioned with an Id
+    rule = Rewrite(Add(a, b), lambda a, b: [Add(b, a), (Neg(a), right)], name="expand")
+    egraph.run([rule], iter_limit=1)
+    assert egraph.equiv(Add("x", "y"), Add("y", "x"))
+    assert egraph.equiv(Neg("x"), "r")
+    assert not egraph.equiv(Neg("y"), "r")
+
+
+def test_applier_single_term_still_supported():
+    egraph = EGraph()
+    egraph.add(Add("x", "y"))
+    egraph.run([Rewrite(Add(a, b), lambda a, b: Add(b, a), name="commute")])
+    assert egraph.equiv(Add("x", "y"), Add("y", "x"))
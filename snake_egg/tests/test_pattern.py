@@ -0,0 +1,230 @@
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph, Pattern, Rewrite, Var, dedup_rewrites, vars
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+class Mul(NamedTuple):
+    x: Any
+    y: Any
+
+
+a, b, x, y = vars("a b x y")  # type: ignore
+
+
+def test_to_sexpr():
+    assert Pattern(Add(a, Mul(b, 0))).to_sexpr() == "(Add ?a (Mul ?b 0))"
+    assert Pattern(a).to_sexpr() == "?a"
+    assert Pattern("x").to_sexpr() == "x"
+
+
+def test_pattern_objects_in_rewrites():
+    rule = Rewrite(Pattern(Add(a, 0)), Pattern(a), name="add-0")
+    egraph = EGraph()
+    egraph.add(Add("x", 0))
+    egraph.run([rule])
+    assert egraph.extract(Add("x", 0)) == "x"
+
+
+def test_alpha_equivalent_patterns_are_equal():
+    assert Pattern(Add(a, b)) == Pattern(Add(x, y))
+    assert Pattern(Add(a, Mul(b, a))) == Pattern(Add(y, Mul(x, y)))
+    assert Pattern(Add(a, b)) != Pattern(Add(a, a))
+    assert Pattern(Add(a, b)) != Pattern(Mul(a, b))
+    assert Pattern(Add(a, 0)) != Pattern(Add(a, 1))
+    assert Pattern(Add(a, b)) != Add(a, b)
+    assert len({Pattern(Add(a, b)), Pattern(Add(b, a)), Pattern(Add(x, x))}) == 2
+
+
+def test_search():
+    egraph = EGraph()
+    root = egraph.add(Add(Add("x", 0), Add("y", 0)))
+    x = egraph.add("x")
+    matches = egraph.search(Add(a, 0))
+    assert len(matches) == 2
+    assert (egraph.add(Add("x", 0)), {"a": x}) in matches
+    assert egraph.search(Add(a, 0), limit=1)[0] in matches
+    assert egraph.search(Add(Add(a, 0), b)) == [(root, {"a": x, "b": egraph.add(Add("y", 0))})]
+    assert egraph.search(Mul(a, b)) == []
+
+
+def test_search_where():
+    egraph = EGraph()
+    egraph.add(Add(Add("x", 0), Add("y", 0)))
+    inner = egraph.add(Add("y", 0))
+    assert egraph.search(Add(a, 0), where=lambda id: id == inner) == [(inner, {"a": egraph.add("y")})]
+    assert egraph.search(Add(a, 0), where=lambda id: False) == []
+    assert len(egraph.search(Add(a, b), limit=2, where=lambda id: True)) == 2
+
+
+def test_iter_search():
+    egraph = EGraph()
+    term = "x"
+    for i in range(50):
+        term = Add(term, 0)
+    egraph.add(term)
+    matches = egraph.iter_search(Add(a, 0))
+    first = next(matches)
+    assert first in egraph.search(Add(a, 0))
+    assert len(list(matches)) == 49
+    assert next(matches, None) is None
+
+    def as_set(matches):
+        return {(id, bindings["a"]) for id, bindings in matches}
+
+    assert as_set(egraph.iter_search(Add(a, 0))) == as_set(egraph.search(Add(a, 0)))
+
+
+def test_iter_search_after_union():
+    egraph = EGraph()
+    egraph.add(Add("x", 0))
+    egraph.add(Add("y", 0))
+    matches = egraph.iter_search(Add(a, 0))
+    # the merged classes are searched once, as their union
+    egraph.union(Add("x", 0), Add("y", 0))
+    assert len(list(matches)) == 2
+
+
+def test_search_commutative():
+    egraph = EGraph(commutative_ops=[Add])
+    egraph.add(Add(0, "x"))
+    assert egraph.search(Pattern(Add(a, 0))) == [(egraph.add(Add(0, "x")), {"a": egraph.add("x")})]
+
+
+def test_count_matches():
+    egraph = EGraph()
+    egraph.add(Add(Add("x", 0), Mul("y", 1)))
+    rules = [
+        ("add-0", Add(a, 0), a),
+        ("mul-1", Mul(a, 1), a),
+        ("add-comm", Add(a, b), Add(b, a)),
+        Rewrite(Mul(a, 0), 0, name="mul-0"),
+    ]
+    counts = egraph.count_matches(rules)
+    assert counts == {"add-0": 1, "mul-1": 1, "add-comm": 2, "mul-0": 0}
+    # nothing was applied
+    assert not egraph.equiv(Add("x", 0), "x")
+    assert egraph.count_matches(rules) == counts
+
+
+class Const(NamedTuple):
+    value: Any
+
+
+def test_kinded_vars():
+    egraph = EGraph(commutative_ops=[Add])
+    egraph.add(Add(Const(1), "x"))
+    egraph.add(Add("y", 2))
+    c = Var("c", kind=Const)
+    (n,) = vars("n", kind=int)
+    assert c.kind is Const and a.kind is None
+    assert len(egraph.search(Add(a, b))) == 4
+    assert egraph.search(Add(c, b)) == [
+        (egraph.add(Add(Const(1), "x")), {"c": egraph.add(Const(1)), "b": egraph.add("x")})
+    ]
+    assert egraph.search(Add(a, n)) == [
+        (egraph.add(Add("y", 2)), {"a": egraph.add("y"), "n": egraph.add(2)})
+    ]
+    # commutative variants are filtered too
+    assert len(egraph.search(Add(n, a))) == 1
+    assert egraph.count_matches([("fold", Add(c, n), 0)]) == {"fold": 0}
+
+    egraph = EGraph()
+    egraph.add(Add("x", Const(1)))
+    egraph.add(Add("y", 2))
+    egraph.run([("const-left", Add(a, c), Add(c, a))])
+    assert egraph.equiv(Add("x", Const(1)), Add(Const(1), "x"))
+    assert not egraph.equiv(Add("y", 2), Add(2, "y"))
+
+
+def test_predicate_vars():
+    egraph = EGraph()
+    egraph.add(Add("x", 2.5))
+    egraph.add(Add("y", -1.0))
+    egraph.add(Add("z", 3))
+    positive = Var("p", kind=lambda op: isinstance(op, float) and op > 0)
+    assert egraph.search(Add(a, positive)) == [
+        (egraph.add(Add("x", 2.5)), {"a": egraph.add("x"), "p": egraph.add(2.5)})
+    ]
+    (named,) = vars("o", kind=lambda op: op is Add)
+    assert len(egraph.search(Mul(named, a))) == 0
+    egraph.add(Mul(Add("x", 2.5), 1))
+    assert len(egraph.search(Mul(named, a))) == 1
+    try:
+        Var("v", kind=3)
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_raising_predicate_var():
+    calls = []
+
+    def no_strings(op):
+        calls.append(op)
+        if isinstance(op, str):
+            raise TypeError("no strings")
+        return True
+
+    egraph = EGraph()
+    for name in "pqr":
+        egraph.add(Add(name, 0))
+    v = Var("v", kind=no_strings)
+    for search in [
+        lambda: egraph.search(Add(v, 0)),
+        lambda: list(egraph.iter_search(Add(v, 0))),
+        lambda: egraph.run([Rewrite(Add(v, 0), v)]),
+    ]:
+        calls.clear()
+        try:
+            search()
+        except TypeError as e:
+            assert str(e) == "no strings"
+        else:
+            assert False
+        # the search stops at the first exception
+        assert len(calls) == 1
+
+
+def test_structural_vars():
+    egraph = EGraph()
+    # 1 and "1" print alike but aren't equal, so these aren't one e-class
+    root = egraph.add(Add(Mul("x", 1), Mul("x", "1")))
+    assert len(egraph.search(Add(x, x))) == 0
+    s = Var("s", structural=True)
+    assert s.structural and not x.structural
+    assert egraph.search(Add(s, s)) == [
+        (root, {"s": egraph.add(Mul("x", 1)), "s#2": egraph.add(Mul("x", "1"))})
+    ]
+    assert len(egraph.search(Add(s, Mul("x", 2)))) == 0
+    assert Rewrite(Add(s, s), lambda s: Mul(2, s)).used_vars() == ["s"]
+    egraph.add(Add("y", "z"))
+    assert [id for id, _ in egraph.search(Add(s, s))] == [root]
+    egraph.run([Rewrite(Add(s, s), lambda s: Mul(2, s), name="double")])
+    assert egraph.equiv(root, Mul(2, Mul("x", 1)))
+
+
+def test_dedup_rewrites():
+    x, y, a, b = vars("x y a b")
+
+    def swap(x, y):
+        return Add(y, x)
+
+    rules = dedup_rewrites(
+        [
+            ("comm", Add(x, y), Add(y, x)),
+            ("comm2", Add(a, b), Add(b, a)),
+            # same sides, but the rhs doesn't swap
+            ("same", Add(a, b), Add(a, b)),
+            ("call", Add(x, y), swap),
+            Rewrite(Add(x, y), swap, name="call2"),
+            # the callable is passed `a` and `b`, not `x` and `y`
+            ("call3", Add(a, b), swap),
+        ]
+    )
+    assert [rule.name for rule in rules] == ["comm", "same", "call", "call3"]
@@ -0,0 +1,27 @@
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+class Mul(NamedTuple):
+    x: Any
+    y: Any
+
+
+def test_orphan_classes():
+    egraph = EGraph()
+    egraph.union(Add("x", "y"), Mul(Mul("p", "q"), "r"))
+    egraph.rebuild()
+    root = egraph.add(Add("x", "y"))
+    dead = [egraph.add(term) for term in [Mul("p", "q"), "p", "q", "r"]]
+
+    # the best term is Add(x, y), so the Mul subterms are unreachable
+    assert sorted(egraph.orphan_classes([root])) == sorted(dead)
+
+    # following every enode keeps them alive
+    assert egraph.orphan_classes([root], all_nodes=True) == []
@@ -0,0 +1,639 @@
+import json
+import logging
+import os
+import tempfile
+import time
+import warnings
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph, Rewrite, RuleSet, RunResult, optimize, vars
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+class Mul(NamedTuple):
+    x: Any
+    y: Any
+
+
+a, b, c = vars("a b c")  # type: ignore
+
+
+def test_tuple_rewrites():
+    egraph = EGraph()
+    egraph.add(Add(Mul("x", 1), 0))
+    egraph.run(
+        [
+            ("add-0", Add(a, 0), a),
+            ("mul-1", Mul(a, 1), a),
+        ]
+    )
+    assert egraph.extract(Add(Mul("x", 1), 0)) == "x"
+    try:
+        egraph.run([Add(a, 0)])
+    except TypeError:
+        pass
+    else:
+        assert False
+    try:
+        egraph.run([("add-0", Add(a, 0))])
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_tuple_rewrites_callable_rhs():
+    egraph = EGraph()
+    egraph.add(Add(2, 3))
+    egraph.run([("fold-add", Add(a, b), lambda a, b: a + b)])
+    assert egraph.extract(Add(2, 3)) == 5
+
+
+def test_stop_reason_saturated():
+    egraph = EGraph()
+    assert egraph.stop_reason is None
+    egraph.add(Add("x", 0))
+    egraph.run([("add-0", Add(a, 0), a)])
+    assert egraph.stop_reason.kind == "saturated"
+    assert not egraph.stop_reason.partial_iteration
+
+
+def test_run_result():
+    egraph = EGraph()
+    egraph.add(Add(Mul("x", 1), 0))
+    result = egraph.run([("mul-1", Mul(a, 1), a), ("add-0", Add(a, 0), a)])
+    assert isinstance(result, RunResult)
+    assert result.stop_reason.kind == "saturated"
+    assert result.iterations == len(egraph.last_run_iterations())
+    assert result.applied == {"mul-1": 1, "add-0": 1}
+    # x, 1, 0 and the merged Mul and Add
+    assert (result.nodes, result.classes) == (5, 3)
+    assert result.search_time >= 0 and result.apply_time >= 0 and result.rebuild_time >= 0
+    assert repr(result).startswith("RunResult(StopReason(saturated), iterations=")
+
+
+def test_run_result_to_json():
+    egraph = EGraph()
+    egraph.add(Add(Mul("x", 1), 0))
+    result = egraph.run([("mul-1", Mul(a, 1), a), ("add-0", Add(a, 0), a)])
+    with tempfile.TemporaryDirectory() as tmp:
+        path = os.path.join(tmp, "run.json")
+        result.to_json(path)
+        with open(path) as f:
+            report = json.load(f)
+    assert report["version"] == 1
+    assert report["stop_reason"] == {
+        "kind": "saturated",
+        "value": None,
+        "message": None,
+        "partial_iteration": False,
+    }
+    assert report["applied"] == {"mul-1": 1, "add-0": 1}
+    assert (report["nodes"], report["classes"]) == (5, 3)
+    assert len(report["per_iteration"]) == result.iterations
+    first = report["per_iteration"][0]
+    assert (first["nodes"], first["applied"]) == (5, {"mul-1": 1, "add-0": 1})
+    assert sorted(first) == [
+        "applied",
+        "apply_time",
+        "classes",
+        "nodes",
+        "rebuild_time",
+        "rebuilds",
+        "search_time",
+        "total_time",
+    ]
+
+
+def test_rewrite_metadata():
+    tagged = Rewrite(Mul(a, 1), a, name="mul-1", metadata={"category": "identity", "enabled": True})
+    assert tagged.metadata == {"category": "identity", "enabled": True}
+    assert Rewrite(Add(a, 0), a).metadata == {}
+    egraph = EGraph()
+    egraph.add(Mul("x", 1))
+    result = egraph.run([tagged, ("add-0", Add(a, 0), a)])
+    assert result.metadata == {"mul-1": {"category": "identity", "enabled": True}, "add-0": {}}
+    assert result.applied == {"mul-1": 1}
+
+
+def test_used_vars():
+    assert Rewrite(Add(a, b), Add(b, a)).used_vars() == ["a", "b"]
+    assert Rewrite(Mul(a, b), a).unused_vars() == ["b"]
+    typo = Rewrite(Add(a, b), lambda a, bb=0: a)
+    assert typo.used_vars() == ["a"]
+    assert typo.unused_vars() == ["b"]
+    assert Rewrite(Add(a, b), lambda **kw: kw["a"]).unused_vars() == []
+    try:
+        Rewrite(Add(a, b), lambda ctx: None, context=True).used_vars()
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_yield_each_iteration():
+    egraph = EGraph()
+    egraph.add(Mul("x", 1))
+    grow = [Rewrite(Mul(a, 1), Mul(Add(a, 1), 1))]
+    steps = egraph.run(grow, iter_limit=5, yield_each_iteration=True)
+    # nothing has run yet
+    assert egraph.run([]).nodes == 3
+    sizes = []
+    for step in steps:
+        assert step.iterations == 1
+        sizes.append(step.nodes)
+        if len(sizes) == 3:
+            break
+    assert sizes == [5, 7, 9]
+    # the e-graph stays as the last step left it
+    assert egraph.run([]).nodes == 9
+
+    egraph = EGraph()
+    egraph.add(Add("x", 0))
+    steps = list(egraph.run([Rewrite(Add(a, 0), a)], yield_each_iteration=True))
+    assert steps[-1].stop_reason.kind == "saturated"
+    try:
+        egraph.run([], hook=lambda i: None, yield_each_iteration=True)
+    except ValueError:
+        pass
+    else:
+        assert False
+
+    # the time limit starts with the first step
+    egraph.add(Mul("x", 1))
+    steps = egraph.run(grow, time_limit=0.05, yield_each_iteration=True)
+    time.sleep(0.1)
+    assert next(steps).stop_reason.kind == "iteration_limit"
+    for yield_each_iteration in [False, True]:
+        try:
+            egraph.run(grow, time_limit=-1.0, yield_each_iteration=yield_each_iteration)
+        except ValueError:
+            pass
+        else:
+            assert False
+
+
+def test_run_until():
+    egraph = EGraph()
+    egraph.add(Mul("x", 1))
+    seen = []
+
+    def until(view):
+        seen.append((view.frozen, len(view.class_ids())))
+        return len(view.class_ids()) == 5
+
+    # each iteration adds one more Add(..., 1) class
+    grow = [("grow", Mul(a, 1), Mul(Add(a, 1), 1))]
+    result = egraph.run(grow, iter_limit=10, until=until)
+    assert result.stop_reason.kind == "other" and result.stop_reason.message == "until"
+    assert seen == [(True, 4), (True, 5)]
+    assert len(egraph.class_ids()) == 5
+
+
+def test_run_until_raises():
+    egraph = EGraph()
+    egraph.add(Add(Add("x", 0), 0))
+
+    def until(view):
+        raise KeyError("boom")
+
+    try:
+        egraph.run([("add-0", Add(a, 0), a)], until=until)
+    except KeyError:
+        pass
+    else:
+        assert False
+
+
+def test_apply_limit():
+    egraph = EGraph()
+    terms = [Add(name, 0) for name in "pqrstu"]
+    for term in terms:
+        egraph.add(term)
+    result = egraph.run([("add-0", Add(a, 0), a)], apply_limit=4)
+    assert result.stop_reason.kind == "other" and result.stop_reason.message == "apply_limit"
+    assert result.applied == {"add-0": 4}
+    assert sum(egraph.equiv(term, term.x) for term in terms) == 4
+    assert egraph.check_invariants()
+    # the limit isn't kept for later runs
+    assert egraph.run([("add-0", Add(a, 0), a)]).stop_reason.kind == "saturated"
+    assert all(egraph.equiv(term, term.x) for term in terms)
+
+
+def test_stop_reason_node_limit_applies_whole_iteration():
+    grow = [
+        ("commute-add", Add(a, b), Add(b, a)),
+        ("assoc-add", Add(a, Add(b, c)), Add(Add(a, b), c)),
+    ]
+    egraph = EGraph()
+    egraph.add(Add(Add(Add(Add("p", "q"), "r"), "s"), "t"))
+    egraph.run(grow, iter_limit=50, node_limit=50)
+    reason = egraph.stop_reason
+    assert reason.kind == "node_limit"
+    assert reason.value > 50
+    assert not reason.partial_iteration
+
+
+def test_on_node_limit_prune():
+    grow = [
+        ("commute-add", Add(a, b), Add(b, a)),
+        ("assoc-add", Add(a, Add(b, c)), Add(Add(a, b), c)),
+    ]
+    term = Add(Add(Add(Add("p", "q"), "r"), "s"), "t")
+    egraph = EGraph()
+    egraph.add(term)
+    egraph.run(grow, iter_limit=12, node_limit=60)
+    assert egraph.stop_reason.kind == "node_limit"
+    assert egraph.last_run_pruned() is None
+
+    egraph = EGraph()
+    root = egraph.add(term)
+    egraph.run(grow, iter_limit=12, node_limit=60, on_node_limit="prune")
+    assert egraph.stop_reason.kind == "iteration_limit"
+    assert len(egraph.last_run_iterations()) == 12
+    pruned, ids = egraph.last_run_pruned()
+    assert pruned > 0
+    # every class is kept, with its best term
+    assert egraph.extract(ids[root]) == term
+    assert egraph.check_invariants()
+    # an apply_limit still holds after pruning
+    egraph = EGraph()
+    egraph.add(term)
+    result = egraph.run(grow, iter_limit=12, node_limit=60, on_node_limit="prune", apply_limit=150)
+    assert egraph.last_run_pruned()[0] > 0
+    assert result.stop_reason.message == "apply_limit"
+    assert sum(result.applied.values()) == 150
+    try:
+        egraph.run(grow, on_node_limit="grow")
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_growth_factor():
+    grow = [
+        ("commute-add", Add(a, b), Add(b, a)),
+        ("assoc-add", Add(a, Add(b, c)), Add(Add(a, b), c)),
+    ]
+    egraph = EGraph()
+    egraph.add(Add(Add(Add(Add("p", "q"), "r"), "s"), "t"))
+    # 9 enodes, so the limit is 27
+    egraph.run(grow, iter_limit=50, growth_factor=3.0)
+    assert egraph.stop_reason.kind == "node_limit"
+    first = egraph.stop_reason.value
+    assert first > 27
+    # the limit follows the e-graph's size when each run starts
+    egraph.run(grow, iter_limit=50, growth_factor=1.5)
+    assert egraph.stop_reason.kind == "node_limit"
+    assert egraph.stop_reason.value > first * 1.5
+    try:
+        egraph.run(grow, growth_factor=0.5)
+    except ValueError:
+        pass
+    else:
+        assert False, "a growth factor below 1 was accepted"
+
+
+def test_rewrite_priority():
+    applied = []
+
+    def record(name):
+        def applier(a):
+            applied.append(name)
+            return a
+
+        return applier
+
+    egraph = EGraph()
+    egraph.add(Add("x", 0))
+    rules = [
+        Rewrite(Add(a, 0), record("low"), name="low"),
+        Rewrite(Add(a, 0), record("high"), name="high", priority=2),
+        Rewrite(Add(a, 0), record("mid"), name="mid", priority=1),
+        Rewrite(Add(a, 0), record("low-2"), name="low-2"),
+    ]
+    assert rules[1].priority == 2
+    egraph.run(rules, iter_limit=1)
+    assert applied == ["high", "mid", "low", "low-2"]
+
+
+def test_rule_set():
+    rules = RuleSet(
+        [
+            ("add-0", Add(a, 0), a),
+            ([("mul-1", Mul(a, 1), a)], {"ban": False}),
+        ]
+    )
+    assert len(rules) == 2
+    assert [rule.name for rule in rules.rewrites] == ["add-0", "mul-1"]
+    for term in [Add("x", 0), Mul("y", 1)]:
+        egraph = EGraph()
+        egraph.add(term)
+        egraph.run(rules)
+        assert egraph.stop_reason.kind == "saturated"
+        assert len(egraph.class_ids()) == 2
+    assert EGraph().count_matches(rules) == {"add-0": 0, "mul-1": 0}
+
+
+def test_rule_set_rejects_bad_rules_up_front():
+    try:
+        RuleSet([("unbound", Add(a, 0), Add(a, b))])
+    except ValueError as err:
+        assert "unbound" in str(err)
+    else:
+        assert False, "a rule with an unbound var was accepted"
+
+
+def test_rewrites_from_generator():
+    def rules():
+        yield ("add-0", Add(a, 0), a)
+        yield ("mul-1", Mul(a, 1), a)
+
+    egraph = EGraph()
+    egraph.add(Mul(Add("x", 0), 1))
+    egraph.run(rules())
+    assert egraph.extract(Mul(Add("x", 0), 1)) == "x"
+
+    egraph.run((r for r in []))
+    assert egraph.stop_reason.kind == "saturated"
+
+
+def test_assert_equiv_after():
+    egraph = EGraph()
+    rules = [("add-0", Add(a, 0), a), ("mul-1", Mul(a, 1), a)]
+    equivalent, reason = egraph.assert_equiv_after(Add(Mul("x", 1), 0), "x", rules)
+    assert equivalent
+    assert reason.kind == "saturated"
+    # the scratch run leaves this e-graph untouched
+    assert egraph.class_ids() == []
+    assert egraph.stop_reason is None
+
+
+def test_assert_equiv_after_not_equal_in_place():
+    egraph = EGraph()
+    rules = [("add-0", Add(a, 0), a)]
+    equivalent, reason = egraph.assert_equiv_after(
+        Mul("x", 1), "x", rules, iter_limit=3, in_place=True
+    )
+    assert not equivalent
+    assert egraph.stop_reason.kind == reason.kind
+    assert len(egraph.class_ids()) == 3
+
+
+def test_last_run_timing():
+    egraph = EGraph()
+    assert egraph.last_run_timing() is None
+    egraph.add(Add(Mul("x", 1), 0))
+    egraph.run([("add-0", Add(a, 0), a), ("mul-1", Mul(a, 1), a)])
+    timing = egraph.last_run_timing()
+    assert sorted(timing) == ["apply_time", "rebuild_time", "search_time"]
+    assert all(t >= 0.0 for t in timing.values())
+
+
+def test_optimize():
+    rules = [("add-0", Add(a, 0), a), ("mul-1", Mul(a, 1), a)]
+    best, cost, reason = optimize(Add(Mul("x", 1), 0), rules)
+    assert (best, cost, reason.kind) == ("x", 1.0, "saturated")
+    best, cost, reason = optimize(Add(Mul("x", 1), 0), rules[:1], cost="depth", iter_limit=1)
+    assert (best, cost, reason.kind) == (Mul("x", 1), 2.0, "iteration_limit")
+
+
+def test_deadline_spans_runs():
+    egraph = EGraph()
+    assert egraph.remaining_time is None
+    egraph.add(Add(Mul("x", 1), 0))
+    egraph.set_deadline(60.0)
+    egraph.run([("add-0", Add(a, 0), a)])
+    assert egraph.stop_reason.kind == "saturated"
+    assert 0.0 < egraph.remaining_time <= 60.0
+
+    egraph.set_deadline(0.0)
+    assert egraph.remaining_time == 0.0
+    egraph.run([("mul-1", Mul(a, 1), a)])
+    assert egraph.stop_reason.kind == "time_limit"
+    assert not egraph.equiv(Mul("x", 1), "x")
+
+    egraph.set_deadline(None)
+    egraph.run([("mul-1", Mul(a, 1), a)])
+    assert egraph.equiv(Mul("x", 1), "x")
+    for bad in [-1.0, float("nan"), float("inf")]:
+        try:
+            egraph.set_deadline(bad)
+        except ValueError:
+            pass
+        else:
+            assert False, bad
+    assert egraph.remaining_time is None
+
+
+def test_validate_warns_for_unused_rewrites():
+    egraph = EGraph()
+    egraph.add(Add("x", 0))
+    with warnings.catch_warnings(record=True) as caught:
+        warnings.simplefilter("always")
+        egraph.run([("add-0", Add(a, 0), a), ("mul-1", Mul(a, 1), a)], validate=True)
+    assert [str(w.message) for w in caught] == ["Rewrite \"mul-1\" was never applied"]
+
+    with warnings.catch_warnings(record=True) as caught:
+        warnings.simplefilter("always")
+        egraph.run([("mul-1", Mul(a, 1), a)])
+    assert caught == []
+
+
+def test_hook_adds_terms():
+    egraph = EGraph()
+    egraph.add(Add(Mul("x", 1), 0))
+    calls = []
+
+    def hook(iteration):
+        calls.append(iteration)
+        if iteration == 0:
+            return [Mul("y", 1), (Add("y", 0), "z")]
+        return None
+
+    egraph.run([("mul-1", Mul(a, 1), a)], hook=hook)
+    assert calls == [0, 1]
+    assert egraph.equiv(Mul("y", 1), "y")
+    assert egraph.equiv(Add("y", 0), "z")
+    stats = egraph.last_run_iterations()
+    assert [s["hook_nodes"] for s in stats] == [4, 0]
+    assert stats[0]["applied"] == {"mul-1": 2}
+    assert stats[1]["applied"] == {}
+
+
+def test_hook_exception_is_raised():
+    egraph = EGraph()
+    egraph.add("x")
+
+    def hook(iteration):
+        raise KeyError("boom")
+
+    try:
+        egraph.run([("mul-1", Mul(a, 1), a)], hook=hook)
+    except KeyError:
+        pass
+    else:
+        assert False
+    assert egraph.equiv("x", "x")
+
+
+def test_log_reports_each_iteration():
+    class Records(logging.Handler):
+        def __init__(self):
+            super().__init__()
+            self.records = []
+
+        def emit(self, record):
+            self.records.append(record)
+
+    logger = logging.getLogger("snake_egg.test_log")
+    logger.setLevel(logging.DEBUG)
+    handler = Records()
+    logger.addHandler(handler)
+    egraph = EGraph()
+    egraph.add(Add(Mul("x", 1), 0))
+    egraph.run([("mul-1", Mul(a, 1), a)], log=logger, log_level=logging.DEBUG)
+    logger.removeHandler(handler)
+
+    stats = egraph.last_run_iterations()
+    assert len(handler.records) == len(stats) == 2
+    assert all(r.levelno == logging.DEBUG for r in handler.records)
+    assert [r.args[0] for r in handler.records] == [1, 2]
+    # Mul(x, 1) merged into x
+    assert handler.records[0].getMessage() == "iteration 1: 5 nodes, 4 classes"
+
+
+def test_ordered_runs_rewrites_in_passes():
+    egraph = EGraph()
+    egraph.add(Mul(Add("x", 0), 1))
+    rules = [
+        ("mul-1", Mul(a, 1), a),
+        ("add-0", Add(a, 0), a),
+    ]
+    egraph.run(rules, ordered=True)
+    assert egraph.stop_reason.kind == "saturated"
+    assert egraph.equiv(Mul(Add("x", 0), 1), "x")
+    # one pass simplifies everything, a second finds nothing left to do
+    assert egraph.last_run_passes() == 2
+    applied = [s["applied"] for s in egraph.last_run_iterations()]
+    assert applied[0] == {"mul-1": 1}
+    assert {"add-0": 1} in applied
+
+    egraph.run(rules)
+    assert egraph.last_run_passes() is None
+
+
+def test_ordered_shares_iter_limit():
+    egraph = EGraph()
+    egraph.add(Mul(Add("x", 0), 1))
+    rules = [
+        ("mul-1", Mul(a, 1), a),
+        ("add-0", Add(a, 0), a),
+    ]
+    egraph.run(rules, iter_limit=1, ordered=True)
+    assert egraph.stop_reason.kind == "iteration_limit"
+    assert egraph.stop_reason.value == 1
+    assert egraph.last_run_passes() == 1
+    assert not egraph.equiv(Add("x", 0), "x")
+
+
+def test_ordered_rejects_hook():
+    egraph = EGraph()
+    try:
+        egraph.run([], hook=lambda i: None, ordered=True)
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_on_union_reports_merges():
+    egraph = EGraph()
+    egraph.add(Add(Mul("x", 1), 0))
+    x = egraph.add("x")
+    mul = egraph.add(Mul("x", 1))
+    add = egraph.add(Add(Mul("x", 1), 0))
+    unions = []
+    rules = [("mul-1", Mul(a, 1), a), ("add-0", Add(a, 0), lambda a: a)]
+    egraph.run(rules, on_union=lambda a, b: unions.append({a, b}))
+    assert len(unions) == 2
+    assert {mul, x} in unions
+    assert {add, mul} in unions or {add, x} in unions
+    # nothing left to union
+    egraph.run(rules, on_union=lambda a, b: unions.append({a, b}))
+    assert len(unions) == 2
+
+
+def test_on_union_exception_is_raised():
+    egraph = EGraph()
+    egraph.add(Mul(Mul("x", 1), 1))
+
+    def on_union(a, b):
+        raise KeyError("boom")
+
+    try:
+        egraph.run([("mul-1", Mul(a, 1), a)], on_union=on_union)
+    except KeyError:
+        pass
+    else:
+        assert False
+    assert egraph.stop_reason.kind == "other"
+    egraph.run([("mul-1", Mul(a, 1), a)])
+    assert egraph.equiv(Mul("x", 1), "x")
+
+
+def test_guard_refuses_merges():
+    egraph = EGraph()
+    egraph.add(Add(Mul("y", 1), 0))
+    rules = [("mul-1", Mul(a, 1), a), ("bad", Add(a, 0), 0)]
+    seen = []
+
+    def guard(a, b):
+        seen.append((a, b))
+        return "0" not in a[0] + b[0]
+
+    try:
+        egraph.run(rules, guard=guard)
+    except ValueError:
+        pass
+    else:
+        assert False
+    assert ((["Add"], None), (["0"], None)) in seen
+    assert not egraph.equiv(Add(Mul("y", 1), 0), 0)
+    egraph.run(rules, guard=lambda a, b: True)
+    assert egraph.equiv(Add(Mul("y", 1), 0), 0)
+
+
+def test_rule_groups_have_own_backoff():
+    terms = [Add(f"x{i}", 0) for i in range(5)]
+    canonical = [("add-0", Add(a, 0), a)]
+    expansion = [("mul-1-intro", Add(a, 0), Mul(Add(a, 0), 1))]
+
+    egraph = EGraph()
+    for term in terms:
+        egraph.add(term)
+    egraph.run([(canonical, {"ban": False}), (expansion, {"match_limit": 2})], iter_limit=1)
+    # the throttled group has too many matches and is banned for now
+    assert egraph.last_run_iterations()[0]["applied"] == {"add-0": 5}
+
+    egraph = EGraph()
+    for term in terms:
+        egraph.add(term)
+    egraph.run([*canonical, (expansion, {"match_limit": 10, "ban_length": 1})], iter_limit=1)
+    assert egraph.last_run_iterations()[0]["applied"] == {"add-0": 5, "mul-1-intro": 5}
+    assert egraph.count_matches([(canonical, {})]) == {"add-0": 5}
+
+
+def test_rule_group_unknown_option():
+    egraph = EGraph()
+    try:
+        egraph.run([([("mul-1", Mul(a, 1), a)], {"limit": 1})])
+    except ValueError:
+        pass
+    else:
+        assert False
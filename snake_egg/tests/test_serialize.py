@@ -0,0 +1,27 @@
+import json
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+def test_egraph_serialize_schema():
+    egraph = EGraph()
+    root = egraph.add(Add(1, "x"))
+    egraph.rebuild()
+
+    data = json.loads(egraph.to_egraph_serialize([root]))
+    nodes = data["nodes"]
+    assert len(nodes) == 3
+
+    ops = sorted(node["op"] for node in nodes.values())
+    assert ops == ["1", "Add", "x"]
+
+    (add_node,) = [node for node in nodes.values() if node["op"] == "Add"]
+    assert data["root_eclasses"] == [add_node["eclass"]]
+    assert all(child in nodes for child in add_node["children"])
+    assert all(node["cost"] == 1.0 for node in nodes.values())
@@ -0,0 +1,107 @@
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+class Div(NamedTuple):
+    x: Any
+    y: Any
+
+
+def eval_add(op, args):
+    if isinstance(op, int):
+        return op
+    if op is Add:
+        return args[0] + args[1]
+    return None
+
+
+def test_set_eval_reanalyzes_existing_classes():
+    egraph = EGraph()
+    egraph.add(Add(2, Add(3, 4)))
+    egraph.rebuild()
+    assert egraph.extract(Add(2, Add(3, 4))) == Add(2, Add(3, 4))
+
+    egraph.set_eval(eval_add)
+    assert egraph.extract(Add(2, Add(3, 4))) == 9
+
+
+def test_set_eval_without_reanalysis():
+    egraph = EGraph()
+    egraph.add(Add(2, 3))
+    egraph.set_eval(eval_add, reanalyze=False)
+    egraph.rebuild()
+    assert egraph.extract(Add(2, 3)) == Add(2, 3)
+
+
+def test_merge_keeps_data():
+    egraph = EGraph(eval=eval_add)
+    x = egraph.add("x")
+    total = egraph.add(Add("x", 1))
+    assert egraph.class_data(x) is None
+    # the data of the merged class flows in, and on to its parents
+    egraph.union(x, 5)
+    egraph.rebuild()
+    assert egraph.class_data(x) == 5
+    assert egraph.class_data(total) == 6
+    try:
+        egraph.union(total, 7)
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_builtin_depth_analysis():
+    egraph = EGraph(analysis="depth")
+    root = egraph.add(Add(Add("x", 1), "y"))
+    assert egraph.class_data(root) == 3
+    assert egraph.class_data(egraph.add("x")) == 1
+    # the shallowest term counts once classes merge
+    egraph.union(root, Add("x", "y"))
+    egraph.rebuild()
+    assert egraph.class_data(root) == 2
+    assert egraph.extract(root) == Add("x", "y")
+
+
+def test_builtin_const_int_analysis():
+    egraph = EGraph(analysis="const_int")
+    sum_ = egraph.add(Add(2, 3))
+    assert egraph.class_data(sum_) is None
+    egraph.union(sum_, 5)
+    egraph.rebuild()
+    assert egraph.class_data(sum_) == 5
+    try:
+        egraph.union(sum_, 6)
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_builtin_fold_analysis():
+    egraph = EGraph(analysis="fold")
+    total = egraph.add(Add(2, Add(3, 4)))
+    egraph.rebuild()
+    assert egraph.class_data(total) == 9
+    assert egraph.extract(total) == 9
+    half = egraph.add(Div(1, 2))
+    egraph.rebuild()
+    assert egraph.extract(Add(half, 1.5)) == 2.0
+    for unfolded in [Div(1, 0), Add("x", 1), Add(2**70, 1)]:
+        assert egraph.class_data(egraph.add(unfolded)) is None
+
+
+def test_builtin_analysis_errors():
+    for kwargs in [{"analysis": "size"}, {"analysis": "depth", "eval": eval_add}]:
+        try:
+            EGraph(**kwargs)
+        except ValueError:
+            pass
+        else:
+            assert False, kwargs
@@ -0,0 +1,152 @@
+from typing import Any, NamedTuple
+
+from snake_egg import EGraph
+
+
+class Add(NamedTuple):
+    x: Any
+    y: Any
+
+
+def test_union_mixes_ids_and_exprs():
+    egraph = EGraph()
+    x = egraph.add("x")
+    assert egraph.union(x, Add("x", 0), "y")
+    egraph.rebuild()
+    assert egraph.equiv("y", x, Add("x", 0))
+    assert not egraph.union(Add("x", 0), x)
+
+
+def test_equiv_mixes_ids_and_exprs():
+    egraph = EGraph()
+    x = egraph.add("x")
+    assert not egraph.equiv(x, "y")
+    egraph.union(egraph.add("y"), x)
+    egraph.rebuild()
+    assert egraph.equiv(x, "y")
+
+
+def test_set_constant():
+    egraph = EGraph()
+    id = egraph.set_constant(Add("x", 0), 5)
+    assert id == egraph.find(egraph.add(Add("x", 0)))
+    assert egraph.equiv(Add("x", 0), 5)
+    # a tuple value is a single leaf, not a term
+    pair = egraph.set_constant("p", (1, 2))
+    assert egraph.extract(pair) in ["p", (1, 2)]
+    # x, 0, Add("x", 0) with 5, and "p" with (1, 2)
+    assert len(egraph.class_ids()) == 4
+
+
+def test_set_constant_conflicting_data():
+    def fold(op, args):
+        if isinstance(op, int):
+            return op
+        return args[0] + args[1] if op is Add else None
+
+    egraph = EGraph(eval=fold)
+    try:
+        egraph.set_constant(Add(1, 2), 4)
+    except ValueError:
+        pass
+    else:
+        assert False
+    egraph.add("z")
+
+
+def test_find_canonicalizes_ids():
+    egraph = EGraph()
+    x = egraph.add("x")
+    y = egraph.add("y")
+    costs = {x: 1, y: 2}
+    egraph.union(x, y)
+    egraph.rebuild()
+    # ids compare by value, so stale keys stay distinct until re-canonicalized
+    assert x != y
+    assert egraph.find(x) == egraph.find(y)
+    assert egraph.find(egraph.find(x)) == egraph.find(x)
+    canonical = {egraph.find(id): cost for id, cost in costs.items()}
+    assert len(canonical) == 1
+    assert egraph.find(x) in canonical
+
+
+def test_resolve():
+    egraph = EGraph()
+    x = egraph.add("x")
+    stale = egraph.add(Add("x", 0))
+    egraph.union("y", x)
+    egraph.union(stale, "y")
+    egraph.rebuild()
+    canonical = egraph.find(x)
+    assert egraph.resolve(stale) == egraph.resolve(x) == canonical
+    assert egraph.resolve(Add("x", 0)) == canonical
+    assert egraph.resolve("z") == egraph.add("z")
+    try:
+        EGraph().resolve(stale)
+    except ValueError:
+        pass
+    else:
+        assert False
+
+
+def test_check_invariants():
+    egraph = EGraph()
+    assert egraph.check_invariants()
+    x = egraph.add("x")
+    nodes = [("y", []), (Add, [0, 0])]
+    egraph.union(x, egraph.from_nodes(nodes))
+    egraph.union(egraph.add("y"), Add("x", "x"))
+    egraph.rebuild()
+    assert egraph.check_invariants()
+    # Add(x) and Add(z) aren't merged until the next rebuild
+    egraph.add(Add("x", "z"))
+    egraph.union("x", "z")
+    try:
+        egraph.check_invariants()
+    except ValueError:
+        pass
+    else:
+        assert False
+    egraph.rebuild()
+    assert egraph.check_invariants()
+
+
+def test_all_equal():
+    egraph = EGraph()
+    x = egraph.add("x")
+    y = egraph.add("y")
+    z = egraph.add(Add("x", "y"))
+    assert egraph.all_equal(x, x)
+    assert egraph.all_equal()
+    assert not egraph.all_equal(x, y, z)
+    egraph.union(x, y)
+    egraph.union(y, z)
+    assert egraph.all_equal(x, y, z)
+    # nothing was added
+    assert len(egraph.class_ids()) == 1
+    big = EGraph()
+    for i in range(10):
+        big.add(i)
+    try:
+        egraph.all_equal(x, big.add(9))
+    except ValueError:
+        pass
+    else:
+        assert False, "expected ValueError"
+
+
+def test_ids_known_after_analysis_rebuild():
+    # merging data is propagated by remaking parents' data, which hands out no ids
+    egraph = EGraph(analysis="depth")
+    egraph.add(Add(Add(Add("x", "y"), "z"), "w"))
+    egraph.union(Add("x", "y"), "q")
+    egraph.rebuild()
+    big = EGraph()
+    for i in range(10):
+        big.add(i)
+    try:
+        egraph.all_equal(big.add(8))
+    except ValueError:
+        pass
+    else:
+        assert False, "expected ValueError"
@@ -1,24 +1,62 @@
 use egg::{
-    AstSize, EGraph, Extractor, Id, Language, Pattern, PatternAst, RecExpr, Rewrite, Runner, Var,
+    Analysis, AstSize, EGraph, ENodeOrVar, Extractor, Id, Iteration, Language, Pattern, PatternAst,
+    RecExpr, Rewrite, StopReason, Subst, Symbol, Var,
 };
-use pyo3::types::{PyDict, PyList, PyString, PyTuple};
-use pyo3::{basic::CompareOp, prelude::*};
+use pyo3::types::{PyDict, PyList, PyString, PyTuple, PyType};
+use pyo3::{basic::CompareOp, prelude::*, AsPyPointer};
 
+use std::cell::RefCell;
+use std::cmp::Reverse;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::hash::{Hash, Hasher};
-use std::time::Duration;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::lang::{PythonAnalysis, PythonApplier, PythonNode};
-use crate::util::{build_node, build_pattern};
-use pyo3::exceptions::PyValueError;
+use crate::extract::{
+    extract_forest, extract_within_size, forbidden_nodes, has_term_under_cost, BestTerms,
+    CostModel, OpCostCache, SampledTerms, TermEnumerator,
+};
+use crate::lang::{
+    insertion_order, provenance, BuiltinAnalysis, CommutativeSearcher, KindedSearcher,
+    PatternApplier, PythonAnalysis, PythonApplier, PythonNode, SharedSearcher, TracedApplier,
+    UnionObserver,
+};
+use crate::run::{
+    backoff_scheduler, stop_at_apply_limit, stop_on_callback_error, stopped_mid_apply, with_logger,
+    with_node_limit, with_python_hook, with_until, EGraphView, GroupConfig, PyRunResult,
+    PyStopReason, PythonRunner, RuleGroups, RunLog, Until,
+};
+use crate::util::{
+    add_leaf, add_rec_expr, build_dict_node, build_node, build_node_list, build_pattern,
+    build_sexpr_node, lookup_node, match_classes, BuildLimits, LabelInterner,
+};
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
 
+/// An e-class id, as returned by `add`.
+///
+/// Ids compare and hash by their value, not by e-class: after a union, the
+/// ids of the merged classes stay different, so ids used as dict keys must be
+/// re-canonicalized with `EGraph.find` to be looked up again.
+///
+/// An id stays valid for the e-graph that handed it out, however many unions
+/// follow, but names its class only up to `find`; `EGraph.resolve` takes an
+/// id or an expression and always gives the current canonical id. Ids don't
+/// carry over to a `subgraph`, or across a pruning `run` (see
+/// `last_run_pruned`).
 #[pyclass]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PyId(pub Id);
 
 #[pymethods]
 impl PyId {
+    fn __hash__(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.0.hash(&mut hasher);
+        hasher.finish()
+    }
+
     fn __richcmp__(&self, other: Self, op: CompareOp) -> bool {
         match op {
             CompareOp::Lt => self.0 < other.0,
@@ -31,21 +69,53 @@ impl PyId {
     }
 }
 
+/// A pattern variable. With a `kind`, it only matches e-classes holding an
+/// enode of that kind: an operator of that class, or a leaf that is an
+/// instance of it. A `kind` that isn't a type is a predicate instead, called
+/// with each enode's operator object (its node class, or the leaf object)
+/// until it returns true, e.g. `lambda op: isinstance(op, float) and op > 0`.
+/// That calls into Python for every enode of every e-class a match binds the
+/// var to, so it is much slower than a type, which is checked in Rust, and
+/// best kept cheap. If it raises, the search (or `run`) stops and raises the
+/// exception.
+///
+/// A `structural` var used more than once in a pattern doesn't need its
+/// occurrences bound to the same e-class, only to e-classes with terms of
+/// the same shape: enodes with the same class name (or, for leaves, `str()`)
+/// and number of children, all the way down, as in
+/// `find_structural_duplicates`. This catches terms that are equal but
+/// weren't unioned, e.g. because their leaves compare unequal; comparing
+/// the classes costs much more than the usual check. The occurrences after
+/// the first are bound to vars of their own, `x#2`, `x#3` and so on for a
+/// var `x`, which show up in `search` results but aren't passed to a
+/// callable applier. Vars compare by name only.
 #[pyclass]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct PyVar(pub Var);
+#[derive(Debug, Clone)]
+pub struct PyVar(pub Var, pub Option<PyObject>, pub bool);
 
 #[pymethods]
 impl PyVar {
     #[new]
-    fn new(str: &PyString) -> Self {
-        Self::from_str(str.to_string_lossy().as_ref())
+    #[args(kind = "None", structural = "false")]
+    fn new(str: &PyString, kind: Option<&PyAny>, structural: bool) -> PyResult<Self> {
+        let var = Self::from_str(str.to_string_lossy().as_ref()).with_kind(kind)?;
+        Ok(PyVar(var.0, var.1, structural))
+    }
+
+    #[getter]
+    fn kind(&self) -> Option<PyObject> {
+        self.1.clone()
+    }
+
+    #[getter]
+    fn structural(&self) -> bool {
+        self.2
     }
 
     fn __hash__(&self) -> u64 {
         let mut hasher = DefaultHasher::new();
         self.0.hash(&mut hasher);
-        hasher.finish().into()
+        hasher.finish()
     }
 
     fn __richcmp__(&self, other: Self, op: CompareOp) -> bool {
@@ -63,57 +133,699 @@ impl PyVar {
 impl PyVar {
     pub fn from_str(str: &str) -> Self {
         let v = format!("?{}", str);
-        PyVar(v.parse().unwrap())
+        PyVar(v.parse().unwrap(), None, false)
+    }
+
+    pub fn with_kind(self, kind: Option<&PyAny>) -> PyResult<Self> {
+        if kind.is_some_and(|kind| !kind.is_callable()) {
+            return Err(PyValueError::new_err("kind must be a type or a predicate"));
+        }
+        Ok(PyVar(self.0, kind.map(Into::into), self.2))
     }
 }
 
 #[pyclass]
 pub struct PyPattern {
     pub pattern: Pattern<PythonNode>,
+    /// The kinds of the pattern's kinded vars.
+    pub kinds: Vec<(Var, PyObject)>,
+    /// `(copy, var)` for each later occurrence of a structural var.
+    pub structural: Vec<(Var, Var)>,
 }
 
 #[pyclass]
 pub struct PyRewrite {
     pub rewrite: Rewrite<PythonNode, PythonAnalysis>,
+    /// The kinds of the left-hand side's vars, enforced by its searcher.
+    pub kinds: Vec<(Var, PyObject)>,
+    /// The left-hand side's structural var copies, checked by its searcher.
+    pub structural: Vec<(Var, Var)>,
+    /// Rewrites of higher priority have their matches applied first.
+    pub priority: i64,
+    /// A callable applier and its `context` flag; `None` for a pattern.
+    pub callable: Option<(PyObject, bool)>,
+    /// The `metadata` dict, opaque to egg.
+    pub metadata: Py<PyDict>,
 }
 
 #[pymethods]
 impl PyRewrite {
+    /// With `context`, a callable `applier` is called as `applier(ctx)` with an
+    /// `ApplierContext` instead of with the matched terms as keyword arguments;
+    /// `context` with a pattern `applier` raises `ValueError`. An exception a
+    /// callable raises stops `run()`, which raises it.
+    /// In each iteration of `run()`, rewrites of higher `priority` have their
+    /// matches applied before those of lower priority; rewrites of equal
+    /// priority keep their order. `metadata`, a dict, is kept as is for tools
+    /// managing rules and reported by `run()`'s `RunResult`.
     #[new]
-    #[args(name = "\"\"")]
-    fn new(searcher: PyPattern, applier: &PyAny, name: &str) -> Self {
+    #[args(name = "\"\"", context = "false", priority = "0", metadata = "None")]
+    fn new(
+        py: Python,
+        searcher: PyPattern,
+        applier: &PyAny,
+        name: &str,
+        context: bool,
+        priority: i64,
+        metadata: Option<Py<PyDict>>,
+    ) -> PyResult<Self> {
+        if context && !applier.is_callable() {
+            return Err(PyValueError::new_err(
+                "context=True needs a callable right-hand side",
+            ));
+        }
+        let kinds = searcher.kinds;
+        let structural = searcher.structural;
+        let callable = applier.is_callable().then(|| (applier.into(), context));
         let rewrite = if applier.is_callable() {
             let applier = PythonApplier {
                 eval: applier.into(),
-                vars: searcher.pattern.vars(),
+                vars: original_vars(searcher.pattern.vars(), &structural),
+                context,
             };
-            Rewrite::new(name, searcher.pattern, applier).unwrap()
+            Rewrite::new(name, searcher.pattern, applier)
         } else if let Ok(pat) = applier.extract::<PyPattern>() {
-            Rewrite::new(name, searcher.pattern, pat.pattern).unwrap()
+            Rewrite::new(name, searcher.pattern, pat.pattern)
         } else {
             panic!("Applier must be a pattern or callable");
         };
-        PyRewrite { rewrite }
+        // e.g. a right-hand side using a var the left-hand side doesn't bind
+        let mut rewrite = rewrite.map_err(PyValueError::new_err)?;
+        if let Some(ast) = rewrite.applier.get_pattern_ast().cloned() {
+            rewrite.applier = Arc::new(PatternApplier {
+                pattern: Pattern::from(ast),
+            });
+        }
+        rewrite.searcher = KindedSearcher::wrap(rewrite.searcher, &kinds, &structural);
+        Ok(PyRewrite {
+            rewrite,
+            kinds,
+            structural,
+            priority,
+            callable,
+            metadata: metadata.unwrap_or_else(|| PyDict::new(py).into()),
+        })
     }
 
     #[getter]
     fn name(&self) -> &str {
         self.rewrite.name.as_str()
     }
+
+    #[getter]
+    fn priority(&self) -> i64 {
+        self.priority
+    }
+
+    #[getter]
+    fn metadata(&self) -> Py<PyDict> {
+        self.metadata.clone()
+    }
+
+    /// The names of the left-hand side's vars that the applier uses, in the
+    /// order they occur in the left-hand side. For a pattern, those it
+    /// mentions; for a callable, those it declares a parameter for (by
+    /// `inspect.signature`), or all of them if it takes `**kwargs`. A
+    /// `context` applier reads its matches by name at run time, so it can't
+    /// be told and raises `ValueError`.
+    fn used_vars(&self, py: Python) -> PyResult<Vec<String>> {
+        let vars = original_vars(self.rewrite.searcher.vars(), &self.structural);
+        let used: Vec<Var> = match &self.callable {
+            Some((_, true)) => {
+                return Err(PyValueError::new_err(
+                    "can't tell which vars a context applier uses",
+                ))
+            }
+            Some((callable, false)) => {
+                let inspect = py.import("inspect")?;
+                let parameter = inspect.getattr("Parameter")?;
+                let signature = inspect.call_method1("signature", (callable,))?;
+                let mut names = HashSet::new();
+                for param in signature
+                    .getattr("parameters")?
+                    .call_method0("values")?
+                    .iter()?
+                {
+                    let param = param?;
+                    if param
+                        .getattr("kind")?
+                        .eq(parameter.getattr("VAR_KEYWORD")?)?
+                    {
+                        return Ok(vars
+                            .iter()
+                            .map(|var| var.to_string()[1..].to_owned())
+                            .collect());
+                    }
+                    names.insert(param.getattr("name")?.extract::<String>()?);
+                }
+                vars.iter()
+                    .copied()
+                    .filter(|var| names.contains(&var.to_string()[1..]))
+                    .collect()
+            }
+            None => self.rewrite.applier.vars(),
+        };
+        Ok(vars
+            .iter()
+            .filter(|var| used.contains(var))
+            .map(|var| var.to_string()[1..].to_owned())
+            .collect())
+    }
+
+    /// The names of the left-hand side's vars that `used_vars()` leaves out,
+    /// often a sign the two sides have drifted apart or a parameter is
+    /// misspelled.
+    fn unused_vars(&self, py: Python) -> PyResult<Vec<String>> {
+        let used = self.used_vars(py)?;
+        Ok(
+            original_vars(self.rewrite.searcher.vars(), &self.structural)
+                .iter()
+                .map(|var| var.to_string()[1..].to_owned())
+                .filter(|name| !used.contains(name))
+                .collect(),
+        )
+    }
+}
+
+impl PyRewrite {
+    /// The rewrites of `run()`'s `rewrites` argument, whose items are rewrites
+    /// (see `from_any`) or `(rewrites, config)` groups, with the group config
+    /// of each grouped rule.
+    pub fn groups_from_any(
+        py: Python,
+        rewrites: &PyAny,
+    ) -> PyResult<(Vec<Py<PyRewrite>>, RuleGroups)> {
+        if let Ok(rules) = rewrites.downcast::<PyCell<PyRuleSet>>() {
+            let rules = rules.borrow();
+            return Ok((rules.rewrites.clone(), rules.groups.clone()));
+        }
+        let mut all = vec![];
+        let mut groups = vec![];
+        for item in rewrites.iter()? {
+            let item = item?;
+            let group = item
+                .downcast::<PyTuple>()
+                .ok()
+                .filter(|group| group.get_type().is(py.get_type::<PyTuple>()) && group.len() == 2)
+                .and_then(|group| Some((group.get_item(0).ok()?, group.get_item(1).ok()?)))
+                .and_then(|(members, config)| Some((members, config.downcast::<PyDict>().ok()?)));
+            match group {
+                Some((members, config)) => {
+                    let config = GroupConfig::from_dict(config)?;
+                    for rewrite in members.iter()? {
+                        let rewrite = Self::from_any(rewrite?)?;
+                        groups.push((rewrite.borrow(py).rewrite.name, config));
+                        all.push(rewrite);
+                    }
+                }
+                None => all.push(Self::from_any(item)?),
+            }
+        }
+        Ok((all, groups))
+    }
+
+    /// Accept either a `Rewrite` or a `(name, lhs, rhs)` tuple, building the
+    /// rewrite from the tuple the same way `Rewrite(lhs, rhs, name)` would.
+    /// Anything else is a `TypeError`, and a malformed tuple a `ValueError`.
+    pub fn from_any(obj: &PyAny) -> PyResult<Py<PyRewrite>> {
+        if let Ok(rewrite) = obj.extract::<Py<PyRewrite>>() {
+            return Ok(rewrite);
+        }
+        // node terms are tuples too, but never plain ones
+        if !obj.get_type().is(obj.py().get_type::<PyTuple>()) {
+            return Err(PyTypeError::new_err(format!(
+                "Rewrites must be Rewrite objects or (name, lhs, rhs) tuples, not {}",
+                obj.get_type().name()?
+            )));
+        }
+        match obj.extract::<(&str, PyPattern, &PyAny)>() {
+            Ok((name, searcher, applier)) => {
+                let rewrite = Self::new(obj.py(), searcher, applier, name, false, 0, None)?;
+                Py::new(obj.py(), rewrite)
+            }
+            Err(_) => Err(PyValueError::new_err(
+                "Rewrites must be Rewrite objects or (name, lhs, rhs) tuples",
+            )),
+        }
+    }
+
+    /// The rewrite up to renaming its vars: both sides numbered together, the
+    /// kinds of the numbered vars and its structural var copies, and a
+    /// callable applier by identity. As a callable is passed the matches by
+    /// var name, its rule keeps the names.
+    fn alpha_key(&self) -> RewriteKey {
+        let mut vars = HashMap::new();
+        let searcher = self.rewrite.searcher.get_pattern_ast().unwrap();
+        let searcher = alpha_key_with(searcher, &mut vars);
+        let mut kinds: Vec<_> = self
+            .kinds
+            .iter()
+            .map(|(var, kind)| (vars[var], kind.as_ptr() as usize))
+            .chain(
+                self.structural
+                    .iter()
+                    .map(|(copy, var)| (vars[copy], usize::MAX - vars[var])),
+            )
+            .collect();
+        kinds.sort_unstable();
+        let applier = match &self.callable {
+            Some((callable, context)) => {
+                let mut names: Vec<_> = vars.iter().map(|(var, &n)| (n, var.to_string())).collect();
+                names.sort_unstable();
+                AlphaApplier::Callable(callable.as_ptr() as usize, *context, names)
+            }
+            None => {
+                let applier = self.rewrite.applier.get_pattern_ast().unwrap();
+                AlphaApplier::Pattern(alpha_key_with(applier, &mut vars))
+            }
+        };
+        (searcher, kinds, applier)
+    }
+
+    /// `rewrites` without the rules that are alpha-equivalent to an earlier
+    /// one, which is kept with its own name.
+    pub fn dedup(py: Python, rewrites: &PyAny) -> PyResult<Vec<Py<PyRewrite>>> {
+        let mut seen = HashSet::new();
+        let mut kept = vec![];
+        for rewrite in rewrites.iter()? {
+            let rewrite = Self::from_any(rewrite?)?;
+            if seen.insert(rewrite.borrow(py).alpha_key()) {
+                kept.push(rewrite);
+            }
+        }
+        Ok(kept)
+    }
+}
+
+/// `run()`'s `rewrites` argument, parsed once to be run many times.
+///
+/// Building one turns every `(name, lhs, rhs)` tuple into a `Rewrite` and
+/// reads the group configs, so malformed rules raise here rather than in a
+/// later `run()`. The e-graph's commutative operators are still applied by
+/// each run, as a rule set isn't tied to an e-graph.
+#[pyclass]
+pub struct PyRuleSet {
+    rewrites: Vec<Py<PyRewrite>>,
+    groups: RuleGroups,
+}
+
+#[pymethods]
+impl PyRuleSet {
+    #[new]
+    fn new(py: Python, rewrites: &PyAny) -> PyResult<Self> {
+        let (rewrites, groups) = PyRewrite::groups_from_any(py, rewrites)?;
+        Ok(Self { rewrites, groups })
+    }
+
+    fn __len__(&self) -> usize {
+        self.rewrites.len()
+    }
+
+    /// The rule set's rewrites, in order.
+    #[getter]
+    fn rewrites(&self) -> Vec<Py<PyRewrite>> {
+        self.rewrites.clone()
+    }
+}
+
+#[pymethods]
+impl PyPattern {
+    #[new]
+    fn new(tree: &PyAny) -> PyResult<Self> {
+        tree.extract()
+    }
+
+    /// The pattern as an egg-style s-expression, e.g. `(Add ?x 0)`.
+    fn to_sexpr(&self, py: Python) -> String {
+        pattern_sexpr(py, &self.pattern.ast)
+    }
+
+    /// Patterns are equal up to renaming variables, so `Add(a, b)` equals
+    /// `Add(x, y)` but not `Add(x, x)`.
+    fn __richcmp__(&self, py: Python, other: &PyAny, op: CompareOp) -> PyObject {
+        let other = match other.downcast::<PyCell<PyPattern>>() {
+            Ok(other) => other.borrow(),
+            Err(_) => return py.NotImplemented(),
+        };
+        let equal = alpha_key(&self.pattern.ast) == alpha_key(&other.pattern.ast);
+        match op {
+            CompareOp::Eq => equal.into_py(py),
+            CompareOp::Ne => (!equal).into_py(py),
+            _ => py.NotImplemented(),
+        }
+    }
+
+    fn __hash__(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        alpha_key(&self.pattern.ast).hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum AlphaKey {
+    /// Operator (by tag pointer, like `PythonNode` equality) and arity.
+    Node(usize, usize),
+    /// Variables numbered by first occurrence.
+    Var(usize),
+}
+
+/// A pattern's nodes in preorder from the root, with variables numbered in
+/// order of first occurrence, so alpha-equivalent patterns get equal keys.
+#[derive(PartialEq, Eq, Hash)]
+enum AlphaApplier {
+    Pattern(Vec<AlphaKey>),
+    /// The callable's pointer, its `context` flag and the numbered var names.
+    Callable(usize, bool, Vec<(usize, String)>),
+}
+
+type RewriteKey = (Vec<AlphaKey>, Vec<(usize, usize)>, AlphaApplier);
+
+fn alpha_key(ast: &PatternAst<PythonNode>) -> Vec<AlphaKey> {
+    alpha_key_with(ast, &mut HashMap::new())
+}
+
+/// The key of `ast`, numbering its vars after those already in `vars`.
+fn alpha_key_with(ast: &PatternAst<PythonNode>, vars: &mut HashMap<Var, usize>) -> Vec<AlphaKey> {
+    fn visit(
+        nodes: &[ENodeOrVar<PythonNode>],
+        id: Id,
+        vars: &mut HashMap<Var, usize>,
+        out: &mut Vec<AlphaKey>,
+    ) {
+        match &nodes[usize::from(id)] {
+            ENodeOrVar::Var(var) => {
+                let next = vars.len();
+                out.push(AlphaKey::Var(*vars.entry(*var).or_insert(next)));
+            }
+            ENodeOrVar::ENode(node) => {
+                out.push(AlphaKey::Node(
+                    node.class.as_ptr() as usize,
+                    node.children.len(),
+                ));
+                for &child in &node.children {
+                    visit(nodes, child, vars, out);
+                }
+            }
+        }
+    }
+    let nodes = ast.as_ref();
+    let mut out = Vec::with_capacity(nodes.len());
+    visit(nodes, Id::from(nodes.len() - 1), vars, &mut out);
+    out
+}
+
+/// Render a pattern with `?var` variables and `node_label` operators.
+pub(crate) fn pattern_sexpr(py: Python, ast: &PatternAst<PythonNode>) -> String {
+    let mut strs: Vec<String> = Vec::with_capacity(ast.as_ref().len());
+    for node in ast.as_ref() {
+        let s = match node {
+            ENodeOrVar::Var(var) => var.to_string(),
+            ENodeOrVar::ENode(node) => {
+                let label = node_label(py, node, |child| match &ast[child] {
+                    ENodeOrVar::ENode(leaf) if leaf.is_leaf() => leaf.class.clone_ref(py),
+                    _ => PyString::new(py, &strs[usize::from(child)]).into(),
+                });
+                if node.is_leaf() {
+                    label
+                } else {
+                    let children: Vec<&str> = node
+                        .children
+                        .iter()
+                        .map(|&child| strs[usize::from(child)].as_str())
+                        .collect();
+                    format!("({} {})", label, children.join(" "))
+                }
+            }
+        };
+        strs.push(s);
+    }
+    strs.pop().unwrap_or_default()
 }
 
 impl<'source> FromPyObject<'source> for PyPattern {
     fn extract(obj: &'source PyAny) -> PyResult<Self> {
+        Self::build(obj, BuildLimits::default())
+    }
+}
+
+impl PyPattern {
+    /// The pattern for `obj`, nested and with as many children per node as
+    /// `limits` allow.
+    pub fn build(obj: &PyAny, limits: BuildLimits) -> PyResult<Self> {
+        if let Ok(pattern) = obj.downcast::<PyCell<PyPattern>>() {
+            let pattern = pattern.borrow();
+            return Ok(Self {
+                pattern: pattern.pattern.clone(),
+                kinds: pattern.kinds.clone(),
+                structural: pattern.structural.clone(),
+            });
+        }
         let mut ast = PatternAst::default();
-        build_pattern(&mut ast, obj);
+        let mut kinds = vec![];
+        let mut structural = vec![];
+        build_pattern(&mut ast, &mut kinds, &mut structural, obj, limits, 1)?;
         let pattern = Pattern::from(ast);
-        Ok(Self { pattern })
+        Ok(Self {
+            pattern,
+            kinds,
+            structural,
+        })
     }
 }
 
 #[pyclass(subclass)]
+#[derive(Clone)]
 pub struct PyEGraph {
     pub egraph: EGraph<PythonNode, PythonAnalysis>,
+    pub stop_reason: Option<PyStopReason>,
+    /// The iterations of the last `run()`.
+    pub iterations: Vec<Iteration<()>>,
+    /// Nodes added by the last `run()`'s hook in each iteration it ran.
+    pub hook_nodes: Vec<usize>,
+    /// The passes over the rewrites made by the last `run(ordered=True)`.
+    pub passes: Option<usize>,
+    /// The enodes the last `run(on_node_limit="prune")` pruned, and where the
+    /// ids handed out before it went, if it pruned any.
+    pub pruned: Option<(usize, HashMap<Id, Id>)>,
+    /// When the wall-clock budget set by `set_deadline` runs out.
+    pub deadline: Option<Instant>,
+    /// Binary operator classes whose pattern nodes `run()` and `search()` also
+    /// match with swapped arguments.
+    pub commutative_ops: Vec<PyObject>,
+    /// The default cost model of the extraction methods.
+    pub cost_model: CostModel,
+    /// The ids handed out by `op_label_id`.
+    pub labels: LabelInterner,
+    /// Set by `freeze`: the methods changing the e-graph raise.
+    pub frozen: bool,
+}
+
+/// A match of a searched pattern: `(id, {var: id})`, var names lacking the `?`.
+type SearchMatch = (PyId, HashMap<String, PyId>);
+
+fn search_match(eclass: Id, vars: &[Var], subst: &Subst) -> SearchMatch {
+    let bindings = vars
+        .iter()
+        .map(|&var| (var.to_string()[1..].to_string(), PyId(subst[var])))
+        .collect();
+    (PyId(eclass), bindings)
+}
+
+/// A new e-graph made of `nodes`, each given with its e-class in `old`, with
+/// the analysis data of their classes there, and the id of each class in it.
+/// The enodes are added in the order they were added to `old`; a class none
+/// of whose enodes can be added, its children never all being there, is left
+/// out.
+fn rebuilt_from(
+    old: &EGraph<PythonNode, PythonAnalysis>,
+    mut nodes: Vec<(Id, &PythonNode)>,
+) -> (EGraph<PythonNode, PythonAnalysis>, HashMap<Id, Id>) {
+    let order = insertion_order(old);
+    nodes.sort_by_key(|(_, node)| order.get(*node).copied().unwrap_or(usize::MAX));
+    let mut egraph = EGraph::new(old.analysis.fresh());
+    let mut ids: HashMap<Id, Id> = HashMap::new();
+    // add each enode once its children are there, until none can be
+    loop {
+        let before = nodes.len();
+        nodes.retain(|&(class, node)| {
+            let children: Option<Vec<Id>> = node
+                .children
+                .iter()
+                .map(|child| ids.get(&old.find(*child)).copied())
+                .collect();
+            let children = match children {
+                Some(children) => children,
+                None => return true,
+            };
+            let mut children = children.into_iter();
+            let added = egraph.add(node.clone().map_children(|_| children.next().unwrap()));
+            match ids.get(&class) {
+                Some(&existing) => {
+                    egraph.union(existing, added);
+                }
+                None => {
+                    ids.insert(class, added);
+                }
+            }
+            false
+        });
+        if nodes.len() == before {
+            break;
+        }
+    }
+    egraph.rebuild();
+    for (&class, new) in ids.iter_mut() {
+        *new = egraph.find(*new);
+        egraph.set_analysis_data(*new, old[class].data.clone());
+    }
+    (egraph, ids)
+}
+
+/// The keyword arguments of `EGraph.run()` but `yield_each_iteration`, with
+/// its defaults.
+#[derive(Clone)]
+struct RunOptions {
+    iter_limit: usize,
+    time_limit: f64,
+    node_limit: usize,
+    validate: bool,
+    hook: Option<PyObject>,
+    ordered: bool,
+    on_union: Option<PyObject>,
+    growth_factor: Option<f64>,
+    log: Option<PyObject>,
+    log_level: i64,
+    on_node_limit: String,
+    until: Option<PyObject>,
+    apply_limit: Option<usize>,
+    guard: Option<PyObject>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            iter_limit: 10,
+            time_limit: 10.0,
+            node_limit: 100_000,
+            validate: false,
+            hook: None,
+            ordered: false,
+            on_union: None,
+            growth_factor: None,
+            log: None,
+            log_level: 20,
+            on_node_limit: "stop".to_owned(),
+            until: None,
+            apply_limit: None,
+            guard: None,
+        }
+    }
+}
+
+/// The iterations of `EGraph.run(..., yield_each_iteration=True)`, each run
+/// when the iterator is advanced and yielding that iteration's `RunResult`.
+/// Between steps the caller can inspect or `copy()` the e-graph, and it
+/// stops the run by no longer advancing. It ends after the step whose stop
+/// reason isn't `"iteration_limit"` or after `iter_limit` steps, with
+/// `time_limit` counted from the first step on. Each step is a run of its
+/// own, so backoff bans don't carry over. `validate`, `hook`, `ordered`,
+/// `log` and `until` are for a run driven by `run()` itself and can't be
+/// used.
+#[pyclass]
+pub struct PyRunSteps {
+    egraph: Py<PyEGraph>,
+    rules: Py<PyRuleSet>,
+    iterations_left: usize,
+    /// The run's `time_limit`, counted from the first step.
+    time_limit: Duration,
+    deadline: Option<Instant>,
+    /// The options of each step; its `apply_limit` is what is left of the
+    /// run's.
+    options: RunOptions,
+    done: bool,
+}
+
+#[pymethods]
+impl PyRunSteps {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> PyResult<Option<PyRunResult>> {
+        if self.done || self.iterations_left == 0 {
+            return Ok(None);
+        }
+        let mut graph = self.egraph.try_borrow_mut(py)?;
+        let time_limit = self.time_limit;
+        let deadline = *self
+            .deadline
+            .get_or_insert_with(|| Instant::now() + time_limit);
+        let time_limit = deadline.saturating_duration_since(Instant::now());
+        let options = RunOptions {
+            iter_limit: 1,
+            time_limit: time_limit.as_secs_f64(),
+            ..self.options.clone()
+        };
+        let result = graph.run_all(py, self.rules.as_ref(py), options);
+        let result = match result {
+            Ok(result) => result,
+            Err(err) => {
+                self.done = true;
+                return Err(err);
+            }
+        };
+        self.iterations_left -= 1;
+        if let Some(left) = &mut self.options.apply_limit {
+            *left = left.saturating_sub(result.applied.values().sum());
+        }
+        self.done = result.stop_reason.kind != "iteration_limit";
+        Ok(Some(result))
+    }
+}
+
+/// The lazy matches of `EGraph.iter_search`.
+#[pyclass]
+pub struct PySearchIter {
+    egraph: Py<PyEGraph>,
+    searcher: SharedSearcher,
+    vars: Vec<Var>,
+    /// The e-classes to search, from `next` on.
+    classes: Vec<Id>,
+    next: usize,
+    /// The matches found in the last e-class searched and not yet returned.
+    pending: VecDeque<SearchMatch>,
+}
+
+#[pymethods]
+impl PySearchIter {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> PyResult<Option<SearchMatch>> {
+        let mut graph = self.egraph.try_borrow_mut(py)?;
+        if !graph.egraph.clean {
+            graph.egraph.rebuild();
+        }
+        while self.pending.is_empty() && self.next < self.classes.len() {
+            let id = self.classes[self.next];
+            self.next += 1;
+            if graph.egraph.find(id) != id {
+                continue;
+            }
+            if let Some(found) = self.searcher.search_eclass(&graph.egraph, id) {
+                self.pending.extend(
+                    found
+                        .substs
+                        .iter()
+                        .map(|subst| search_match(found.eclass, &self.vars, subst)),
+                );
+            }
+        }
+        graph.egraph.analysis.raise_pending()?;
+        Ok(self.pending.pop_front())
+    }
 }
 
 #[pyclass]
@@ -167,129 +879,1152 @@ fn nested_vector_to_list<T: Copy + ToPyObject>(py: Python, data: &[Vec<T>]) -> P
 
 #[pymethods]
 impl PyEGraph {
+    /// `key`, if given, maps each leaf object to a hashable canonical key used
+    /// for hash-consing: leaves with equal keys become one node, so equal keys
+    /// must mean interchangeable objects. `canonical_leaf`, if given, maps each
+    /// leaf object to the object stored in its place (before any `key`), e.g.
+    /// `float` to store `1` and `1.0` both as `1.0`; it should map
+    /// equal-by-value leaves to equal representatives, which then hash-cons
+    /// into one enode. Both also apply to the leaves that rules add, but not
+    /// to the leaves of a left-hand side, which are matched as written. An
+    /// exception either raises comes out of the call adding the leaf, or
+    /// stops `run` and comes out of it.
+    /// `commutative_ops` is the initial value of the property of that name.
+    /// `analysis` names a built-in analysis used instead of an `eval`
+    /// callback, `"depth"`, `"const_int"` or `"fold"`, which folds constant
+    /// arithmetic (see `class_data`). Merging e-classes whose data are both
+    /// set but unequal (by `==`) raises `ValueError` from the call that
+    /// merged them, such as `union`, `set_constant`, `rebuild` or `run`;
+    /// `"depth"` and `"fold"` data always merge.
+    /// Adding a term nested more than `max_depth` deep (1000 by default) or
+    /// with a node of more than `max_arity` children (unlimited by default)
+    /// raises `ValueError`, as does a cyclic object. This holds however the
+    /// term arrives: through `add`, `add_dict` or `add_expr`, as analysis
+    /// data, or from a rule's callback, whose error stops `run`.
+    /// `capacity`, a rough count of the enodes to be added, only pre-sizes the
+    /// e-graph's bookkeeping of when each enode was added; egg has no way to
+    /// pre-size its own tables, which still grow as needed.
     #[new]
-    fn new(eval: Option<PyObject>) -> Self {
-        Self {
-            egraph: EGraph::new(PythonAnalysis { eval }),
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        eval: Option<PyObject>,
+        key: Option<PyObject>,
+        commutative_ops: Option<&PyAny>,
+        analysis: Option<&str>,
+        max_depth: Option<usize>,
+        max_arity: Option<usize>,
+        capacity: Option<usize>,
+        canonical_leaf: Option<PyObject>,
+    ) -> PyResult<Self> {
+        if analysis.is_some() && eval.is_some() {
+            return Err(PyValueError::new_err("Give either eval or analysis"));
+        }
+        let builtin = analysis.map(BuiltinAnalysis::from_name).transpose()?;
+        let mut analysis = PythonAnalysis::new(eval, key);
+        analysis.builtin = builtin;
+        analysis.canonicalize = canonical_leaf;
+        let defaults = BuildLimits::default();
+        analysis.build_limits = BuildLimits {
+            depth: max_depth.unwrap_or(defaults.depth),
+            arity: max_arity.unwrap_or(defaults.arity),
+        };
+        analysis.reserve(capacity.unwrap_or(0));
+        let mut egraph = Self::with_analysis(analysis);
+        if let Some(ops) = commutative_ops {
+            egraph.set_commutative_ops(ops)?;
+        }
+        Ok(egraph)
+    }
+
+    /// Binary operator classes treated as commutative when searching: in
+    /// `run()` and `search()`, a pattern node of one of these classes matches
+    /// both argument orders. Nested commutative nodes are swapped independently, so
+    /// `Add(Mul(?a, ?b), ?c)` also matches `Add(?c, Mul(?b, ?a))`; a pattern
+    /// with `k` such nodes costs up to `2^k` searches. Operators with any other
+    /// number of arguments are matched as written.
+    #[getter]
+    fn commutative_ops(&self) -> Vec<PyObject> {
+        self.commutative_ops.clone()
+    }
+
+    #[setter]
+    fn set_commutative_ops(&mut self, ops: &PyAny) -> PyResult<()> {
+        self.commutative_ops = ops
+            .iter()?
+            .map(|op| Ok(op?.downcast::<PyType>()?.into()))
+            .collect::<PyResult<_>>()?;
+        Ok(())
+    }
+
+    fn add(&mut self, expr: &PyAny) -> PyResult<PyId> {
+        self.check_mutable()?;
+        build_node(&mut self.egraph, expr).map(PyId)
+    }
+
+    /// Add a term given as nested `{"op": ..., "children": [...]}` dicts.
+    /// `op` may be a node class or a name looked up in `ops`.
+    #[args(ops = "None")]
+    fn add_dict(&mut self, expr: &PyAny, ops: Option<&PyDict>) -> PyResult<PyId> {
+        self.check_mutable()?;
+        build_dict_node(&mut self.egraph, expr, ops).map(PyId)
+    }
+
+    /// Add a term written as an s-expression, e.g. `"(Add x (Mul 2 y))"`.
+    /// Operator names are looked up in `ops`; other atoms are leaves, parsed
+    /// as numbers where possible and otherwise kept as strings.
+    #[args(ops = "None")]
+    fn add_expr(&mut self, py: Python, expr: &str, ops: Option<&PyDict>) -> PyResult<PyId> {
+        self.check_mutable()?;
+        build_sexpr_node(&mut self.egraph, py, expr, ops).map(PyId)
+    }
+
+    /// Read equalities from the file at `path`, one `lhs ; rhs` pair of
+    /// s-expressions (as for `add_expr`, but with no `;` inside them) per line,
+    /// skipping blank lines and `#` comments. Adds and unions each pair,
+    /// rebuilds, and returns how many unions merged two distinct classes. A
+    /// line that can't be parsed or merged raises `ValueError` naming it.
+    #[args(ops = "None")]
+    fn load_equalities(&mut self, py: Python, path: &str, ops: Option<&PyDict>) -> PyResult<usize> {
+        self.check_mutable()?;
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| PyValueError::new_err(format!("Can't read {}: {}", path, err)))?;
+        let mut changed = 0;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (lhs, rhs) = line.split_once(';').ok_or_else(|| {
+                PyValueError::new_err(format!("{}:{}: expected `lhs ; rhs`", path, i + 1))
+            })?;
+            let lhs = build_sexpr_node(&mut self.egraph, py, lhs, ops)?;
+            let rhs = build_sexpr_node(&mut self.egraph, py, rhs, ops)?;
+            if self.egraph.union(lhs, rhs) {
+                changed += 1;
+            }
+            self.egraph.analysis.raise_pending().map_err(|err| {
+                PyValueError::new_err(format!("{}:{}: {}", path, i + 1, err.value(py)))
+            })?;
         }
+        self.egraph.rebuild();
+        self.egraph
+            .analysis
+            .raise_pending()
+            .map_err(|err| PyValueError::new_err(format!("{}: {}", path, err.value(py))))?;
+        Ok(changed)
     }
 
-    fn add(&mut self, expr: &PyAny) -> PyId {
-        PyId(build_node(&mut self.egraph, expr))
+    /// Add a term given as a topologically ordered, egg `RecExpr`-style list of
+    /// `(op, [child_indices])` pairs and return the id of its root, the last
+    /// entry. Leaves have no children and any leaf object as `op`; every child
+    /// index must refer to an earlier entry.
+    #[pyo3(name = "from_nodes")]
+    fn add_nodes(&mut self, nodes: &PyAny) -> PyResult<PyId> {
+        self.check_mutable()?;
+        build_node_list(&mut self.egraph, nodes).map(PyId)
     }
 
+    /// Union the e-classes of `exprs`. Each argument may be an expression,
+    /// which is added first, or an `Id` from an earlier call, used as is.
     #[args(exprs = "*")]
-    fn union(&mut self, exprs: &PyTuple) -> bool {
+    fn union(&mut self, exprs: &PyTuple) -> PyResult<bool> {
         assert!(exprs.len() > 1);
+        self.check_mutable()?;
         let mut exprs = exprs.iter();
-        let id = self.add(exprs.next().unwrap()).0;
+        let id = build_node(&mut self.egraph, exprs.next().unwrap())?;
         let mut did_something = false;
         for expr in exprs {
-            let added = self.add(expr);
-            did_something |= self.egraph.union(id, added.0);
+            let added = build_node(&mut self.egraph, expr)?;
+            did_something |= self.egraph.union(id, added);
         }
-        did_something
+        self.egraph.analysis.raise_pending()?;
+        Ok(did_something)
+    }
+
+    /// Union `expr` (an expression or an `Id`) with `value` added as a leaf,
+    /// even if it is a tuple or other term-like object, and return the
+    /// canonical id of the merged e-class.
+    fn set_constant(&mut self, expr: &PyAny, value: &PyAny) -> PyResult<PyId> {
+        self.check_mutable()?;
+        let id = build_node(&mut self.egraph, expr)?;
+        let constant = add_leaf(&mut self.egraph, value)?;
+        self.egraph.union(id, constant);
+        self.egraph.analysis.raise_pending()?;
+        Ok(PyId(self.egraph.find(id)))
     }
 
+    /// Whether all of `exprs` are in one e-class; like `union`, arguments may
+    /// mix expressions and `Id`s.
     #[args(exprs = "*")]
-    fn equiv(&mut self, exprs: &PyTuple) -> bool {
+    fn equiv(&mut self, exprs: &PyTuple) -> PyResult<bool> {
         assert!(exprs.len() > 1);
         let mut exprs = exprs.iter();
-        let id = self.add(exprs.next().unwrap()).0;
+        let id = self.term_id(exprs.next().unwrap())?;
         let mut all_equiv = true;
         for expr in exprs {
-            let added = self.add(expr);
-            all_equiv &= added.0 == id
+            let added = self.term_id(expr)?;
+            all_equiv &= added == id
         }
-        all_equiv
+        Ok(all_equiv)
     }
 
-    fn rebuild(&mut self) -> usize {
-        self.egraph.rebuild()
+    /// Whether all of `ids` are in one e-class, without adding anything: the
+    /// read-only counterpart of `equiv` for ids already in hand. Raises
+    /// `ValueError` for an id that isn't from this e-graph.
+    #[args(ids = "*")]
+    fn all_equal(&self, ids: Vec<PyId>) -> PyResult<bool> {
+        let ids = ids
+            .into_iter()
+            .map(|id| self.known(id))
+            .collect::<PyResult<Vec<Id>>>()?;
+        Ok(ids
+            .iter()
+            .all(|&id| self.egraph.find(id) == self.egraph.find(ids[0])))
     }
 
-    #[args(iter_limit = "10", time_limit = "10.0", node_limit = "100_000")]
-    fn run(
-        &mut self,
-        rewrites: &PyList,
-        iter_limit: usize,
-        time_limit: f64,
-        node_limit: usize,
-    ) -> PyResult<()> {
-        let refs = rewrites
-            .iter()
-            .map(FromPyObject::extract)
-            .collect::<PyResult<Vec<PyRef<PyRewrite>>>>()?;
-        let egraph = std::mem::take(&mut self.egraph);
-        let scheduled_runner = Runner::<PythonNode, PythonAnalysis>::default();
-        let runner = scheduled_runner
-            .with_iter_limit(iter_limit)
-            .with_node_limit(node_limit)
-            .with_time_limit(Duration::from_secs_f64(time_limit))
-            .with_egraph(egraph)
-            .run(refs.iter().map(|r| &r.rewrite));
+    /// The canonical id of `id`'s e-class. Ids of the same class are equal
+    /// after `find`, so use it to re-key dicts of ids after unions.
+    fn find(&self, id: PyId) -> PyId {
+        PyId(self.egraph.find(id.0))
+    }
 
-        self.egraph = runner.egraph;
-        Ok(())
+    /// The current canonical id of `expr`: for an `Id`, however stale, the
+    /// id of the e-class it was merged into, and for an expression that of
+    /// its e-class, added first if it isn't in the e-graph. Raises
+    /// `ValueError` for an `Id` this e-graph didn't hand out.
+    fn resolve(&mut self, expr: &PyAny) -> PyResult<PyId> {
+        let id = match expr.extract::<PyId>() {
+            Ok(id) => self.known(id)?,
+            Err(_) => self.term_id(expr)?,
+        };
+        Ok(PyId(self.egraph.find(id)))
     }
 
-    #[args(exprs = "*")]
-    fn extract(&mut self, py: Python, exprs: &PyTuple) -> Vec<PyObject> {
-        let ids: Vec<Id> = exprs.iter().map(|expr| self.add(expr).0).collect();
-        let extractor = Extractor::new(&self.egraph, AstSize);
-        ids.iter()
-            .map(|&id| {
-                let (_cost, recexpr) = extractor.find_best(id);
-                reconstruct(py, &recexpr)
-            })
-            .collect()
+    fn rebuild(&mut self) -> PyResult<usize> {
+        let unions = self.egraph.rebuild();
+        self.egraph.analysis.raise_pending()?;
+        Ok(unions)
     }
 
-    fn dump(&self) -> PyResult<()> {
-        let dump = self.egraph.dump();
-        println!("{:?}", dump);
+    /// Rebuild, then make the e-graph read-only: from then on `add`, `union`,
+    /// `run` and the other methods changing its e-classes raise
+    /// `RuntimeError`. Queries still work, but one given an expression not in
+    /// the e-graph raises `ValueError` instead of adding it. A `copy` is not
+    /// frozen. A merge error from the rebuild is raised, leaving it unfrozen.
+    fn freeze(&mut self) -> PyResult<()> {
+        self.egraph.rebuild();
+        self.egraph.analysis.raise_pending()?;
+        self.frozen = true;
         Ok(())
     }
 
-    fn prepare_ilp_snapshot(
-        &mut self,
-        py: Python,
-        root: PyId,
-        cost_fn: PyObject,
-    ) -> PyResult<Py<IlpSnapshot>> {
-        let extractor = Extractor::new(&self.egraph, AstSize);
-        let m_id_map: Vec<Id> = self
-            .egraph
-            .classes()
-            .map(|c| self.egraph.find(c.id))
-            .collect();
-        let mut id_m_map = HashMap::new();
-        for (idx, id) in m_id_map.iter().enumerate() {
-            id_m_map.insert(*id, idx);
-        }
-
-        let num_classes = m_id_map.len();
-        let mut e_m = vec![Vec::new(); num_classes];
-        let mut h_i: Vec<Vec<usize>> = Vec::new();
-        let mut g_i: Vec<usize> = Vec::new();
-        let mut costs: Vec<f64> = Vec::new();
-        let mut nodes: Vec<PythonNode> = Vec::new();
-        let mut child_cache: HashMap<Id, PyObject> = HashMap::new();
-
-        let root_m = *id_m_map
-            .get(&self.egraph.find(root.0))
-            .ok_or_else(|| PyValueError::new_err("Root eclass not found"))?;
+    /// Whether `freeze` has been called.
+    #[getter]
+    fn frozen(&self) -> bool {
+        self.frozen
+    }
 
-        for class in self.egraph.classes() {
-            let m = *id_m_map.get(&self.egraph.find(class.id)).unwrap();
+    /// Check that every e-class id is canonical, every enode's children are
+    /// canonical ids of live e-classes, and the e-graph is congruent: each
+    /// enode is in exactly one e-class, which looking it up finds. Returns
+    /// `True`, or raises `ValueError` naming the first broken invariant. The
+    /// e-graph is checked as it stands, so unions not yet rebuilt show up.
+    fn check_invariants(&self) -> PyResult<bool> {
+        let egraph = &self.egraph;
+        let classes: HashSet<Id> = egraph.classes().map(|class| class.id).collect();
+        let mut owners: HashMap<&PythonNode, Id> = HashMap::new();
+        for class in egraph.classes() {
+            if egraph.find(class.id) != class.id {
+                return Err(PyValueError::new_err(format!(
+                    "e-class {} isn't canonical: it was merged into {}",
+                    class.id,
+                    egraph.find(class.id)
+                )));
+            }
             for node in class.iter() {
-                let idx = nodes.len();
-                e_m[m].push(idx);
-                let children: Vec<usize> = node
-                    .children()
+                if let Some(&child) = node
+                    .children
                     .iter()
-                    .map(|id| *id_m_map.get(&self.egraph.find(*id)).unwrap())
-                    .collect();
+                    .find(|&&child| egraph.find(child) != child || !classes.contains(&child))
+                {
+                    return Err(PyValueError::new_err(format!(
+                        "an enode of e-class {} has child {}, which isn't a live e-class",
+                        class.id, child
+                    )));
+                }
+                if let Some(other) = owners.insert(node, class.id) {
+                    return Err(PyValueError::new_err(format!(
+                        "an enode is in both e-class {} and e-class {}",
+                        other, class.id
+                    )));
+                }
+                if egraph.lookup(node.clone()) != Some(class.id) {
+                    return Err(PyValueError::new_err(format!(
+                        "an enode of e-class {} isn't found there by lookup",
+                        class.id
+                    )));
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// An independent copy of this e-graph, of the same Python class.
+    fn copy(slf: &PyCell<Self>) -> PyResult<PyObject> {
+        let copy = slf.get_type().call0()?;
+        {
+            let this = slf.borrow();
+            let mut other = copy.downcast::<PyCell<Self>>()?.borrow_mut();
+            *other = Self {
+                frozen: false,
+                ..this.clone()
+            };
+        }
+        Ok(copy.into())
+    }
+
+    /// A new e-graph of the same Python class holding only the e-classes
+    /// reachable from `roots` through any of their enodes, and a dict from
+    /// the id of each such class here to its id there. It gets this e-graph's
+    /// `eval`, `key`, `commutative_ops` and cost model, each class keeps its
+    /// analysis data, and the enodes are added in the order they were added
+    /// here. A class with no finite term can't be rebuilt enode by enode, so
+    /// it is left out along with the enodes using it.
+    fn subgraph(slf: &PyCell<Self>, roots: Vec<PyId>) -> PyResult<(PyObject, HashMap<PyId, PyId>)> {
+        slf.borrow_mut().egraph.rebuild();
+        let this = slf.borrow();
+        let mut todo = roots
+            .into_iter()
+            .map(|id| this.known(id))
+            .collect::<PyResult<Vec<_>>>()?;
+        let mut reachable = HashSet::new();
+        while let Some(id) = todo.pop() {
+            let id = this.egraph.find(id);
+            if reachable.insert(id) {
+                todo.extend(
+                    this.egraph[id]
+                        .iter()
+                        .flat_map(|node| node.children.clone()),
+                );
+            }
+        }
+
+        let nodes = reachable
+            .iter()
+            .flat_map(|&id| this.egraph[id].iter().map(move |node| (id, node)))
+            .collect();
+        let (egraph, ids) = rebuilt_from(&this.egraph, nodes);
+
+        let copy = slf.get_type().call0()?;
+        {
+            let mut other = copy.downcast::<PyCell<Self>>()?.borrow_mut();
+            other.egraph = egraph;
+            other.commutative_ops = this.commutative_ops.clone();
+            other.cost_model = this.cost_model.clone();
+            other.labels = this.labels.clone();
+        }
+        let ids = ids
+            .into_iter()
+            .map(|(old, new)| (PyId(old), PyId(new)))
+            .collect();
+        Ok((copy.into(), ids))
+    }
+
+    /// Replace the analysis `eval`, and any built-in analysis. With
+    /// `reanalyze`, every existing e-class is re-analysed (calling `eval` on
+    /// each enode and rebuilding), which may be expensive on large graphs;
+    /// otherwise only new nodes see the new `eval`.
+    #[args(reanalyze = "true")]
+    fn set_eval(&mut self, eval: Option<PyObject>, reanalyze: bool) -> PyResult<()> {
+        self.check_mutable()?;
+        self.egraph.analysis.eval = eval;
+        self.egraph.analysis.builtin = None;
+        if !reanalyze {
+            return Ok(());
+        }
+        let classes: Vec<(Id, Vec<PythonNode>)> = self
+            .egraph
+            .classes()
+            .map(|class| (class.id, class.nodes.clone()))
+            .collect();
+        for (id, nodes) in classes {
+            let data = nodes
+                .iter()
+                .find_map(|node| PythonAnalysis::make(&self.egraph, node));
+            self.egraph.set_analysis_data(id, data);
+        }
+        self.egraph.rebuild();
+        self.egraph.analysis.raise_pending()
+    }
+
+    /// Run the rewrites (any iterable, consumed once, or a `RuleSet`) until
+    /// saturation or a limit is hit, returning a `RunResult` summing up the
+    /// run; see also `stop_reason`. Items may also be `(rewrites, config)`
+    /// groups, whose rules are scheduled with the backoff parameters in
+    /// `config`: `match_limit`, `ban_length`, and `ban=False` to never ban.
+    /// - `iter_limit`, `time_limit`: in iterations and (non-negative) seconds;
+    ///   a deadline (see `set_deadline`) cuts `time_limit` to the time left.
+    /// - `node_limit`: checked between iterations, so the one crossing it is
+    ///   applied in full.
+    /// - `validate`: warn for each rewrite (by name) that changed nothing.
+    /// - `hook(iteration)`: called before each iteration's search; it may
+    ///   return terms to add and `(a, b)` pairs to union.
+    /// - `ordered`: apply the rewrites one at a time to saturation, by
+    ///   priority and then list order, in passes; see `last_run_passes`.
+    /// - `on_union(a, b)`: told the canonical ids of each two e-classes a
+    ///   rewrite or the hook is about to merge.
+    /// - `guard(a, b)`: may refuse such a merge, which stops the run with
+    ///   `ValueError`.
+    /// - `growth_factor`: the node limit as a multiple of the starting size.
+    /// - `log`, `log_level`: a `logging.Logger` and the level to record each
+    ///   iteration's size at.
+    /// - `on_node_limit`: `"stop"`, or `"prune"` (experimental) to prune
+    ///   costly enodes at the node limit and carry on; see `last_run_pruned`.
+    /// - `until(egraph)`: stop, with message `"until"`, once it returns true
+    ///   for a frozen copy of the e-graph, asked after every iteration but
+    ///   the last.
+    /// - `apply_limit`: stop, with message `"apply_limit"`, once that many
+    ///   rewrite applications were made.
+    /// - `yield_each_iteration` (keyword only): return a `RunSteps` iterator
+    ///   stepping the run instead of running it.
+    /// `hook`, `until` and `on_node_limit="prune"` can't be used with
+    /// `ordered`; a raising callback stops the run and is re-raised.
+    #[args(
+        iter_limit = "10",
+        time_limit = "10.0",
+        node_limit = "100_000",
+        validate = "false",
+        hook = "None",
+        ordered = "false",
+        on_union = "None",
+        growth_factor = "None",
+        log = "None",
+        log_level = "20",
+        on_node_limit = "\"stop\"",
+        until = "None",
+        apply_limit = "None",
+        guard = "None",
+        "*",
+        yield_each_iteration = "false"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn run(
+        slf: PyRefMut<Self>,
+        py: Python,
+        rewrites: &PyAny,
+        iter_limit: usize,
+        time_limit: f64,
+        node_limit: usize,
+        validate: bool,
+        hook: Option<PyObject>,
+        ordered: bool,
+        on_union: Option<PyObject>,
+        growth_factor: Option<f64>,
+        log: Option<PyObject>,
+        log_level: i64,
+        on_node_limit: &str,
+        until: Option<PyObject>,
+        apply_limit: Option<usize>,
+        guard: Option<PyObject>,
+        yield_each_iteration: bool,
+    ) -> PyResult<PyObject> {
+        let options = RunOptions {
+            iter_limit,
+            time_limit,
+            node_limit,
+            validate,
+            hook,
+            ordered,
+            on_union,
+            growth_factor,
+            log,
+            log_level,
+            on_node_limit: on_node_limit.to_owned(),
+            until,
+            apply_limit,
+            guard,
+        };
+        if !yield_each_iteration {
+            let mut slf = slf;
+            let result = slf.run_all(py, rewrites, options)?;
+            return Ok(result.into_py(py));
+        }
+        slf.check_mutable()?;
+        let unsupported = [
+            ("validate", options.validate),
+            ("hook", options.hook.is_some()),
+            ("ordered", options.ordered),
+            ("log", options.log.is_some()),
+            ("until", options.until.is_some()),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, used)| *used) {
+            return Err(PyValueError::new_err(format!(
+                "{} can't be used with yield_each_iteration",
+                name
+            )));
+        }
+        let node_limit = match growth_factor {
+            Some(factor) if factor.is_nan() || factor < 1.0 => {
+                return Err(PyValueError::new_err(format!(
+                    "growth_factor must be at least 1, got {}",
+                    factor
+                )))
+            }
+            Some(factor) => (slf.egraph.total_size() as f64 * factor) as usize,
+            None => node_limit,
+        };
+        let rules = Py::new(py, PyRuleSet::new(py, rewrites)?)?;
+        let steps = PyRunSteps {
+            egraph: slf.into(),
+            rules,
+            iterations_left: iter_limit,
+            time_limit: seconds("time_limit", time_limit)?,
+            deadline: None,
+            options: RunOptions {
+                node_limit,
+                growth_factor: None,
+                ..options
+            },
+            done: false,
+        };
+        Ok(steps.into_py(py))
+    }
+
+    /// Match `pattern` against the e-graph, returning `(id, {var: id})` for each
+    /// match, with variable names lacking the `?`. With `limit`, the search
+    /// stops once that many matches are found. With `where`, only the e-classes
+    /// whose id it returns true for are searched. Rebuilds the e-graph first.
+    #[args(limit = "None", r#where = "None")]
+    fn search(
+        &mut self,
+        py: Python,
+        pattern: PyPattern,
+        limit: Option<usize>,
+        r#where: Option<PyObject>,
+    ) -> PyResult<Vec<(PyId, HashMap<String, PyId>)>> {
+        self.egraph.rebuild();
+        let vars = pattern.pattern.vars();
+        let searcher = self.searcher(pattern);
+        let limit = limit.unwrap_or(usize::MAX);
+        let matches = match r#where {
+            None => searcher.search_with_limit(&self.egraph, limit),
+            Some(predicate) => {
+                let mut matches = vec![];
+                let mut found = 0;
+                for class in self.egraph.classes() {
+                    if found >= limit {
+                        break;
+                    }
+                    if !predicate
+                        .call1(py, (PyId(class.id),))?
+                        .as_ref(py)
+                        .is_true()?
+                    {
+                        continue;
+                    }
+                    if let Some(matched) =
+                        searcher.search_eclass_with_limit(&self.egraph, class.id, limit - found)
+                    {
+                        found += matched.substs.len();
+                        matches.push(matched);
+                    }
+                }
+                matches
+            }
+        };
+        self.egraph.analysis.raise_pending()?;
+        Ok(matches
+            .iter()
+            .flat_map(|found| {
+                found
+                    .substs
+                    .iter()
+                    .map(|subst| search_match(found.eclass, &vars, subst))
+            })
+            .collect())
+    }
+
+    /// Like `search`, but returns an iterator that searches one e-class at a
+    /// time as it is advanced, so matches are never all held at once and
+    /// stopping early skips the rest of the search.
+    ///
+    /// The e-classes searched are those present when the iterator is made;
+    /// classes merged away by a later union are skipped, and the e-graph is
+    /// rebuilt before searching if it has been changed since.
+    fn iter_search(slf: PyRefMut<Self>, pattern: PyPattern) -> PySearchIter {
+        let vars = pattern.pattern.vars();
+        let searcher = slf.searcher(pattern);
+        let classes = slf.egraph.classes().map(|class| class.id).collect();
+        PySearchIter {
+            egraph: slf.into(),
+            searcher,
+            vars,
+            classes,
+            next: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// How many matches each of `rewrites` has in the e-graph right now, by
+    /// rule name, without applying any of them. Rules sharing a name are
+    /// counted together.
+    fn count_matches(&mut self, py: Python, rewrites: &PyAny) -> PyResult<HashMap<String, usize>> {
+        self.egraph.rebuild();
+        let mut counts = HashMap::new();
+        for rewrite in PyRewrite::groups_from_any(py, rewrites)?.0 {
+            let rewrite = self.commutative(&rewrite.borrow(py));
+            let matches: usize = rewrite
+                .search(&self.egraph)
+                .iter()
+                .map(|found| found.substs.len())
+                .sum();
+            self.egraph.analysis.raise_pending()?;
+            *counts.entry(rewrite.name.to_string()).or_default() += matches;
+        }
+        Ok(counts)
+    }
+
+    /// Check that `rewrites` prove `lhs` and `rhs` equal: add both, run the
+    /// rewrites with the given limits, and return `(equivalent, stop_reason)`.
+    /// The run happens on a scratch e-graph with this one's `eval`, `key`,
+    /// `commutative_ops` and cost model, unless `in_place` is true.
+    #[args(
+        iter_limit = "10",
+        time_limit = "10.0",
+        node_limit = "100_000",
+        in_place = "false"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn assert_equiv_after(
+        &mut self,
+        py: Python,
+        lhs: &PyAny,
+        rhs: &PyAny,
+        rewrites: &PyAny,
+        iter_limit: usize,
+        time_limit: f64,
+        node_limit: usize,
+        in_place: bool,
+    ) -> PyResult<(bool, PyStopReason)> {
+        let mut scratch;
+        let egraph = if in_place {
+            self
+        } else {
+            scratch = Self {
+                deadline: self.deadline,
+                commutative_ops: self.commutative_ops.clone(),
+                cost_model: self.cost_model.clone(),
+                ..Self::with_analysis(self.egraph.analysis.fresh())
+            };
+            &mut scratch
+        };
+        let lhs = egraph.add(lhs)?;
+        let rhs = egraph.add(rhs)?;
+        let options = RunOptions {
+            iter_limit,
+            time_limit,
+            node_limit,
+            ..RunOptions::default()
+        };
+        egraph.run_all(py, rewrites, options)?;
+        let equivalent = egraph.egraph.find(lhs.0) == egraph.egraph.find(rhs.0);
+        Ok((equivalent, egraph.stop_reason.clone().unwrap()))
+    }
+
+    /// Give all later `run()` calls one shared wall-clock budget of `budget`
+    /// seconds from now, or remove it with `None`. `budget` must be finite and
+    /// non-negative.
+    fn set_deadline(&mut self, budget: Option<f64>) -> PyResult<()> {
+        self.deadline = match budget {
+            Some(secs) => Some(Instant::now() + seconds("budget", secs)?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Seconds left before the deadline (0 once it has passed), or `None`
+    /// without one.
+    #[getter]
+    fn remaining_time(&self) -> Option<f64> {
+        self.deadline.map(|deadline| {
+            deadline
+                .saturating_duration_since(Instant::now())
+                .as_secs_f64()
+        })
+    }
+
+    /// Why the last `run()` stopped, or `None` if it was never run.
+    #[getter]
+    fn stop_reason(&self) -> Option<PyStopReason> {
+        self.stop_reason.clone()
+    }
+
+    /// Total seconds the last `run()` spent searching, applying and rebuilding,
+    /// as a dict with `"search_time"`, `"apply_time"` and `"rebuild_time"`, or
+    /// `None` if it was never run.
+    fn last_run_timing(&self) -> Option<HashMap<&'static str, f64>> {
+        self.stop_reason.as_ref()?;
+        let total = |time: fn(&Iteration<()>) -> f64| self.iterations.iter().map(time).sum();
+        Some(HashMap::from([
+            ("search_time", total(|i| i.search_time)),
+            ("apply_time", total(|i| i.apply_time)),
+            ("rebuild_time", total(|i| i.rebuild_time)),
+        ]))
+    }
+
+    /// The number of passes over the rewrites the last `run()` made if it was
+    /// `ordered`, else `None`.
+    fn last_run_passes(&self) -> Option<usize> {
+        self.passes
+    }
+
+    /// `(enodes, ids)` if the last `run(on_node_limit="prune")` pruned: the
+    /// number of enodes it pruned, and a dict from every id handed out before
+    /// it to the id of the same e-class now. Ids from before the run mean
+    /// nothing in the rebuilt e-graph, so they must be looked up here.
+    fn last_run_pruned(&self) -> Option<(usize, HashMap<PyId, PyId>)> {
+        let (enodes, ids) = self.pruned.as_ref()?;
+        let ids = ids.iter().map(|(&old, &new)| (PyId(old), PyId(new)));
+        Some((*enodes, ids.collect()))
+    }
+
+    /// One dict per iteration of the last `run()`, with the e-graph's
+    /// `"egraph_nodes"` and `"egraph_classes"` at its start, the per-rewrite
+    /// `"applied"` counts, the `"search_time"`, `"apply_time"`,
+    /// `"rebuild_time"` and `"hook_time"` in seconds, and the nodes added by
+    /// the `hook` (`"hook_nodes"`) and by applying rewrites and rebuilding
+    /// (`"rewrite_nodes"`, negative if rebuilding shrank the graph).
+    fn last_run_iterations(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        let final_nodes = self.egraph.total_size();
+        let mut out = Vec::with_capacity(self.iterations.len());
+        for (i, iteration) in self.iterations.iter().enumerate() {
+            let hook_nodes = self.hook_nodes.get(i).copied().unwrap_or(0);
+            let end_nodes = self
+                .iterations
+                .get(i + 1)
+                .map_or(final_nodes, |next| next.egraph_nodes);
+            let applied: HashMap<&str, usize> = iteration
+                .applied
+                .iter()
+                .map(|(name, &count)| (name.as_str(), count))
+                .collect();
+            let dict = PyDict::new(py);
+            dict.set_item("egraph_nodes", iteration.egraph_nodes)?;
+            dict.set_item("egraph_classes", iteration.egraph_classes)?;
+            dict.set_item("applied", applied)?;
+            dict.set_item("search_time", iteration.search_time)?;
+            dict.set_item("apply_time", iteration.apply_time)?;
+            dict.set_item("rebuild_time", iteration.rebuild_time)?;
+            dict.set_item("hook_time", iteration.hook_time)?;
+            dict.set_item("hook_nodes", hook_nodes)?;
+            dict.set_item(
+                "rewrite_nodes",
+                end_nodes as i64 - (iteration.egraph_nodes + hook_nodes) as i64,
+            )?;
+            out.push(dict.into());
+        }
+        Ok(out)
+    }
+
+    /// Set the cost model used by `extract`, `best_costs` and `orphan_classes`
+    /// (and the node costs of `to_egraph_serialize`) unless a call passes its
+    /// own `cost`, and by the methods that rebuild child terms to show an
+    /// e-class, such as `class_ops`, `describe_class` and `class_enodes`.
+    /// `model` is `"size"` (the default: one per enode), `"depth"` (longest
+    /// path), or a callable `cost(op) -> float` charged per enode and summed,
+    /// where `op` is the node class or the leaf object. With
+    /// `with_data`, the callable is `cost(op, data)` and also gets the analysis
+    /// data of the enode's e-class, e.g. to make constant-folded classes free.
+    /// Costs must be non-negative.
+    #[args(with_data = "false")]
+    fn set_cost_model(&mut self, model: &PyAny, with_data: bool) -> PyResult<()> {
+        self.cost_model = CostModel::from_py(model, with_data)?;
+        Ok(())
+    }
+
+    /// Extract the cheapest term of each expression's e-class under `cost`
+    /// (see `set_cost_model`), defaulting to the e-graph's cost model.
+    /// Equal-cost terms are ordered by `tie_break(a, b)` when given, else by
+    /// their s-expressions, so the result is deterministic.
+    /// Enodes whose operator is in `forbid_ops` (node classes, or op names as
+    /// in `node_label`) are never used, whatever their cost; if an expression
+    /// has no term without them this raises `ValueError`.
+    /// `decode`, if given, is applied to each leaf's stored object as the
+    /// terms are rebuilt.
+    /// With `prefer_original`, equal-cost enodes are ordered by when they were
+    /// added instead, earliest first, so unimproved subterms come out as they
+    /// were written; `tie_break` still has the final say.
+    /// With `maximize`, the term of highest total value is extracted instead,
+    /// each enode being worth `value_fn(op)` (by default, what `cost` would
+    /// charge, so `"size"` gives the largest term). Values add up over the
+    /// term as a tree: one e-class gets one term, reused wherever it occurs,
+    /// and a shared subterm counts at every occurrence. As a loop through an
+    /// e-class could be unrolled without end, no class's term may contain the
+    /// class itself; an enode that would close such a loop is left out, the
+    /// enodes added earlier being kept first.
+    /// With `max_nodes`, a term with more enodes than that, counted as a
+    /// tree, raises `ValueError` instead of being returned. Its Python objects
+    /// are shared between occurrences of a subterm, so the term itself is held
+    /// in one object per e-class, but a small e-graph can have a term that is
+    /// exponentially big as a tree, which would hang anything walking it.
+    #[args(
+        exprs = "*",
+        tie_break = "None",
+        cost = "None",
+        forbid_ops = "None",
+        decode = "None",
+        prefer_original = "false",
+        maximize = "false",
+        value_fn = "None",
+        max_nodes = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn extract(
+        &mut self,
+        py: Python,
+        exprs: &PyTuple,
+        tie_break: Option<&PyAny>,
+        cost: Option<&PyAny>,
+        forbid_ops: Option<Vec<&PyAny>>,
+        decode: Option<&PyAny>,
+        prefer_original: bool,
+        maximize: bool,
+        value_fn: Option<&PyAny>,
+        max_nodes: Option<usize>,
+    ) -> PyResult<Vec<PyObject>> {
+        let model = match (value_fn, maximize, cost) {
+            (Some(_), false, _) => {
+                return Err(PyValueError::new_err("value_fn needs maximize=True"))
+            }
+            (Some(_), true, Some(_)) => {
+                return Err(PyValueError::new_err("Give either cost or value_fn"))
+            }
+            (Some(value_fn), true, None) => CostModel::from_py(value_fn, false)?,
+            (None, _, cost) => self.cost_model(cost)?,
+        };
+        let ids = exprs
+            .iter()
+            .map(|expr| self.term_id(expr))
+            .collect::<PyResult<Vec<Id>>>()?;
+        let forbidden = match forbid_ops {
+            Some(ops) => forbidden_nodes(py, &self.egraph, &ops)?,
+            None => HashSet::new(),
+        };
+        let mut best = if maximize {
+            BestTerms::maximizing(py, &self.egraph, &model, tie_break, forbidden)?
+        } else {
+            BestTerms::with_forbidden(py, &self.egraph, &model, tie_break, forbidden)?
+        };
+        best.set_decode(decode);
+        best.set_order(prefer_original.then(|| insertion_order(&self.egraph)));
+        ids.iter()
+            .map(|&id| {
+                if let Some(max_nodes) = max_nodes {
+                    check_term_size(best.tree_size(id)?, max_nodes)?;
+                }
+                best.object(id)
+            })
+            .collect()
+    }
+
+    /// The depth (longest root-to-leaf path, a leaf counting as 1) of the term
+    /// `extract` would return for `expr`.
+    #[args(tie_break = "None", cost = "None")]
+    fn extract_depth(
+        &mut self,
+        py: Python,
+        expr: &PyAny,
+        tie_break: Option<&PyAny>,
+        cost: Option<&PyAny>,
+    ) -> PyResult<usize> {
+        let id = self.term_id(expr)?;
+        let model = self.cost_model(cost)?;
+        BestTerms::new(py, &self.egraph, &model, tie_break)?.depth(id)
+    }
+
+    /// Like `extract` for a single expression, but return `(term, cost, depth)`.
+    #[args(tie_break = "None", cost = "None")]
+    fn extract_with_cost(
+        &mut self,
+        py: Python,
+        expr: &PyAny,
+        tie_break: Option<&PyAny>,
+        cost: Option<&PyAny>,
+    ) -> PyResult<(PyObject, f64, usize)> {
+        let id = self.term_id(expr)?;
+        let model = self.cost_model(cost)?;
+        let mut best = BestTerms::new(py, &self.egraph, &model, tie_break)?;
+        let term = best.object(id)?;
+        Ok((term, best.cost(id).unwrap(), best.depth(id)?))
+    }
+
+    /// Add the term `extract` would return for `expr` under `cost` to `into`,
+    /// another `EGraph`, enode by enode instead of as reconstructed Python
+    /// objects, and return the id of its root there. Leaves are interned under
+    /// `into`'s `key`.
+    #[args(cost = "None")]
+    fn reinsert_best(
+        &mut self,
+        py: Python,
+        expr: &PyAny,
+        into: &PyCell<PyEGraph>,
+        cost: Option<&PyAny>,
+    ) -> PyResult<PyId> {
+        let mut into = into
+            .try_borrow_mut()
+            .map_err(|_| PyValueError::new_err("into must be another e-graph"))?;
+        into.check_mutable()?;
+        let id = self.term_id(expr)?;
+        let model = self.cost_model(cost)?;
+        let term = BestTerms::new(py, &self.egraph, &model, None)?.rec_expr(id)?;
+        add_rec_expr(&mut into.egraph, py, &term).map(PyId)
+    }
+
+    /// Extract `expr` under the cost models `cost_a` and `cost_b` (each as
+    /// `extract`'s `cost` takes), returning `(term_a, cost_a, term_b, cost_b,
+    /// differ)`, where `differ` is whether the two terms differ in any enode
+    /// chosen.
+    #[args(tie_break = "None")]
+    fn compare_extractions(
+        &mut self,
+        py: Python,
+        expr: &PyAny,
+        cost_a: &PyAny,
+        cost_b: &PyAny,
+        tie_break: Option<&PyAny>,
+    ) -> PyResult<(PyObject, f64, PyObject, f64, bool)> {
+        let id = self.term_id(expr)?;
+        let model_a = CostModel::from_py(cost_a, false)?;
+        let model_b = CostModel::from_py(cost_b, false)?;
+        let mut best_a = BestTerms::new(py, &self.egraph, &model_a, tie_break)?;
+        let mut best_b = BestTerms::new(py, &self.egraph, &model_b, tie_break)?;
+        let differ = best_a.plan(id)? != best_b.plan(id)?;
+        Ok((
+            best_a.object(id)?,
+            best_a.cost(id).unwrap(),
+            best_b.object(id)?,
+            best_b.cost(id).unwrap(),
+            differ,
+        ))
+    }
+
+    /// A good but not necessarily cheapest term of `expr` under `cost`, for
+    /// e-graphs too big for `extract`: the cheapest of `samples` randomized
+    /// greedy descents from its e-class, the first fully greedy. The same
+    /// `seed` gives the same result; by default a fresh one is used each call.
+    #[args(samples = "10", seed = "None", cost = "None")]
+    fn extract_sampled(
+        &mut self,
+        py: Python,
+        expr: &PyAny,
+        samples: usize,
+        seed: Option<u64>,
+        cost: Option<&PyAny>,
+    ) -> PyResult<PyObject> {
+        let id = self.term_id(expr)?;
+        let model = self.cost_model(cost)?;
+        let seed = seed.unwrap_or_else(|| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            now.as_nanos() as u64
+        });
+        SampledTerms::new(py, &self.egraph, &model, seed).object(id, samples)
+    }
+
+    /// The extraction decisions behind `extract(expr)`: a dict from each
+    /// e-class its best term visits to the index, in `class_enodes`, of the
+    /// enode chosen for it.
+    #[args(tie_break = "None", cost = "None")]
+    fn extract_plan(
+        &mut self,
+        py: Python,
+        expr: &PyAny,
+        tie_break: Option<&PyAny>,
+        cost: Option<&PyAny>,
+    ) -> PyResult<HashMap<PyId, usize>> {
+        let id = self.term_id(expr)?;
+        let model = self.cost_model(cost)?;
+        let plan = BestTerms::new(py, &self.egraph, &model, tie_break)?.plan(id)?;
+        Ok(plan
+            .into_iter()
+            .map(|(id, index)| (PyId(id), index))
+            .collect())
+    }
+
+    /// `(term, fit)`: the cheapest term of `expr` under `cost` with at most
+    /// `max_size` enodes, counted as a tree, and `True`; or if every term of
+    /// it is bigger, its smallest term and `False`. Unlike `extract`, this
+    /// can pick a costlier term to stay within the size. It takes time cubic
+    /// in `max_size`, so it's meant for modest budgets; a `max_size` beyond
+    /// the size of the unrestricted best term is treated as that size.
+    #[args(cost = "None")]
+    fn extract_within_size(
+        &mut self,
+        py: Python,
+        expr: &PyAny,
+        max_size: usize,
+        cost: Option<&PyAny>,
+    ) -> PyResult<(PyObject, bool)> {
+        let id = self.term_id(expr)?;
+        self.egraph.rebuild();
+        let model = self.cost_model(cost)?;
+        match extract_within_size(py, &self.egraph, &model, id, max_size)? {
+            Some(term) => Ok((term, true)),
+            None => Ok((
+                BestTerms::new(py, &self.egraph, &CostModel::Size, None)?.object(id)?,
+                false,
+            )),
+        }
+    }
+
+    /// The best terms for all of `exprs` at once, under a joint cost that
+    /// counts a subterm they share (or one term repeats) only once, so the
+    /// terms come out sharing as much as pays off. The cost is summed over
+    /// the distinct e-classes used, so depth isn't allowed.
+    ///
+    /// This is a local search over the choices `extract` makes and is much
+    /// more expensive than extracting each expression on its own, but the
+    /// combined output is never bigger and often smaller.
+    #[args(cost = "None")]
+    fn extract_forest(
+        &mut self,
+        py: Python,
+        exprs: Vec<&PyAny>,
+        cost: Option<&PyAny>,
+    ) -> PyResult<Vec<PyObject>> {
+        let roots = exprs
+            .into_iter()
+            .map(|expr| self.term_id(expr))
+            .collect::<PyResult<Vec<Id>>>()?;
+        self.egraph.rebuild();
+        let model = self.cost_model(cost)?;
+        extract_forest(py, &self.egraph, &model, &roots)
+    }
+
+    /// How many times each operator occurs in the term `extract(expr)` would
+    /// return, by name as in `to_egraph_serialize` (leaves by `str()` of the
+    /// leaf), as a structure-blind fingerprint of the term.
+    #[args(cost = "None")]
+    fn extract_op_bag(
+        &mut self,
+        py: Python,
+        expr: &PyAny,
+        cost: Option<&PyAny>,
+    ) -> PyResult<HashMap<String, usize>> {
+        let id = self.term_id(expr)?;
+        let model = self.cost_model(cost)?;
+        BestTerms::new(py, &self.egraph, &model, None)?.label_counts(id)
+    }
+
+    /// Whether `expr` has a term costing at most `max_cost` under `cost`,
+    /// computing costs only as far as needed to tell, so cheaper than
+    /// `extract_with_cost` for a yes or no. Rebuilds the e-graph first.
+    #[args(cost = "None")]
+    fn has_term_under_cost(
+        &mut self,
+        py: Python,
+        expr: &PyAny,
+        max_cost: f64,
+        cost: Option<&PyAny>,
+    ) -> PyResult<bool> {
+        let id = self.term_id(expr)?;
+        self.egraph.rebuild();
+        let model = self.cost_model(cost)?;
+        has_term_under_cost(py, &self.egraph, &model, id, max_cost)
+    }
+
+    /// The operator names (as for `extract_op_bag`) along the spine of the
+    /// term `extract(expr)` would return: from its root down to a leaf, each
+    /// time into the child with the cheapest term, the first of equally cheap
+    /// ones.
+    #[args(cost = "None")]
+    fn best_spine(
+        &mut self,
+        py: Python,
+        expr: &PyAny,
+        cost: Option<&PyAny>,
+    ) -> PyResult<Vec<String>> {
+        let id = self.term_id(expr)?;
+        let model = self.cost_model(cost)?;
+        BestTerms::new(py, &self.egraph, &model, None)?.spine(id)
+    }
+
+    /// The cheapest term under `cost` rooted at an enode whose operator is
+    /// `op`, a node class or a name as for `forbid_ops`: a minimal example of
+    /// how `op` appears in the e-graph. Equal-cost terms are ordered by
+    /// `repr`. `None` if no enode has the operator. Rebuilds the e-graph first.
+    #[args(cost = "None")]
+    fn smallest_term_with_op(
+        &mut self,
+        py: Python,
+        op: &PyAny,
+        cost: Option<&PyAny>,
+    ) -> PyResult<Option<PyObject>> {
+        self.egraph.rebuild();
+        let model = self.cost_model(cost)?;
+        let nodes = forbidden_nodes(py, &self.egraph, &[op])?;
+        let mut best = BestTerms::new(py, &self.egraph, &model, None)?;
+        let mut smallest: Option<(f64, String, PyObject)> = None;
+        for node in &nodes {
+            if let Some((cost, term)) = best.rooted_at(node)? {
+                let repr = term.as_ref(py).repr()?.to_string();
+                let better = smallest.as_ref().is_none_or(|(best_cost, best_repr, _)| {
+                    (cost, &repr) < (*best_cost, best_repr)
+                });
+                if better {
+                    smallest = Some((cost, repr, term));
+                }
+            }
+        }
+        Ok(smallest.map(|(_, _, term)| term))
+    }
+
+    fn dump(&self) -> PyResult<()> {
+        let dump = self.egraph.dump();
+        println!("{:?}", dump);
+        Ok(())
+    }
+
+    fn prepare_ilp_snapshot(
+        &mut self,
+        py: Python,
+        root: PyId,
+        cost_fn: PyObject,
+    ) -> PyResult<Py<IlpSnapshot>> {
+        let extractor = Extractor::new(&self.egraph, AstSize);
+        let m_id_map: Vec<Id> = self
+            .egraph
+            .classes()
+            .map(|c| self.egraph.find(c.id))
+            .collect();
+        let mut id_m_map = HashMap::new();
+        for (idx, id) in m_id_map.iter().enumerate() {
+            id_m_map.insert(*id, idx);
+        }
+
+        let num_classes = m_id_map.len();
+        let mut e_m = vec![Vec::new(); num_classes];
+        let mut h_i: Vec<Vec<usize>> = Vec::new();
+        let mut g_i: Vec<usize> = Vec::new();
+        let mut costs: Vec<f64> = Vec::new();
+        let mut nodes: Vec<PythonNode> = Vec::new();
+        let mut child_cache: HashMap<Id, PyObject> = HashMap::new();
+
+        let root_m = *id_m_map
+            .get(&self.egraph.find(root.0))
+            .ok_or_else(|| PyValueError::new_err("Root eclass not found"))?;
+
+        for class in self.egraph.classes() {
+            let m = *id_m_map.get(&self.egraph.find(class.id)).unwrap();
+            for node in class.iter() {
+                let idx = nodes.len();
+                e_m[m].push(idx);
+                let children: Vec<usize> = node
+                    .children()
+                    .iter()
+                    .map(|id| *id_m_map.get(&self.egraph.find(*id)).unwrap())
+                    .collect();
                 h_i.push(children);
                 g_i.push(m);
                 nodes.push(node.clone());
@@ -369,17 +2104,14 @@ impl PyEGraph {
         build_expr(py, root_id, &self.egraph, &picked, &mut memo)
     }
     fn pretty_dump(&self, py: Python) -> PyResult<String> {
-        use egg::{AstSize, Extractor, Id};
+        use egg::Id;
         use pyo3::types::{PyString, PyTuple};
 
-        let extractor = Extractor::new(&self.egraph, AstSize);
+        let best = RefCell::new(BestTerms::new(py, &self.egraph, &self.cost_model, None)?);
         let mut out = String::new();
 
         // Helper: reconstruct child id minimally
-        let reconstruct_child = |child_id: Id| {
-            let (_cost, expr) = extractor.find_best(child_id);
-            reconstruct(py, &expr)
-        };
+        let reconstruct_child = |child_id: Id| best.borrow_mut().object(child_id);
 
         for eclass in self.egraph.classes() {
             let id: Id = eclass.id;
@@ -403,30 +2135,17 @@ impl PyEGraph {
 
                 // Heuristic for detective.ir.Operation (5 fields: name,args,regions,attributes,result_types)
                 if node.children.len() == 5 {
-                    let name_obj = reconstruct_child(node.children[0]);
-                    // Extract string for op name if possible
-                    let name_s = name_obj
-                        .cast_as::<PyString>(py)
-                        .ok()
-                        .map(|s| s.to_str().unwrap_or("<?>").to_string())
-                        .unwrap_or_else(|| {
-                            // fallback to str(name_obj)
-                            name_obj
-                                .as_ref(py)
-                                .str()
-                                .map(|s| s.to_str().unwrap_or("<?>").to_string())
-                                .unwrap_or_else(|_| "<?>".to_string())
-                        });
+                    let name_s = operation_name(py, &reconstruct_child(node.children[0])?);
 
                     // lengths: args (tuple), regions (tuple), attributes (tuple), result_types (tuple)
-                    let tuple_len = |child_id: Id| -> Option<usize> {
-                        let obj = reconstruct_child(child_id);
-                        obj.cast_as::<PyTuple>(py).ok().map(|t| t.len())
+                    let tuple_len = |child_id: Id| -> PyResult<Option<usize>> {
+                        let obj = reconstruct_child(child_id)?;
+                        Ok(obj.cast_as::<PyTuple>(py).ok().map(|t| t.len()))
                     };
-                    let args_len = tuple_len(node.children[1]).unwrap_or(0);
-                    let regions_len = tuple_len(node.children[2]).unwrap_or(0);
-                    let attrs_len = tuple_len(node.children[3]).unwrap_or(0);
-                    let results_len = tuple_len(node.children[4]).unwrap_or(0);
+                    let args_len = tuple_len(node.children[1])?.unwrap_or(0);
+                    let regions_len = tuple_len(node.children[2])?.unwrap_or(0);
+                    let attrs_len = tuple_len(node.children[3])?.unwrap_or(0);
+                    let results_len = tuple_len(node.children[4])?.unwrap_or(0);
 
                     label = format!(
                         "Operation(name='{}', args={}, regions={}, attrs={}, results={})",
@@ -437,7 +2156,7 @@ impl PyEGraph {
                     // We can limit to op name only to avoid value explosions.
                     // Attempt to reconstruct first child (name-like) if it's a string.
                     let maybe_name = {
-                        let obj = reconstruct_child(node.children[0]);
+                        let obj = reconstruct_child(node.children[0])?;
                         obj.cast_as::<PyString>(py)
                             .ok()
                             .map(|s| s.to_str().unwrap_or("<?>").to_string())
@@ -455,10 +2174,58 @@ impl PyEGraph {
     }
 
     /// Return the e-class id for a given expression by adding it (idempotent).
-    fn class_id_for(&mut self, expr: &PyAny) -> PyId {
+    fn class_id_for(&mut self, expr: &PyAny) -> PyResult<PyId> {
         self.add(expr)
     }
 
+    /// Whether `id`'s e-class has an enode whose operator is `op_name` (a
+    /// node class, or a name compared with the enode's `node_label`) and
+    /// whose children are the e-classes of `child_ids`, in order. Rebuilds the
+    /// e-graph first, so that children are compared canonically.
+    fn class_has_node(
+        &mut self,
+        py: Python,
+        id: PyId,
+        op_name: &PyAny,
+        child_ids: Vec<PyId>,
+    ) -> PyResult<bool> {
+        self.egraph.rebuild();
+        let id = self.egraph.find(self.known(id)?);
+        let children = child_ids
+            .into_iter()
+            .map(|child| Ok(self.egraph.find(self.known(child)?)))
+            .collect::<PyResult<Vec<Id>>>()?;
+        let name = op_name.extract::<String>().ok();
+        let class = match name {
+            Some(_) => None,
+            None => Some(op_name.downcast::<PyType>()?),
+        };
+        let mut best = match name {
+            Some(_) => Some(BestTerms::new(py, &self.egraph, &self.cost_model, None)?),
+            None => None,
+        };
+        for node in self.egraph[id].iter() {
+            let same_children = node.children.len() == children.len()
+                && node
+                    .children
+                    .iter()
+                    .zip(&children)
+                    .all(|(&a, &b)| self.egraph.find(a) == b);
+            if !same_children {
+                continue;
+            }
+            let found = match (&class, &name, &mut best) {
+                (Some(class), _, _) => node.class.is(*class),
+                (None, Some(name), Some(best)) => best.label(node)? == *name,
+                _ => false,
+            };
+            if found {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     /// Return compact labels for operations in an e-class.
     /// ops_only: omit non-operation nodes; include_bodies: summarize linalg.generic body ops.
     #[args(ops_only = "true", include_bodies = "true")]
@@ -469,46 +2236,32 @@ impl PyEGraph {
         ops_only: bool,
         include_bodies: bool,
     ) -> PyResult<Vec<String>> {
-        use egg::{AstSize, Extractor, Id};
-        use pyo3::types::{PyString, PyTuple};
+        use egg::Id;
+        use pyo3::types::PyTuple;
 
-        let extractor = Extractor::new(&self.egraph, AstSize);
+        let best = RefCell::new(BestTerms::new(py, &self.egraph, &self.cost_model, None)?);
         let eclass = &self.egraph[id.0];
         let mut out: Vec<String> = Vec::new();
 
-        let reconstruct_child = |child_id: Id| {
-            let (_cost, expr) = extractor.find_best(child_id);
-            reconstruct(py, &expr)
-        };
+        let reconstruct_child = |child_id: Id| best.borrow_mut().object(child_id);
 
         for node in &eclass.nodes {
             if node.children.len() == 5 {
                 // detective.ir.Operation
-                let name_obj = reconstruct_child(node.children[0]);
-                let name_s = name_obj
-                    .cast_as::<PyString>(py)
-                    .ok()
-                    .map(|s| s.to_str().unwrap_or("<?>").to_string())
-                    .unwrap_or_else(|| {
-                        name_obj
-                            .as_ref(py)
-                            .str()
-                            .map(|s| s.to_str().unwrap_or("<?>").to_string())
-                            .unwrap_or_else(|_| "<?>".to_string())
-                    });
+                let name_s = operation_name(py, &reconstruct_child(node.children[0])?);
 
-                let tuple_len = |child_id: Id| -> Option<usize> {
-                    let obj = reconstruct_child(child_id);
-                    obj.cast_as::<PyTuple>(py).ok().map(|t| t.len())
+                let tuple_len = |child_id: Id| -> PyResult<Option<usize>> {
+                    let obj = reconstruct_child(child_id)?;
+                    Ok(obj.cast_as::<PyTuple>(py).ok().map(|t| t.len()))
                 };
-                let args_len = tuple_len(node.children[1]).unwrap_or(0);
-                let regions_len = tuple_len(node.children[2]).unwrap_or(0);
-                let results_len = tuple_len(node.children[4]).unwrap_or(0);
+                let args_len = tuple_len(node.children[1])?.unwrap_or(0);
+                let regions_len = tuple_len(node.children[2])?.unwrap_or(0);
+                let results_len = tuple_len(node.children[4])?.unwrap_or(0);
 
                 if include_bodies && name_s == "linalg.generic" {
                     // Try to summarize inner body ops as addf/mulf tokens
                     let mut tokens: Vec<String> = Vec::new();
-                    let regions_obj = reconstruct_child(node.children[2]);
+                    let regions_obj = reconstruct_child(node.children[2])?;
                     if let Ok(regions_tuple) = regions_obj.cast_as::<PyTuple>(py) {
                         if let Ok(region_obj) = regions_tuple.get_item(0) {
                             if let Ok(blocks_obj) = region_obj.getattr("blocks") {
@@ -581,6 +2334,13 @@ impl PyEGraph {
         Ok(format!("{}: [{}]", usize::from(id.0), labels.join(", ")))
     }
 
+    /// The analysis data of `id`'s e-class: what `eval` or the built-in
+    /// `analysis` computed for it, or `None` without any.
+    fn class_data(&self, py: Python, id: PyId) -> PyResult<PyObject> {
+        let id = self.egraph.find(self.known(id)?);
+        Ok(self.egraph[id].data.to_object(py))
+    }
+
     /// Return all current e-class ids.
     fn class_ids(&self) -> Vec<PyId> {
         self.egraph
@@ -589,28 +2349,1117 @@ impl PyEGraph {
             .collect::<Vec<_>>()
     }
 
-    /// Reconstruct concrete Python objects for each enode in an e-class
-    fn class_enodes(&self, py: Python, id: PyId) -> PyResult<Vec<PyObject>> {
-        use egg::{AstSize, Extractor, Id};
-        let extractor = Extractor::new(&self.egraph, AstSize);
+    /// Reconstruct concrete Python objects for each enode in an e-class,
+    /// passing leaf objects through `decode` if given. With `max_nodes`, an
+    /// enode whose term would have more enodes than that raises `ValueError`
+    /// instead, as in `extract`.
+    #[args(decode = "None", max_nodes = "None")]
+    fn class_enodes(
+        &self,
+        py: Python,
+        id: PyId,
+        decode: Option<&PyAny>,
+        max_nodes: Option<usize>,
+    ) -> PyResult<Vec<PyObject>> {
+        let mut best = BestTerms::new(py, &self.egraph, &self.cost_model, None)?;
+        best.set_decode(decode);
         let eclass = &self.egraph[id.0];
-        let reconstruct_child = |child_id: Id| {
-            let (_cost, expr) = extractor.find_best(child_id);
-            reconstruct(py, &expr)
-        };
         let mut out: Vec<PyObject> = Vec::with_capacity(eclass.nodes.len());
         for node in &eclass.nodes {
-            let obj = node.to_object(py, |child_id| reconstruct_child(child_id));
-            out.push(obj);
+            if let Some(max_nodes) = max_nodes {
+                let mut size: usize = 1;
+                for &child in &node.children {
+                    size = size.saturating_add(best.tree_size(child)?);
+                }
+                check_term_size(size, max_nodes)?;
+            }
+            let children = node
+                .children
+                .iter()
+                .map(|&child| best.object(child))
+                .collect::<PyResult<Vec<PyObject>>>()?;
+            let mut children = children.into_iter();
+            let obj = node.to_object(py, |_| children.next().unwrap());
+            out.push(decode_leaf(node, obj, decode)?);
         }
         Ok(out)
     }
-}
-pub(crate) fn reconstruct(py: Python, recexpr: &RecExpr<PythonNode>) -> PyObject {
-    let mut objs = Vec::<PyObject>::with_capacity(recexpr.as_ref().len());
-    for node in recexpr.as_ref() {
-        let obj = node.to_object(py, |id| objs[usize::from(id)].clone());
-        objs.push(obj)
-    }
-    objs.pop().unwrap()
+
+    /// `(label, children)` for each enode in `id`'s e-class, its `node_label`
+    /// and the canonical ids of its children, without reconstructing terms
+    /// (except an `Operation`-shaped enode's op, to label it). Rebuilds the
+    /// e-graph first.
+    fn class_node_structure(&mut self, py: Python, id: PyId) -> PyResult<Vec<(String, Vec<PyId>)>> {
+        self.egraph.rebuild();
+        let id = self.known(id)?;
+        // built only if an `Operation`-shaped enode needs its op reconstructed
+        let mut best = None;
+        let mut out = vec![];
+        for node in self.egraph[id].iter() {
+            let label = match node.children.len() {
+                5 => {
+                    if best.is_none() {
+                        best = Some(BestTerms::new(py, &self.egraph, &self.cost_model, None)?);
+                    }
+                    best.as_mut().unwrap().label(node)?
+                }
+                _ => class_label(py, node),
+            };
+            let children = node
+                .children
+                .iter()
+                .map(|&child| PyId(self.egraph.find(child)))
+                .collect();
+            out.push((label, children));
+        }
+        Ok(out)
+    }
+
+    /// The canonical ids, in increasing order, of the e-classes holding a
+    /// term shaped like `expr`: with an enode of the same class name (or, for
+    /// a leaf, `str()`) and number of children at every position, whether or
+    /// not the enodes hash-cons together. More than one class means terms
+    /// that look alike were kept apart, e.g. by leaves that print the same
+    /// but compare unequal, or by two node classes of the same name. A var in
+    /// `expr` matches any e-class. `expr` isn't added; the e-graph is rebuilt
+    /// first.
+    fn find_structural_duplicates(&mut self, py: Python, expr: PyPattern) -> Vec<PyId> {
+        self.egraph.rebuild();
+        let ast = expr.pattern.ast.as_ref();
+        let root = Id::from(ast.len() - 1);
+        let mut memo = HashMap::new();
+        let mut ids: Vec<Id> = self
+            .egraph
+            .classes()
+            .map(|class| class.id)
+            .filter(|&id| shaped_like(py, &self.egraph, ast, root, id, &mut memo))
+            .collect();
+        ids.sort();
+        ids.into_iter().map(PyId).collect()
+    }
+
+    /// The distinct operator classes used by enodes in the graph, ordered by
+    /// name; with `names=True`, just their names. Leaf values aren't included.
+    #[args(names = "false")]
+    fn operator_classes(&self, py: Python, names: bool) -> PyResult<Vec<PyObject>> {
+        let mut seen = HashSet::new();
+        let mut ops: Vec<(String, &PyType)> = Vec::new();
+        for node in self.egraph.classes().flat_map(|class| class.iter()) {
+            if let Ok(ty) = node.class.as_ref(py).downcast::<PyType>() {
+                if seen.insert(ty.as_ptr()) {
+                    ops.push((ty.name()?.to_string(), ty));
+                }
+            }
+        }
+        ops.sort_by(|a, b| a.0.cmp(&b.0));
+        if names {
+            let mut names: Vec<String> = ops.into_iter().map(|(name, _)| name).collect();
+            names.dedup();
+            Ok(names.into_iter().map(|name| name.into_py(py)).collect())
+        } else {
+            Ok(ops.into_iter().map(|(_, ty)| ty.into()).collect())
+        }
+    }
+
+    /// A small integer for an operator label, stable for this e-graph (and its
+    /// copies). `label` is a label string as used by `to_egraph_serialize`, or
+    /// an `Id` whose enodes all have the same label.
+    fn op_label_id(&mut self, py: Python, label: &PyAny) -> PyResult<usize> {
+        if let Ok(PyId(id)) = label.extract() {
+            let mut best = BestTerms::new(py, &self.egraph, &self.cost_model, None)?;
+            let labels = self.egraph[id]
+                .iter()
+                .map(|node| best.label(node))
+                .collect::<PyResult<HashSet<String>>>()?;
+            if labels.len() != 1 {
+                return Err(PyValueError::new_err(format!(
+                    "e-class {} has {} different labels",
+                    id,
+                    labels.len()
+                )));
+            }
+            let label = labels.into_iter().next().unwrap();
+            Ok(self.labels.intern(&label))
+        } else {
+            let label: &str = label
+                .extract()
+                .map_err(|_| PyValueError::new_err("expected an Id or a label string"))?;
+            Ok(self.labels.intern(label))
+        }
+    }
+
+    /// The operator label interned as `id` by `op_label_id`.
+    fn op_label_name(&self, id: usize) -> PyResult<String> {
+        self.labels
+            .name(id)
+            .map(str::to_string)
+            .ok_or_else(|| PyValueError::new_err(format!("unknown label id {}", id)))
+    }
+
+    /// `(attempts, distinct_nodes)`: how many enodes, counting subterms, were
+    /// added from Python terms through `add` and friends, and how many
+    /// distinct enodes the e-graph holds. Copies keep the count.
+    fn insertion_stats(&self) -> (usize, usize) {
+        (
+            self.egraph.analysis.insertions,
+            self.egraph.total_number_of_nodes(),
+        )
+    }
+
+    /// The names of the rules whose application during a `run()` added any
+    /// enode now in the e-class of `id`. Enodes added through `add` or a run's
+    /// hook have no rule, and neither do those a rule only unioned in after
+    /// they already existed. Rebuilds the e-graph first.
+    fn class_provenance(&mut self, id: PyId) -> HashSet<String> {
+        self.egraph.rebuild();
+        let id = self.egraph.find(id.0);
+        provenance(&self.egraph, id)
+            .into_iter()
+            .map(|rule| rule.to_string())
+            .collect()
+    }
+
+    /// Add to the e-class of every enode whose class is `from_name` the same
+    /// enode with the class `to_name` instead, then rebuild so that nodes made
+    /// congruent merge. Each may be a node class or the name of one already in
+    /// the e-graph. egg can't remove enodes, so the old ones stay beside their
+    /// relabeled copies, and terms added later with the old operator find the
+    /// same e-class. Returns `(relabeled, merged)`: the enodes relabeled and
+    /// the e-classes merged away.
+    fn relabel_op(
+        &mut self,
+        py: Python,
+        from_name: &PyAny,
+        to_name: &PyAny,
+    ) -> PyResult<(usize, usize)> {
+        self.check_mutable()?;
+        let from = self.operator(py, from_name)?;
+        let to = self.operator(py, to_name)?;
+
+        self.egraph.rebuild();
+        let before = self.egraph.number_of_classes();
+        let mut relabeled = vec![];
+        for class in self.egraph.classes() {
+            for node in class.iter().filter(|node| node.class.is(&from)) {
+                let node = PythonNode {
+                    class: to.clone_ref(py),
+                    children: node.children.clone(),
+                };
+                relabeled.push((class.id, node));
+            }
+        }
+        for (id, node) in &relabeled {
+            let new = self.egraph.add(node.clone());
+            self.egraph.union(*id, new);
+        }
+        self.egraph.rebuild();
+        self.egraph.analysis.raise_pending()?;
+        Ok((relabeled.len(), before - self.egraph.number_of_classes()))
+    }
+
+    /// How many enodes in the graph have the e-class `id` as a direct child
+    /// (an enode using it twice counts once).
+    fn reference_count(&self, id: PyId) -> usize {
+        let id = self.egraph.find(id.0);
+        self.egraph
+            .classes()
+            .flat_map(|class| class.iter())
+            .filter(|node| node.children.iter().any(|&c| self.egraph.find(c) == id))
+            .count()
+    }
+
+    /// Enumerate distinct terms of an e-class with at most `max_size` nodes,
+    /// smallest first and at most `max_count` of them.
+    #[args(max_size = "10", max_count = "100")]
+    fn all_terms(&self, py: Python, id: PyId, max_size: usize, max_count: usize) -> Vec<PyObject> {
+        TermEnumerator::new(&self.egraph, max_count).objects(py, id.0, max_size)
+    }
+
+    /// Up to `count` distinct terms of an e-class of any size, smallest first
+    /// and terms of the same size cheapest first under `cost` (default: the
+    /// e-graph's cost model). Rebuilds the e-graph first.
+    #[args(count = "10", cost = "None")]
+    fn enumerate_forms(
+        &mut self,
+        py: Python,
+        id: PyId,
+        count: usize,
+        cost: Option<&PyAny>,
+    ) -> PyResult<Vec<PyObject>> {
+        self.egraph.rebuild();
+        let model = self.cost_model(cost)?;
+        let mut terms = TermEnumerator::with_costs(py, &self.egraph, count, &model)?;
+        Ok(terms.smallest_objects(py, id.0))
+    }
+
+    /// Map every e-class to the cost of its best term under `cost` (default:
+    /// the e-graph's cost model), without reconstructing any terms.
+    #[args(cost = "None")]
+    fn best_costs(&self, py: Python, cost: Option<&PyAny>) -> PyResult<HashMap<PyId, f64>> {
+        let model = self.cost_model(cost)?;
+        let best = BestTerms::new(py, &self.egraph, &model, None)?;
+        Ok(self
+            .egraph
+            .classes()
+            .filter_map(|class| best.cost(class.id).map(|cost| (PyId(class.id), cost)))
+            .collect())
+    }
+
+    /// Bin the e-classes by the cost of their best term (as in `best_costs`)
+    /// into `buckets` equal-width buckets spanning the lowest to the highest
+    /// cost, returning `(low, high, count)` for each; the last bucket includes
+    /// its `high`. Classes without a finite cost are left out, and with none
+    /// left the result is empty.
+    #[args(buckets = "10", cost = "None")]
+    fn cost_histogram(
+        &self,
+        py: Python,
+        buckets: usize,
+        cost: Option<&PyAny>,
+    ) -> PyResult<Vec<(f64, f64, usize)>> {
+        if buckets == 0 {
+            return Err(PyValueError::new_err("buckets must be at least 1"));
+        }
+        let model = self.cost_model(cost)?;
+        let best = BestTerms::new(py, &self.egraph, &model, None)?;
+        let costs: Vec<f64> = self
+            .egraph
+            .classes()
+            .filter_map(|class| best.cost(class.id))
+            .collect();
+        let (low, high) = match costs.iter().copied().reduce(f64::min) {
+            Some(low) => (low, costs.iter().copied().fold(low, f64::max)),
+            None => return Ok(vec![]),
+        };
+        let width = (high - low) / buckets as f64;
+        let mut counts = vec![0; buckets];
+        for cost in costs {
+            let bucket = if width > 0.0 {
+                (((cost - low) / width) as usize).min(buckets - 1)
+            } else {
+                buckets - 1
+            };
+            counts[bucket] += 1;
+        }
+        Ok(counts
+            .into_iter()
+            .enumerate()
+            .map(|(i, count)| {
+                let bucket_high = if i + 1 == buckets {
+                    high
+                } else {
+                    low + width * (i + 1) as f64
+                };
+                (low + width * i as f64, bucket_high, count)
+            })
+            .collect())
+    }
+
+    /// The `n` largest e-classes, by number of enodes with `by="size"` or by
+    /// the cost of their best term (as in `best_costs`) with `by="cost"`,
+    /// largest first; equal classes come in id order. Ranking by cost leaves
+    /// out classes without a finite cost.
+    #[args(by = "\"size\"", cost = "None")]
+    fn top_classes(
+        &self,
+        py: Python,
+        n: usize,
+        by: &str,
+        cost: Option<&PyAny>,
+    ) -> PyResult<Vec<PyId>> {
+        let mut ranked: Vec<(f64, Id)> = match by {
+            "size" => self
+                .egraph
+                .classes()
+                .map(|class| (class.len() as f64, class.id))
+                .collect(),
+            "cost" => {
+                let model = self.cost_model(cost)?;
+                let best = BestTerms::new(py, &self.egraph, &model, None)?;
+                self.egraph
+                    .classes()
+                    .filter_map(|class| best.cost(class.id).map(|cost| (cost, class.id)))
+                    .collect()
+            }
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "by must be \"size\" or \"cost\", got {:?}",
+                    by
+                )))
+            }
+        };
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+        Ok(ranked.into_iter().take(n).map(|(_, id)| PyId(id)).collect())
+    }
+
+    /// Return the e-classes not reachable from any of `roots`. By default only
+    /// the best-term extraction DAG is followed; `all_nodes=True` follows
+    /// every enode instead.
+    #[args(all_nodes = "false")]
+    fn orphan_classes(&self, py: Python, roots: Vec<PyId>, all_nodes: bool) -> PyResult<Vec<PyId>> {
+        let mut best = BestTerms::new(py, &self.egraph, &self.cost_model, None)?;
+        let mut reachable: HashSet<Id> = HashSet::new();
+        let mut todo: Vec<Id> = roots.iter().map(|id| self.egraph.find(id.0)).collect();
+        while let Some(id) = todo.pop() {
+            if !reachable.insert(id) {
+                continue;
+            }
+            if all_nodes {
+                for node in self.egraph[id].iter() {
+                    todo.extend(node.children.iter().map(|&c| self.egraph.find(c)));
+                }
+            } else {
+                let node = best.node(id)?;
+                todo.extend(node.children.iter().map(|&c| self.egraph.find(c)));
+            }
+        }
+
+        let mut orphans: Vec<PyId> = self
+            .egraph
+            .classes()
+            .map(|class| self.egraph.find(class.id))
+            .filter(|id| !reachable.contains(id))
+            .map(PyId)
+            .collect();
+        orphans.sort();
+        Ok(orphans)
+    }
+
+    /// Export the e-graph as JSON in the `egraph-serialize` schema used by
+    /// extraction-gym and the e-graph visualizers. Node ids are `"<class>.<index>"`,
+    /// and children point at the first node of each child class.
+    #[args(roots = "None")]
+    fn to_egraph_serialize(&self, py: Python, roots: Option<Vec<PyId>>) -> PyResult<String> {
+        let extractor = Extractor::new(&self.egraph, AstSize);
+        let reconstruct_child = |child_id: Id| {
+            let (_cost, expr) = extractor.find_best(child_id);
+            reconstruct(py, &expr)
+        };
+
+        let nodes = PyDict::new(py);
+        let mut op_costs = OpCostCache::default();
+        for eclass in self.egraph.classes() {
+            let class_id = usize::from(self.egraph.find(eclass.id));
+            for (idx, node) in eclass.nodes.iter().enumerate() {
+                let children: Vec<String> = node
+                    .children
+                    .iter()
+                    .map(|&child| format!("{}.0", usize::from(self.egraph.find(child))))
+                    .collect();
+                let entry = PyDict::new(py);
+                entry.set_item("op", node_label(py, node, reconstruct_child))?;
+                entry.set_item("children", children)?;
+                entry.set_item("eclass", class_id.to_string())?;
+                let cost = op_costs.op_cost(py, &self.cost_model, node, eclass.data.as_ref())?;
+                entry.set_item("cost", cost)?;
+                nodes.set_item(format!("{}.{}", class_id, idx), entry)?;
+            }
+        }
+
+        let root_eclasses: Vec<String> = roots
+            .unwrap_or_default()
+            .iter()
+            .map(|id| usize::from(self.egraph.find(id.0)).to_string())
+            .collect();
+        let out = PyDict::new(py);
+        out.set_item("nodes", nodes)?;
+        out.set_item("root_eclasses", root_eclasses)?;
+
+        let json = py.import("json")?;
+        json.call_method1("dumps", (out,))?.extract()
+    }
+
+    /// Compare this e-graph with an earlier snapshot of it (e.g. a `copy()`
+    /// taken before `run()`). Classes are matched by structure, so `other`
+    /// needn't share ids with this graph. Returns a dict with
+    /// - `"merged"`: `(id, [old_ids])` for each class of this graph that
+    ///   several of `other`'s classes were merged into,
+    /// - `"new_nodes"`: `(id, label)` for each enode not present in `other`,
+    /// - `"unmatched"`: ids of `other`'s classes with no counterpart here.
+    fn diff(&self, py: Python, other: PyRef<PyEGraph>) -> PyResult<PyObject> {
+        let old = &other.egraph;
+        let matched = match_classes(old, &self.egraph);
+
+        let mut groups: HashMap<Id, Vec<PyId>> = HashMap::new();
+        let mut unmatched = Vec::new();
+        for class in old.classes() {
+            match matched.get(&class.id) {
+                Some(&id) => groups.entry(id).or_default().push(PyId(class.id)),
+                None => unmatched.push(PyId(class.id)),
+            }
+        }
+        let mut merged: Vec<(PyId, Vec<PyId>)> = groups
+            .into_iter()
+            .filter(|(_, olds)| olds.len() > 1)
+            .map(|(id, mut olds)| {
+                olds.sort();
+                (PyId(id), olds)
+            })
+            .collect();
+        merged.sort();
+        unmatched.sort();
+
+        let old_nodes: HashSet<PythonNode> = old
+            .classes()
+            .flat_map(|class| class.iter())
+            .filter_map(|node| {
+                let mut node = node.clone();
+                for child in node.children.iter_mut() {
+                    *child = *matched.get(&old.find(*child))?;
+                }
+                Some(node)
+            })
+            .collect();
+        let extractor = Extractor::new(&self.egraph, AstSize);
+        let reconstruct_child = |child_id: Id| {
+            let (_cost, expr) = extractor.find_best(child_id);
+            reconstruct(py, &expr)
+        };
+        let mut new_nodes: Vec<(PyId, String)> = self
+            .egraph
+            .classes()
+            .flat_map(|class| class.iter().map(move |node| (class.id, node)))
+            .filter(|(_, node)| {
+                !old_nodes.contains(&(*node).clone().map_children(|c| self.egraph.find(c)))
+            })
+            .map(|(id, node)| (PyId(id), node_label(py, node, reconstruct_child)))
+            .collect();
+        new_nodes.sort();
+
+        let out = PyDict::new(py);
+        out.set_item("merged", merged.into_py(py))?;
+        out.set_item("new_nodes", new_nodes.into_py(py))?;
+        out.set_item("unmatched", unmatched.into_py(py))?;
+        Ok(out.into())
+    }
+}
+
+impl PyEGraph {
+    /// An empty, mutable e-graph with `analysis` and default settings.
+    fn with_analysis(analysis: PythonAnalysis) -> Self {
+        Self {
+            egraph: EGraph::new(analysis),
+            stop_reason: None,
+            iterations: vec![],
+            hook_nodes: vec![],
+            passes: None,
+            pruned: None,
+            deadline: None,
+            commutative_ops: vec![],
+            cost_model: CostModel::Size,
+            labels: LabelInterner::default(),
+            frozen: false,
+        }
+    }
+
+    /// Makes the frozen `EGraph` with this one's settings that `run(until=...)`
+    /// gives its predicate, a copy of the runner's e-graph.
+    fn frozen_view(&self) -> EGraphView {
+        let commutative_ops = self.commutative_ops.clone();
+        let cost_model = self.cost_model.clone();
+        let labels = self.labels.clone();
+        Rc::new(move |py, egraph| {
+            let view = PyEGraph {
+                egraph: egraph.clone(),
+                commutative_ops: commutative_ops.clone(),
+                cost_model: cost_model.clone(),
+                labels: labels.clone(),
+                frozen: true,
+                ..PyEGraph::with_analysis(PythonAnalysis::default())
+            };
+            Ok(Py::new(py, view)?.into_py(py))
+        })
+    }
+
+    /// The node class `op` names: `op` itself if it's a class, otherwise the
+    /// one class in the e-graph with that name.
+    fn operator(&self, py: Python, op: &PyAny) -> PyResult<PyObject> {
+        if let Ok(ty) = op.downcast::<PyType>() {
+            return Ok(ty.into());
+        }
+        let name: &str = op.extract()?;
+        let mut found: Vec<&PyType> = vec![];
+        for node in self.egraph.classes().flat_map(|class| class.iter()) {
+            if let Ok(ty) = node.class.as_ref(py).downcast::<PyType>() {
+                if ty.name()? == name && !found.iter().any(|seen| seen.is(ty)) {
+                    found.push(ty);
+                }
+            }
+        }
+        match found[..] {
+            [ty] => Ok(ty.into()),
+            [] => Err(PyValueError::new_err(format!(
+                "No operator {} in the e-graph",
+                name
+            ))),
+            _ => Err(PyValueError::new_err(format!(
+                "More than one operator in the e-graph is named {}",
+                name
+            ))),
+        }
+    }
+
+    /// Raises if the e-graph is frozen.
+    fn check_mutable(&self) -> PyResult<()> {
+        if self.frozen {
+            return Err(PyRuntimeError::new_err("e-graph is frozen"));
+        }
+        Ok(())
+    }
+
+    /// `run()` without `yield_each_iteration`.
+    fn run_all(
+        &mut self,
+        py: Python,
+        rewrites: &PyAny,
+        options: RunOptions,
+    ) -> PyResult<PyRunResult> {
+        self.check_mutable()?;
+        let RunOptions {
+            iter_limit,
+            time_limit,
+            node_limit,
+            validate,
+            hook,
+            ordered,
+            on_union,
+            growth_factor,
+            log,
+            log_level,
+            on_node_limit,
+            until,
+            apply_limit,
+            guard,
+        } = options;
+        let (mut rewrites, groups) = PyRewrite::groups_from_any(py, rewrites)?;
+        rewrites.sort_by_key(|rewrite| Reverse(rewrite.borrow(py).priority));
+        let mut metadata = HashMap::new();
+        for rewrite in &rewrites {
+            let rewrite = rewrite.borrow(py);
+            metadata
+                .entry(rewrite.rewrite.name.to_string())
+                .or_insert_with(|| rewrite.metadata.clone());
+        }
+        let node_limit = match growth_factor {
+            Some(factor) if factor.is_nan() || factor < 1.0 => {
+                return Err(PyValueError::new_err(format!(
+                    "growth_factor must be at least 1, got {}",
+                    factor
+                )))
+            }
+            Some(factor) => (self.egraph.total_size() as f64 * factor) as usize,
+            None => node_limit,
+        };
+        if ordered && hook.is_some() {
+            return Err(PyValueError::new_err("hook can't be used with ordered"));
+        }
+        if ordered && until.is_some() {
+            return Err(PyValueError::new_err("until can't be used with ordered"));
+        }
+        let prune = match on_node_limit.as_str() {
+            "stop" => false,
+            "prune" if ordered => {
+                return Err(PyValueError::new_err(
+                    "on_node_limit=\"prune\" can't be used with ordered",
+                ))
+            }
+            "prune" => true,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "on_node_limit must be \"stop\" or \"prune\", got {:?}",
+                    on_node_limit
+                )))
+            }
+        };
+        let mut rewrites: Vec<Rewrite<PythonNode, PythonAnalysis>> = rewrites
+            .iter()
+            .map(|r| self.commutative(&r.borrow(py)))
+            .collect();
+        if on_union.is_some() || guard.is_some() {
+            self.egraph.analysis.on_union = Some(UnionObserver::new(on_union, guard));
+        }
+        for rewrite in &mut rewrites {
+            rewrite.applier = Arc::new(TracedApplier {
+                applier: rewrite.applier.clone(),
+            });
+        }
+        let mut time_limit = seconds("time_limit", time_limit)?;
+        if let Some(deadline) = self.deadline {
+            time_limit = time_limit.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        let log = log.map(|log| RunLog {
+            log,
+            level: log_level,
+            first: 0,
+        });
+        self.pruned = None;
+        self.egraph.analysis.apply_budget = apply_limit;
+        let result = if ordered {
+            self.run_ordered(&rewrites, &groups, iter_limit, time_limit, node_limit, log)
+        } else if prune {
+            self.passes = None;
+            self.run_pruning(
+                py, &rewrites, &groups, iter_limit, time_limit, node_limit, hook, until, log,
+            )
+        } else {
+            self.passes = None;
+            self.run_rewrites(
+                &rewrites, &groups, iter_limit, time_limit, node_limit, hook, until, log,
+            )
+        };
+        let observer = self.egraph.analysis.on_union.take();
+        self.egraph.analysis.apply_budget = None;
+        result?;
+        if let Some(err) = observer.and_then(|observer| observer.error.lock().unwrap().take()) {
+            return Err(err);
+        }
+        if validate {
+            let warnings = py.import("warnings")?;
+            let mut warned = HashSet::new();
+            for rewrite in &rewrites {
+                if !warned.insert(rewrite.name) {
+                    continue;
+                }
+                let applied: usize = self
+                    .iterations
+                    .iter()
+                    .filter_map(|iteration| iteration.applied.get(&rewrite.name))
+                    .sum();
+                if applied == 0 {
+                    let message = format!("Rewrite {:?} was never applied", rewrite.name.as_str());
+                    warnings.call_method1("warn", (message,))?;
+                }
+            }
+        }
+        let stop_reason = self.stop_reason.clone().unwrap();
+        let mut result = PyRunResult::new(stop_reason, &self.iterations, &self.egraph);
+        result.metadata = metadata;
+        Ok(result)
+    }
+
+    /// One egg run of `rewrites`, recording its stop reason and iterations.
+    #[allow(clippy::too_many_arguments)]
+    fn run_rewrites(
+        &mut self,
+        rewrites: &[Rewrite<PythonNode, PythonAnalysis>],
+        groups: &[(Symbol, GroupConfig)],
+        iter_limit: usize,
+        time_limit: Duration,
+        node_limit: usize,
+        hook: Option<PyObject>,
+        until: Option<PyObject>,
+        log: Option<RunLog>,
+    ) -> PyResult<()> {
+        let view = self.frozen_view();
+        let egraph = std::mem::take(&mut self.egraph);
+        let scheduled_runner = PythonRunner::default().with_scheduler(backoff_scheduler(groups));
+        let (scheduled_runner, node_limit_hit) = with_node_limit(scheduled_runner, node_limit);
+        let scheduled_runner = stop_at_apply_limit(stop_on_callback_error(scheduled_runner));
+        let (scheduled_runner, until) = match until {
+            Some(until) => {
+                let (runner, until) = with_until(scheduled_runner, until, view);
+                (runner, Some(until))
+            }
+            None => (scheduled_runner, None),
+        };
+        let (scheduled_runner, python_hook) = match hook {
+            Some(hook) => {
+                let (runner, python_hook) = with_python_hook(scheduled_runner, hook);
+                (runner, Some(python_hook))
+            }
+            None => (scheduled_runner, None),
+        };
+        // after the Python hook, so an iteration it stops isn't logged twice
+        let (scheduled_runner, logger) = match log {
+            Some(log) => {
+                let (runner, logger) = with_logger(scheduled_runner, log);
+                (runner, Some(logger))
+            }
+            None => (scheduled_runner, None),
+        };
+        let runner = scheduled_runner
+            .with_iter_limit(iter_limit)
+            .with_time_limit(time_limit)
+            .with_egraph(egraph)
+            .run(rewrites);
+        let logged = logger.map_or(Ok(()), |logger| logger.finish(&runner));
+
+        let reason = node_limit_hit.stop_reason(&runner);
+        let partial = stopped_mid_apply(&runner.iterations);
+        self.stop_reason = Some(PyStopReason::new(&reason, partial));
+        self.iterations = runner.iterations;
+        self.egraph = runner.egraph;
+        self.egraph.analysis.raise_pending()?;
+        self.hook_nodes = match python_hook {
+            Some(python_hook) => python_hook.finish()?,
+            None => vec![],
+        };
+        until.map_or(Ok(()), Until::finish)?;
+        logged
+    }
+
+    /// Run each rewrite to saturation in order, in passes until a pass
+    /// changes nothing or a limit is hit. Limits are shared by the whole run,
+    /// and a run's `hook` and `until`, which are per iteration, don't apply.
+    fn run_ordered(
+        &mut self,
+        rewrites: &[Rewrite<PythonNode, PythonAnalysis>],
+        groups: &[(Symbol, GroupConfig)],
+        iter_limit: usize,
+        time_limit: Duration,
+        node_limit: usize,
+        log: Option<RunLog>,
+    ) -> PyResult<()> {
+        let start = Instant::now();
+        let mut iterations = vec![];
+        let mut passes = 0;
+        let mut changed = true;
+        self.stop_reason = Some(PyStopReason::new(&StopReason::Saturated, false));
+        'passes: while changed {
+            passes += 1;
+            changed = false;
+            for rewrite in rewrites {
+                self.run_rewrites(
+                    std::slice::from_ref(rewrite),
+                    groups,
+                    iter_limit.saturating_sub(iterations.len()),
+                    time_limit.saturating_sub(start.elapsed()),
+                    node_limit,
+                    None,
+                    None,
+                    log.clone().map(|log| RunLog {
+                        first: iterations.len(),
+                        ..log
+                    }),
+                )?;
+                changed |= self.iterations.iter().any(|i| !i.applied.is_empty());
+                iterations.append(&mut self.iterations);
+                // report limits for the whole run, not this rewrite's part
+                let reason = self.stop_reason.as_mut().unwrap();
+                match reason.kind.as_str() {
+                    "saturated" => continue,
+                    "iteration_limit" => reason.value = Some(iterations.len() as f64),
+                    "time_limit" => reason.value = Some(start.elapsed().as_secs_f64()),
+                    _ => (),
+                }
+                break 'passes;
+            }
+        }
+        self.iterations = iterations;
+        self.passes = Some(passes);
+        Ok(())
+    }
+
+    /// `run_rewrites`, pruning the e-graph and carrying on each time it stops
+    /// at the node limit, until it stops for another reason or there is
+    /// nothing left to prune. Limits are shared by the whole run.
+    ///
+    /// Each prune goes down to half the node limit, keeping every e-class and
+    /// the enode of its best term under the cost model (see `prune`). It is
+    /// heuristic and lossy: equalities found through the dropped enodes are
+    /// kept, but the terms they represented are gone. The e-graph is rebuilt
+    /// from the enodes kept, so ids change; `last_run_pruned` maps them.
+    #[allow(clippy::too_many_arguments)]
+    fn run_pruning(
+        &mut self,
+        py: Python,
+        rewrites: &[Rewrite<PythonNode, PythonAnalysis>],
+        groups: &[(Symbol, GroupConfig)],
+        iter_limit: usize,
+        time_limit: Duration,
+        node_limit: usize,
+        hook: Option<PyObject>,
+        until: Option<PyObject>,
+        log: Option<RunLog>,
+    ) -> PyResult<()> {
+        let start = Instant::now();
+        let mut iterations = vec![];
+        let mut hook_nodes = vec![];
+        let mut pruned: Option<(usize, HashMap<Id, Id>)> = None;
+        loop {
+            self.run_rewrites(
+                rewrites,
+                groups,
+                iter_limit.saturating_sub(iterations.len()),
+                time_limit.saturating_sub(start.elapsed()),
+                node_limit,
+                hook.clone(),
+                until.clone(),
+                log.clone().map(|log| RunLog {
+                    first: iterations.len(),
+                    ..log
+                }),
+            )?;
+            let at_limit = self.stop_reason.as_ref().unwrap().kind == "node_limit";
+            let (enodes, moved) = match at_limit {
+                true => self.prune(py, node_limit / 2)?,
+                false => (0, HashMap::new()),
+            };
+            if enodes > 0 {
+                // egg's record of the iteration the limit stopped before it ran
+                self.iterations.pop();
+            }
+            iterations.append(&mut self.iterations);
+            hook_nodes.append(&mut self.hook_nodes);
+            if enodes == 0 {
+                break;
+            }
+            pruned = Some(match pruned {
+                None => (enodes, moved),
+                Some((total, ids)) => {
+                    let ids = ids
+                        .into_iter()
+                        .filter_map(|(old, id)| Some((old, *moved.get(&id)?)))
+                        .collect();
+                    (total + enodes, ids)
+                }
+            });
+        }
+        // report limits for the whole run, not its last part
+        let reason = self.stop_reason.as_mut().unwrap();
+        match reason.kind.as_str() {
+            "iteration_limit" => reason.value = Some(iterations.len() as f64),
+            "time_limit" => reason.value = Some(start.elapsed().as_secs_f64()),
+            _ => (),
+        }
+        self.iterations = iterations;
+        self.hook_nodes = hook_nodes;
+        self.pruned = pruned;
+        Ok(())
+    }
+
+    /// Rebuild the e-graph without its costliest enodes, down to `target`
+    /// enodes if it can: each class's best enode is kept, and the others go
+    /// in order of the cost of their best terms, the latest added first among
+    /// equals. Returns how many enodes were dropped, and the id of the same
+    /// class in the new e-graph of every id handed out by the old one.
+    fn prune(&mut self, py: Python, target: usize) -> PyResult<(usize, HashMap<Id, Id>)> {
+        self.egraph.rebuild();
+        let egraph = &self.egraph;
+        let mut best = BestTerms::new(py, egraph, &self.cost_model, None)?;
+        let order = insertion_order(egraph);
+        let mut kept = vec![];
+        let mut spare = vec![];
+        for class in egraph.classes() {
+            let chosen = match best.cost(class.id) {
+                Some(_) => Some(best.node(class.id)?.clone()),
+                None => None,
+            };
+            for node in class.iter() {
+                if chosen.as_ref() == Some(node) {
+                    kept.push((class.id, node));
+                } else {
+                    let cost = best.node_cost(node).unwrap_or(f64::INFINITY);
+                    let added = order.get(node).copied().unwrap_or(usize::MAX);
+                    spare.push((cost, added, class.id, node));
+                }
+            }
+        }
+        spare.sort_by(|a, b| b.0.total_cmp(&a.0).then(b.1.cmp(&a.1)));
+        let excess = egraph.total_size().saturating_sub(target).min(spare.len());
+        kept.extend(spare[excess..].iter().map(|&(_, _, id, node)| (id, node)));
+        let (mut new, ids) = rebuilt_from(egraph, kept);
+        // the run carries on in the new e-graph, with what's left of its budget
+        new.analysis.apply_budget = egraph.analysis.apply_budget;
+        let moved = (0..egraph.analysis.id_count.get())
+            .map(Id::from)
+            .filter_map(|id| Some((id, *ids.get(&egraph.find(id))?)))
+            .collect();
+        self.egraph = new;
+        Ok((excess, moved))
+    }
+
+    /// The e-class of a query's `expr` (an expression or an `Id`), adding
+    /// it if need be; a frozen e-graph only looks it up and raises
+    /// `ValueError` if it isn't there.
+    fn term_id(&mut self, expr: &PyAny) -> PyResult<Id> {
+        if !self.frozen {
+            return build_node(&mut self.egraph, expr);
+        }
+        lookup_node(&mut self.egraph, expr)?
+            .ok_or_else(|| PyValueError::new_err(format!("{} is not in the frozen e-graph", expr)))
+    }
+
+    /// The cost model given by a call's `cost` argument, or the default one.
+    fn cost_model(&self, cost: Option<&PyAny>) -> PyResult<CostModel> {
+        match cost {
+            Some(cost) => CostModel::from_py(cost, false),
+            None => Ok(self.cost_model.clone()),
+        }
+    }
+
+    /// `id`, if this e-graph handed it out.
+    fn known(&self, id: PyId) -> PyResult<Id> {
+        if usize::from(id.0) < self.egraph.analysis.id_count.get() {
+            Ok(id.0)
+        } else {
+            Err(PyValueError::new_err(format!(
+                "Id {} isn't from this e-graph",
+                id.0
+            )))
+        }
+    }
+
+    /// The searcher `search` uses for `pattern`, which also matches swapped
+    /// arguments of the commutative ops and checks the pattern's kinds.
+    fn searcher(&self, pattern: PyPattern) -> SharedSearcher {
+        let searcher: SharedSearcher =
+            match CommutativeSearcher::new(&pattern.pattern.ast, &self.commutative_ops) {
+                Some(searcher) => Arc::new(searcher),
+                None => Arc::new(pattern.pattern),
+            };
+        KindedSearcher::wrap(searcher, &pattern.kinds, &pattern.structural)
+    }
+
+    /// `rewrite`, searching with a `CommutativeSearcher` if its pattern has
+    /// any commutative operators (still enforcing its vars' kinds).
+    fn commutative(&self, rewrite: &PyRewrite) -> Rewrite<PythonNode, PythonAnalysis> {
+        let (kinds, structural) = (&rewrite.kinds, &rewrite.structural);
+        let mut rewrite = rewrite.rewrite.clone();
+        let searcher = rewrite
+            .searcher
+            .get_pattern_ast()
+            .and_then(|ast| CommutativeSearcher::new(ast, &self.commutative_ops));
+        if let Some(searcher) = searcher {
+            rewrite.searcher = KindedSearcher::wrap(Arc::new(searcher), kinds, structural);
+        }
+        rewrite
+    }
+}
+
+/// String form of an `Operation`'s name field, falling back to `str()`.
+fn operation_name(py: Python, name_obj: &PyObject) -> String {
+    name_obj
+        .cast_as::<PyString>(py)
+        .ok()
+        .map(|s| s.to_str().unwrap_or("<?>").to_string())
+        .unwrap_or_else(|| {
+            name_obj
+                .as_ref(py)
+                .str()
+                .map(|s| s.to_str().unwrap_or("<?>").to_string())
+                .unwrap_or_else(|_| "<?>".to_string())
+        })
+}
+
+/// Stable operator label for an enode, used wherever nodes are exported by name.
+/// `Operation`-shaped nodes (5 fields) are labeled by their op name, other
+/// constructors by their class name, and leaves by `str()` of the stored object.
+pub(crate) fn node_label(
+    py: Python,
+    node: &PythonNode,
+    reconstruct_child: impl Fn(Id) -> PyObject,
+) -> String {
+    if node.children.len() == 5 {
+        return operation_name(py, &reconstruct_child(node.children[0]));
+    }
+    class_label(py, node)
+}
+
+/// `vars` without the copies in `structural`.
+fn original_vars(vars: Vec<Var>, structural: &[(Var, Var)]) -> Vec<Var> {
+    vars.into_iter()
+        .filter(|var| !structural.iter().any(|(copy, _)| copy == var))
+        .collect()
+}
+
+/// `node_label` without the special case for `Operation`-shaped nodes: the
+/// class name of a constructor, or `str()` of a leaf.
+pub(crate) fn class_label(py: Python, node: &PythonNode) -> String {
+    let class = node.class.as_ref(py);
+    if let Ok(ty) = class.downcast::<PyType>() {
+        ty.name().unwrap_or("<class>").to_string()
+    } else {
+        class
+            .str()
+            .map(|s| s.to_str().unwrap_or("<?>").to_string())
+            .unwrap_or_else(|_| "<?>".to_string())
+    }
+}
+
+/// Whether e-class `id` has a term shaped like the subpattern at `at` of
+/// `ast`, for `find_structural_duplicates`.
+fn shaped_like(
+    py: Python,
+    egraph: &EGraph<PythonNode, PythonAnalysis>,
+    ast: &[ENodeOrVar<PythonNode>],
+    at: Id,
+    id: Id,
+    memo: &mut HashMap<(Id, Id), bool>,
+) -> bool {
+    if let Some(&shaped) = memo.get(&(at, id)) {
+        return shaped;
+    }
+    let shaped = match &ast[usize::from(at)] {
+        ENodeOrVar::Var(_) => true,
+        ENodeOrVar::ENode(pattern) => {
+            let label = class_label(py, pattern);
+            egraph[id].iter().any(|node| {
+                node.children.len() == pattern.children.len()
+                    && class_label(py, node) == label
+                    && node
+                        .children
+                        .iter()
+                        .zip(&pattern.children)
+                        .all(|(&child, &sub)| {
+                            shaped_like(py, egraph, ast, sub, egraph.find(child), memo)
+                        })
+            })
+        }
+    };
+    memo.insert((at, id), shaped);
+    shaped
+}
+
+/// `secs` seconds, or a `ValueError` naming `what` if that isn't a finite
+/// non-negative number.
+fn seconds(what: &str, secs: f64) -> PyResult<Duration> {
+    if !(secs.is_finite() && secs >= 0.0) {
+        return Err(PyValueError::new_err(format!(
+            "{} must be a finite number of seconds, at least 0, got {}",
+            what, secs
+        )));
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// Raises unless a term of `size` enodes is within `max_nodes`.
+fn check_term_size(size: usize, max_nodes: usize) -> PyResult<()> {
+    if size > max_nodes {
+        return Err(PyValueError::new_err(format!(
+            "Term has more than max_nodes={} enodes",
+            max_nodes
+        )));
+    }
+    Ok(())
+}
+
+/// `obj` reconstructed from `node`, passed through `decode` if `node` is a leaf.
+pub(crate) fn decode_leaf(
+    node: &PythonNode,
+    obj: PyObject,
+    decode: Option<&PyAny>,
+) -> PyResult<PyObject> {
+    match decode {
+        Some(decode) if node.is_leaf() => decode.call1((obj,)).map(Into::into),
+        _ => Ok(obj),
+    }
+}
+
+/// `reconstruct` with every leaf passed through `decode`.
+pub(crate) fn reconstruct_decoded(
+    py: Python,
+    recexpr: &RecExpr<PythonNode>,
+    decode: Option<&PyAny>,
+) -> PyResult<PyObject> {
+    let mut objs = Vec::<PyObject>::with_capacity(recexpr.as_ref().len());
+    for node in recexpr.as_ref() {
+        let obj = node.to_object(py, |id| objs[usize::from(id)].clone());
+        objs.push(decode_leaf(node, obj, decode)?)
+    }
+    Ok(objs.pop().unwrap())
+}
+
+pub(crate) fn reconstruct(py: Python, recexpr: &RecExpr<PythonNode>) -> PyObject {
+    reconstruct_decoded(py, recexpr, None).unwrap()
 }
@@ -0,0 +1,1415 @@
+use egg::{AstSize, EGraph, Extractor, Id, Language, RecExpr};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyType;
+use pyo3::AsPyPointer;
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::core::{decode_leaf, node_label, reconstruct};
+use crate::lang::{insertion_order, PythonAnalysis, PythonNode};
+
+/// How extraction prices a term.
+#[derive(Clone, Default)]
+pub enum CostModel {
+    /// One per enode (egg's `AstSize`).
+    #[default]
+    Size,
+    /// The longest root-to-leaf path (egg's `AstDepth`).
+    Depth,
+    /// `cost(op)` per enode, summed over the term. `op` is the node class or,
+    /// for leaves, the leaf object.
+    Callable(PyObject),
+    /// Like `Callable`, but called as `cost(op, data)` with the analysis data
+    /// of the enode's e-class (`None` if it has none).
+    WithData(PyObject),
+}
+
+impl CostModel {
+    /// Accept `"size"`, `"depth"` or a callable; `with_data` selects
+    /// `WithData` for a callable.
+    pub fn from_py(model: &PyAny, with_data: bool) -> PyResult<Self> {
+        if model.is_callable() {
+            return Ok(if with_data {
+                CostModel::WithData(model.into())
+            } else {
+                CostModel::Callable(model.into())
+            });
+        }
+        match model.extract::<&str>() {
+            Ok("size") => Ok(CostModel::Size),
+            Ok("depth") => Ok(CostModel::Depth),
+            _ => Err(PyValueError::new_err(
+                "Cost model must be \"size\", \"depth\" or a callable",
+            )),
+        }
+    }
+
+    /// The cost this model charges for `node` itself, given its e-class's
+    /// analysis `data`.
+    pub fn op_cost(&self, py: Python, node: &PythonNode, data: Option<&PyObject>) -> PyResult<f64> {
+        let cost = match self {
+            CostModel::Size | CostModel::Depth => return Ok(1.0),
+            CostModel::Callable(cost) => cost.call1(py, (node.class.clone_ref(py),))?,
+            CostModel::WithData(cost) => cost.call1(py, (node.class.clone_ref(py), data))?,
+        };
+        let cost: f64 = cost.extract(py)?;
+        if cost.is_nan() || cost < 0.0 {
+            return Err(PyValueError::new_err(format!(
+                "Costs must be non-negative, got {}",
+                cost
+            )));
+        }
+        Ok(cost)
+    }
+}
+
+/// [`CostModel::op_cost`] for the enodes of one extraction, calling a callable
+/// model once per operator: its cost only depends on the node class or leaf,
+/// so enodes sharing an operator share a call. A model that also takes data
+/// is called for every enode.
+#[derive(Default)]
+pub struct OpCostCache {
+    by_op: HashMap<usize, f64>,
+}
+
+impl OpCostCache {
+    pub fn op_cost(
+        &mut self,
+        py: Python,
+        model: &CostModel,
+        node: &PythonNode,
+        data: Option<&PyObject>,
+    ) -> PyResult<f64> {
+        if let CostModel::WithData(_) = model {
+            return model.op_cost(py, node, data);
+        }
+        match self.by_op.entry(node.class.as_ptr() as usize) {
+            Entry::Occupied(entry) => Ok(*entry.get()),
+            Entry::Vacant(entry) => Ok(*entry.insert(model.op_cost(py, node, None)?)),
+        }
+    }
+}
+
+/// Whether the e-class `id` has a term costing at most `max_cost` under
+/// `model`. This is the cost fixpoint of [`BestTerms`], except that costs over
+/// `max_cost` are dropped, which costs being non-negative can't make any
+/// qualifying term use, and it stops as soon as `id` gets a cost.
+pub fn has_term_under_cost(
+    py: Python,
+    egraph: &EGraph<PythonNode, PythonAnalysis>,
+    model: &CostModel,
+    id: Id,
+    max_cost: f64,
+) -> PyResult<bool> {
+    let id = egraph.find(id);
+    let mut cache = OpCostCache::default();
+    let mut costs: HashMap<Id, f64> = HashMap::new();
+    let mut did_something = true;
+    while did_something {
+        did_something = false;
+        for class in egraph.classes() {
+            let mut best = costs.get(&class.id).copied();
+            for node in class.iter() {
+                let children: Option<Vec<f64>> = node
+                    .children
+                    .iter()
+                    .map(|&child| costs.get(&egraph.find(child)).copied())
+                    .collect();
+                let children = match children {
+                    Some(children) => children,
+                    None => continue,
+                };
+                let op_cost = cache.op_cost(py, model, node, class.data.as_ref())?;
+                let cost = match model {
+                    CostModel::Depth => children
+                        .iter()
+                        .fold(op_cost, |depth, cost| depth.max(op_cost + cost)),
+                    _ => op_cost + children.iter().sum::<f64>(),
+                };
+                if cost <= max_cost && best.is_none_or(|best| cost < best) {
+                    best = Some(cost);
+                }
+            }
+            if best != costs.get(&class.id).copied() {
+                if class.id == id {
+                    return Ok(true);
+                }
+                costs.insert(class.id, best.unwrap());
+                did_something = true;
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Enodes that a maximizing extraction leaves out so that the chosen enodes
+/// never form a cycle, which would be worth going round forever.
+///
+/// Every enode whose children all got a finite cost in earlier passes of the
+/// cost fixpoint is kept, so each class with a term keeps one. The others are
+/// then kept in the order they were added, each unless one of its children
+/// can already reach its class through the enodes kept so far.
+fn cyclic_nodes(
+    egraph: &EGraph<PythonNode, PythonAnalysis>,
+    forbidden: &HashSet<PythonNode>,
+) -> HashSet<PythonNode> {
+    let usable = |node: &PythonNode| !forbidden.contains(node);
+    let mut passes: HashMap<Id, usize> = HashMap::new();
+    for pass in 0.. {
+        let found: Vec<Id> = egraph
+            .classes()
+            .filter(|class| !passes.contains_key(&class.id))
+            .filter(|class| {
+                class.iter().filter(|node| usable(node)).any(|node| {
+                    node.children
+                        .iter()
+                        .all(|&child| passes.contains_key(&egraph.find(child)))
+                })
+            })
+            .map(|class| class.id)
+            .collect();
+        if found.is_empty() {
+            break;
+        }
+        passes.extend(found.into_iter().map(|id| (id, pass)));
+    }
+
+    let mut uses: HashMap<Id, Vec<Id>> = HashMap::new();
+    let mut rest = vec![];
+    for class in egraph.classes() {
+        for node in class.iter().filter(|node| usable(node)) {
+            let children: Vec<Id> = node.children.iter().map(|&c| egraph.find(c)).collect();
+            let earlier = passes.get(&class.id).is_some_and(|&pass| {
+                children
+                    .iter()
+                    .all(|child| passes.get(child).is_some_and(|&p| p < pass))
+            });
+            if earlier {
+                uses.entry(class.id).or_default().extend(children);
+            } else {
+                rest.push((class.id, node, children));
+            }
+        }
+    }
+    let order = insertion_order(egraph);
+    rest.sort_by_key(|(_, node, _)| order.get(*node).copied().unwrap_or(usize::MAX));
+    let mut cyclic = HashSet::new();
+    for (id, node, children) in rest {
+        if children.iter().any(|&child| reaches(&uses, child, id)) {
+            cyclic.insert(node.clone());
+        } else {
+            uses.entry(id).or_default().extend(children);
+        }
+    }
+    cyclic
+}
+
+fn reaches(uses: &HashMap<Id, Vec<Id>>, from: Id, to: Id) -> bool {
+    let mut seen = HashSet::new();
+    let mut todo = vec![from];
+    while let Some(id) = todo.pop() {
+        if id == to {
+            return true;
+        }
+        if seen.insert(id) {
+            todo.extend(uses.get(&id).into_iter().flatten());
+        }
+    }
+    false
+}
+
+struct Choice {
+    node: PythonNode,
+    obj: PyObject,
+    /// Depth of the chosen term; a leaf has depth 1.
+    depth: usize,
+    /// Enodes in the chosen term as a tree, saturating at `usize::MAX`.
+    size: usize,
+    sexpr: Option<String>,
+}
+
+/// Best-term extraction under a [`CostModel`] that picks deterministically
+/// among equal-cost enodes.
+///
+/// Ties are broken by `tie_break(a, b)` on the reconstructed candidates
+/// (negative means `a` is preferred, like a `cmp` function), and otherwise by
+/// comparing the candidates' s-expressions, so the result never depends on
+/// hash map order. With an insertion `order`, the earliest-added candidate is
+/// preferred before comparing s-expressions.
+pub struct BestTerms<'a> {
+    py: Python<'a>,
+    egraph: &'a EGraph<PythonNode, PythonAnalysis>,
+    model: &'a CostModel,
+    /// `model.op_cost` of each enode.
+    op_costs: HashMap<PythonNode, f64>,
+    /// Enodes that are never part of an extracted term.
+    forbidden: HashSet<PythonNode>,
+    costs: HashMap<Id, f64>,
+    tie_break: Option<&'a PyAny>,
+    decode: Option<&'a PyAny>,
+    /// When each enode was added, for `prefer_original`.
+    order: Option<HashMap<PythonNode, usize>>,
+    chosen: HashMap<Id, Choice>,
+    in_progress: HashSet<Id>,
+    /// Extract the term of highest cost instead of lowest.
+    maximize: bool,
+}
+
+impl<'a> BestTerms<'a> {
+    pub fn new(
+        py: Python<'a>,
+        egraph: &'a EGraph<PythonNode, PythonAnalysis>,
+        model: &'a CostModel,
+        tie_break: Option<&'a PyAny>,
+    ) -> PyResult<Self> {
+        Self::with_forbidden(py, egraph, model, tie_break, HashSet::new())
+    }
+
+    /// Like `new`, but never choosing any enode in `forbidden`.
+    pub fn with_forbidden(
+        py: Python<'a>,
+        egraph: &'a EGraph<PythonNode, PythonAnalysis>,
+        model: &'a CostModel,
+        tie_break: Option<&'a PyAny>,
+        forbidden: HashSet<PythonNode>,
+    ) -> PyResult<Self> {
+        Self::build_costs(py, egraph, model, tie_break, forbidden, false)
+    }
+
+    /// Like `with_forbidden`, but choosing the term of highest cost, which
+    /// the model then gives as a value. Enodes that would let a class's term
+    /// contain the class itself are never chosen (see [`cyclic_nodes`]).
+    pub fn maximizing(
+        py: Python<'a>,
+        egraph: &'a EGraph<PythonNode, PythonAnalysis>,
+        model: &'a CostModel,
+        tie_break: Option<&'a PyAny>,
+        mut forbidden: HashSet<PythonNode>,
+    ) -> PyResult<Self> {
+        let cyclic = cyclic_nodes(egraph, &forbidden);
+        forbidden.extend(cyclic);
+        Self::build_costs(py, egraph, model, tie_break, forbidden, true)
+    }
+
+    fn build_costs(
+        py: Python<'a>,
+        egraph: &'a EGraph<PythonNode, PythonAnalysis>,
+        model: &'a CostModel,
+        tie_break: Option<&'a PyAny>,
+        forbidden: HashSet<PythonNode>,
+        maximize: bool,
+    ) -> PyResult<Self> {
+        let mut cache = OpCostCache::default();
+        let mut op_costs = HashMap::new();
+        for class in egraph.classes() {
+            for node in class.iter() {
+                let cost = cache.op_cost(py, model, node, class.data.as_ref())?;
+                op_costs.insert(node.clone(), cost);
+            }
+        }
+        let mut best = Self {
+            py,
+            egraph,
+            model,
+            op_costs,
+            forbidden,
+            costs: HashMap::new(),
+            tie_break,
+            decode: None,
+            order: None,
+            chosen: HashMap::new(),
+            in_progress: HashSet::new(),
+            maximize,
+        };
+        best.find_costs();
+        Ok(best)
+    }
+
+    /// Pass each leaf's stored object through `decode` when reconstructing.
+    pub fn set_decode(&mut self, decode: Option<&'a PyAny>) {
+        self.decode = decode;
+    }
+
+    /// Break ties in favor of the enode added first, by `order`; enodes
+    /// missing from it count as added last.
+    pub fn set_order(&mut self, order: Option<HashMap<PythonNode, usize>>) {
+        self.order = order;
+    }
+
+    /// Same fixpoint as egg's `Extractor`: iterate until no class improves.
+    fn find_costs(&mut self) {
+        let mut did_something = true;
+        while did_something {
+            did_something = false;
+            for class in self.egraph.classes() {
+                let pass = class
+                    .iter()
+                    .filter_map(|node| self.node_cost(node))
+                    .reduce(|best, cost| if self.better(cost, best) { cost } else { best });
+                let id = self.egraph.find(class.id);
+                match (self.costs.get(&id), pass) {
+                    (None, Some(new)) => {
+                        self.costs.insert(id, new);
+                        did_something = true;
+                    }
+                    (Some(&old), Some(new)) if self.better(new, old) => {
+                        self.costs.insert(id, new);
+                        did_something = true;
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    fn better(&self, cost: f64, than: f64) -> bool {
+        if self.maximize {
+            cost > than
+        } else {
+            cost < than
+        }
+    }
+
+    /// The cost of the best term with `node` at its root, if it is finite and
+    /// `node` isn't forbidden.
+    pub fn node_cost(&self, node: &PythonNode) -> Option<f64> {
+        if self.forbidden.contains(node) {
+            return None;
+        }
+        let op_cost = self.op_costs[node];
+        let mut children = node
+            .children
+            .iter()
+            .map(|&child| self.costs.get(&self.egraph.find(child)).copied());
+        match self.model {
+            CostModel::Depth => children.try_fold(op_cost, |depth, cost| {
+                cost.map(|cost| depth.max(op_cost + cost))
+            }),
+            _ => children.try_fold(op_cost, |sum, cost| cost.map(|cost| sum + cost)),
+        }
+    }
+
+    /// The cost of the best term of `id`, if it has a finite one.
+    pub fn cost(&self, id: Id) -> Option<f64> {
+        self.costs.get(&self.egraph.find(id)).copied()
+    }
+
+    /// Reconstruct the chosen best term of the e-class `id`.
+    pub fn object(&mut self, id: Id) -> PyResult<PyObject> {
+        let id = self.egraph.find(id);
+        self.choose(id)?;
+        Ok(self.chosen[&id].obj.clone_ref(self.py))
+    }
+
+    /// The cheapest term with `node` at its root, and its cost: `node` over
+    /// the best terms of its children. `None` if `node` is forbidden or a
+    /// child has no finite cost.
+    pub fn rooted_at(&mut self, node: &PythonNode) -> PyResult<Option<(f64, PyObject)>> {
+        let cost = match self.node_cost(node) {
+            Some(cost) => cost,
+            None => return Ok(None),
+        };
+        let children = node
+            .children
+            .iter()
+            .map(|&child| self.object(child))
+            .collect::<PyResult<Vec<_>>>()?;
+        let mut children = children.into_iter();
+        let obj = node.to_object(self.py, |_| children.next().unwrap());
+        Ok(Some((cost, decode_leaf(node, obj, self.decode)?)))
+    }
+
+    /// The depth of the chosen best term of `id`, counting a leaf as 1.
+    pub fn depth(&mut self, id: Id) -> PyResult<usize> {
+        let id = self.egraph.find(id);
+        self.choose(id)?;
+        Ok(self.chosen[&id].depth)
+    }
+
+    /// How many enodes the chosen best term of `id` has as a tree, counting a
+    /// shared subterm at each occurrence (saturating at `usize::MAX`).
+    pub fn tree_size(&mut self, id: Id) -> PyResult<usize> {
+        let id = self.egraph.find(id);
+        self.choose(id)?;
+        Ok(self.chosen[&id].size)
+    }
+
+    /// The enode chosen as the root of the e-class `id`'s best term.
+    pub fn node(&mut self, id: Id) -> PyResult<&PythonNode> {
+        let id = self.egraph.find(id);
+        self.choose(id)?;
+        Ok(&self.chosen[&id].node)
+    }
+
+    /// The chosen enode of every class reachable from `id`'s best term, as its
+    /// index in the class's `nodes`.
+    pub fn plan(&mut self, id: Id) -> PyResult<HashMap<Id, usize>> {
+        let mut plan = HashMap::new();
+        let mut todo = vec![self.egraph.find(id)];
+        while let Some(id) = todo.pop() {
+            if plan.contains_key(&id) {
+                continue;
+            }
+            let egraph = self.egraph;
+            let node = self.node(id)?;
+            let index = egraph[id].nodes.iter().position(|n| n == node);
+            todo.extend(node.children.iter().map(|&child| egraph.find(child)));
+            plan.insert(id, index.expect("chosen enode is in its class"));
+        }
+        Ok(plan)
+    }
+
+    /// The chosen best term of `id` as a `RecExpr`, each shared subterm once.
+    pub fn rec_expr(&mut self, id: Id) -> PyResult<RecExpr<PythonNode>> {
+        let mut expr = RecExpr::default();
+        let mut added = HashMap::new();
+        self.add_to(id, &mut expr, &mut added)?;
+        Ok(expr)
+    }
+
+    fn add_to(
+        &mut self,
+        id: Id,
+        expr: &mut RecExpr<PythonNode>,
+        added: &mut HashMap<Id, Id>,
+    ) -> PyResult<Id> {
+        let id = self.egraph.find(id);
+        if let Some(&index) = added.get(&id) {
+            return Ok(index);
+        }
+        let node = self.node(id)?.clone();
+        let children = node
+            .children
+            .iter()
+            .map(|&child| self.add_to(child, expr, added))
+            .collect::<PyResult<Vec<Id>>>()?;
+        let mut children = children.into_iter();
+        let index = expr.add(node.map_children(|_| children.next().unwrap()));
+        added.insert(id, index);
+        Ok(index)
+    }
+
+    /// The `node_label` of `node`, reconstructing the best term of its op's
+    /// class if it is `Operation`-shaped.
+    pub fn label(&mut self, node: &PythonNode) -> PyResult<String> {
+        // only an `Operation`-shaped node's label needs a child's term
+        let op = match node.children.len() {
+            5 => Some(self.object(node.children[0])?),
+            _ => None,
+        };
+        Ok(node_label(self.py, node, |_| op.clone().unwrap()))
+    }
+
+    /// The labels (see `node_label`) down `id`'s best term from its root to
+    /// a leaf, each time stepping into the child with the cheapest best term,
+    /// the first of equally cheap ones.
+    pub fn spine(&mut self, id: Id) -> PyResult<Vec<String>> {
+        let mut labels = vec![];
+        let mut id = self.egraph.find(id);
+        loop {
+            let node = self.node(id)?.clone();
+            labels.push(self.label(&node)?);
+            let cheapest = node.children.iter().copied().reduce(|best, child| {
+                match self.cost(child) < self.cost(best) {
+                    true => child,
+                    false => best,
+                }
+            });
+            match cheapest {
+                Some(child) => id = self.egraph.find(child),
+                None => return Ok(labels),
+            }
+        }
+    }
+
+    /// How many times each label (see `node_label`) occurs among the enodes of
+    /// `id`'s best term, counting a shared subterm at every occurrence.
+    pub fn label_counts(&mut self, id: Id) -> PyResult<HashMap<String, usize>> {
+        let id = self.egraph.find(id);
+        self.label_counts_in(id, &mut HashMap::new())
+    }
+
+    fn label_counts_in(
+        &mut self,
+        id: Id,
+        memo: &mut HashMap<Id, HashMap<String, usize>>,
+    ) -> PyResult<HashMap<String, usize>> {
+        if let Some(counts) = memo.get(&id) {
+            return Ok(counts.clone());
+        }
+        let node = self.node(id)?.clone();
+        let label = self.label(&node)?;
+        let mut counts: HashMap<String, usize> = HashMap::from([(label, 1)]);
+        for &child in &node.children {
+            let child = self.egraph.find(child);
+            for (label, count) in self.label_counts_in(child, memo)? {
+                let total = counts.entry(label).or_insert(0);
+                *total = total.saturating_add(count);
+            }
+        }
+        memo.insert(id, counts.clone());
+        Ok(counts)
+    }
+
+    fn choose(&mut self, id: Id) -> PyResult<()> {
+        match self.try_choose(id)? {
+            true => Ok(()),
+            false => Err(PyValueError::new_err(
+                "Can't extract, e-class has no acyclic term",
+            )),
+        }
+    }
+
+    /// Choose the best term of `id`, or return false if every cheapest enode
+    /// leads back to a class being chosen further up. With zero-cost enodes,
+    /// a cycle can be as cheap as an acyclic term; its enodes are skipped in
+    /// favor of the acyclic ones.
+    fn try_choose(&mut self, id: Id) -> PyResult<bool> {
+        if self.chosen.contains_key(&id) {
+            return Ok(true);
+        }
+        let best = *self.costs.get(&id).ok_or_else(|| {
+            PyValueError::new_err(if self.forbidden.is_empty() {
+                "Can't extract, e-class has no finite cost"
+            } else {
+                "Can't extract, e-class has no term without forbidden operators"
+            })
+        })?;
+
+        self.in_progress.insert(id);
+        let candidates: Vec<PythonNode> = self.egraph[id]
+            .iter()
+            .filter(|node| self.node_cost(node) == Some(best))
+            .filter(|node| {
+                node.children
+                    .iter()
+                    .all(|&child| !self.in_progress.contains(&self.egraph.find(child)))
+            })
+            .cloned()
+            .collect();
+        let mut acyclic = Vec::with_capacity(candidates.len());
+        'candidates: for node in candidates {
+            for &child in &node.children {
+                if !self.try_choose(self.egraph.find(child))? {
+                    continue 'candidates;
+                }
+            }
+            acyclic.push(node);
+        }
+        self.in_progress.remove(&id);
+        if acyclic.is_empty() {
+            return Ok(false);
+        }
+        let node = self.pick(acyclic)?;
+
+        let obj = self.build(&node)?;
+        let depth = 1 + node
+            .children
+            .iter()
+            .map(|&child| self.chosen[&self.egraph.find(child)].depth)
+            .max()
+            .unwrap_or(0);
+        let size = node.children.iter().fold(1, |size: usize, &child| {
+            size.saturating_add(self.chosen[&self.egraph.find(child)].size)
+        });
+        self.chosen.insert(
+            id,
+            Choice {
+                node,
+                obj,
+                depth,
+                size,
+                sexpr: None,
+            },
+        );
+        Ok(true)
+    }
+
+    /// Build `node` from the already chosen terms of its children.
+    fn build(&self, node: &PythonNode) -> PyResult<PyObject> {
+        let obj = node.to_object(self.py, |child| {
+            self.chosen[&self.egraph.find(child)].obj.clone_ref(self.py)
+        });
+        decode_leaf(node, obj, self.decode)
+    }
+
+    fn pick(&mut self, candidates: Vec<PythonNode>) -> PyResult<PythonNode> {
+        if candidates.len() == 1 {
+            return Ok(candidates.into_iter().next().unwrap());
+        }
+
+        let mut keyed: Vec<(usize, String, PythonNode)> = candidates
+            .into_iter()
+            .map(|node| {
+                let added = self
+                    .order
+                    .as_ref()
+                    .map_or(0, |order| order.get(&node).copied().unwrap_or(usize::MAX));
+                (added, self.node_sexpr(&node), node)
+            })
+            .collect();
+        keyed.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        let mut keyed = keyed.into_iter().map(|(_, _, node)| node);
+        let mut best = keyed.next().unwrap();
+
+        if let Some(tie_break) = self.tie_break {
+            let mut best_obj = self.build(&best)?;
+            for node in keyed {
+                let obj = self.build(&node)?;
+                let order: f64 = tie_break
+                    .call1((obj.clone_ref(self.py), best_obj.clone_ref(self.py)))?
+                    .extract()?;
+                if order < 0.0 {
+                    best = node;
+                    best_obj = obj;
+                }
+            }
+        }
+        Ok(best)
+    }
+
+    fn node_sexpr(&mut self, node: &PythonNode) -> String {
+        let label = node_label(self.py, node, |child| {
+            self.chosen[&self.egraph.find(child)].obj.clone_ref(self.py)
+        });
+        if node.children.is_empty() {
+            return label;
+        }
+        let children: Vec<String> = node
+            .children
+            .iter()
+            .map(|&child| self.sexpr(self.egraph.find(child)))
+            .collect();
+        format!("({} {})", label, children.join(" "))
+    }
+
+    fn sexpr(&mut self, id: Id) -> String {
+        if let Some(sexpr) = &self.chosen[&id].sexpr {
+            return sexpr.clone();
+        }
+        let node = self.chosen[&id].node.clone();
+        let sexpr = self.node_sexpr(&node);
+        self.chosen.get_mut(&id).unwrap().sexpr = Some(sexpr.clone());
+        sexpr
+    }
+}
+
+/// Terms for all of `roots` under a joint cost that counts each shared
+/// e-class once: the sum of the op costs of the distinct classes the terms
+/// use, i.e. the cost of the DAG they form together.
+///
+/// Finding the cheapest such DAG is NP-hard, so this is a local search. It
+/// starts from each class's [`BestTerms`] choice and keeps switching a single
+/// class the DAG uses to another of its enodes while that lowers the joint
+/// cost, re-walking the DAG for every candidate (and skipping switches that
+/// would make it cyclic). That's much slower than extracting each root on its
+/// own, and the result can be worse than the true optimum, but never worse
+/// than the independent choices.
+pub fn extract_forest(
+    py: Python,
+    egraph: &EGraph<PythonNode, PythonAnalysis>,
+    model: &CostModel,
+    roots: &[Id],
+) -> PyResult<Vec<PyObject>> {
+    if let CostModel::Depth = model {
+        return Err(PyValueError::new_err(
+            "extract_forest needs a cost that is summed over the term",
+        ));
+    }
+    let roots: Vec<Id> = roots.iter().map(|&id| egraph.find(id)).collect();
+    let mut best = BestTerms::new(py, egraph, model, None)?;
+    let mut choice: HashMap<Id, PythonNode> = HashMap::new();
+    for class in egraph.classes() {
+        if best.cost(class.id).is_some() {
+            choice.insert(class.id, best.node(class.id)?.clone());
+        }
+    }
+
+    let mut cache = OpCostCache::default();
+    let mut op_costs: HashMap<PythonNode, f64> = HashMap::new();
+    for class in egraph.classes() {
+        for node in class.iter() {
+            let cost = cache.op_cost(py, model, node, class.data.as_ref())?;
+            op_costs.insert(node.clone(), cost);
+        }
+    }
+
+    let mut current = forest_cost(egraph, &roots, &choice, &op_costs)
+        .ok_or_else(|| PyValueError::new_err("Can't extract, e-class has no finite cost"))?;
+    let mut improved = true;
+    while improved {
+        improved = false;
+        let mut used: Vec<Id> = forest_order(egraph, &roots, &choice).unwrap_or_default();
+        used.sort();
+        for id in used {
+            for node in egraph[id].iter() {
+                if *node == choice[&id] {
+                    continue;
+                }
+                let previous = choice.insert(id, node.clone()).unwrap();
+                match forest_cost(egraph, &roots, &choice, &op_costs) {
+                    Some(cost) if cost < current => {
+                        current = cost;
+                        improved = true;
+                    }
+                    _ => {
+                        choice.insert(id, previous);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut built: HashMap<Id, PyObject> = HashMap::new();
+    let mut order = forest_order(egraph, &roots, &choice).unwrap_or_default();
+    order.reverse();
+    for id in order {
+        let obj = choice[&id].to_object(py, |child| built[&egraph.find(child)].clone_ref(py));
+        built.insert(id, obj);
+    }
+    Ok(roots.iter().map(|id| built[id].clone_ref(py)).collect())
+}
+
+/// The total op cost of the DAG chosen from `roots` by `choice`, or `None`
+/// if it's cyclic or reaches a class without a choice.
+fn forest_cost(
+    egraph: &EGraph<PythonNode, PythonAnalysis>,
+    roots: &[Id],
+    choice: &HashMap<Id, PythonNode>,
+    op_costs: &HashMap<PythonNode, f64>,
+) -> Option<f64> {
+    let order = forest_order(egraph, roots, choice)?;
+    Some(order.iter().map(|id| op_costs[&choice[id]]).sum())
+}
+
+/// A topological order (users first) of the classes the DAG chosen from
+/// `roots` by `choice` uses, or `None` if it's cyclic or incomplete.
+fn forest_order(
+    egraph: &EGraph<PythonNode, PythonAnalysis>,
+    roots: &[Id],
+    choice: &HashMap<Id, PythonNode>,
+) -> Option<Vec<Id>> {
+    // `false` while a class is on the walk's stack, `true` once it's done.
+    let mut done: HashMap<Id, bool> = HashMap::new();
+    let mut post = Vec::new();
+    for &root in roots {
+        if done.contains_key(&root) {
+            continue;
+        }
+        let mut stack: Vec<(Id, usize)> = vec![(root, 0)];
+        done.insert(root, false);
+        while let Some((id, next)) = stack.pop() {
+            let node = choice.get(&id)?;
+            match node.children.get(next) {
+                Some(&child) => {
+                    stack.push((id, next + 1));
+                    let child = egraph.find(child);
+                    match done.get(&child) {
+                        Some(false) => return None,
+                        Some(true) => {}
+                        None => {
+                            done.insert(child, false);
+                            stack.push((child, 0));
+                        }
+                    }
+                }
+                None => {
+                    done.insert(id, true);
+                    post.push(id);
+                }
+            }
+        }
+    }
+    post.reverse();
+    Some(post)
+}
+
+/// The cheapest term of the e-class `id` under `model` with at most
+/// `max_size` enodes as a tree, or `None` if all of its terms are bigger.
+///
+/// For each size up to `max_size` this finds every class's cheapest term of
+/// exactly that size, from its enodes and the cheapest terms of their
+/// children at every split of the remaining size, so it takes time cubic in
+/// `max_size`. Terms of equal cost are told apart by size, smallest first,
+/// then by the order their enodes were added. `max_size` is capped at the
+/// size of the unrestricted best term, since more room can't beat that.
+pub fn extract_within_size(
+    py: Python,
+    egraph: &EGraph<PythonNode, PythonAnalysis>,
+    model: &CostModel,
+    id: Id,
+    max_size: usize,
+) -> PyResult<Option<PyObject>> {
+    let id = egraph.find(id);
+    let max_size = max_size.min(BestTerms::new(py, egraph, model, None)?.tree_size(id)?);
+    let order = insertion_order(egraph);
+    let mut cache = OpCostCache::default();
+    let mut classes: Vec<(Id, Vec<(f64, PythonNode)>)> = vec![];
+    for class in egraph.classes() {
+        let mut nodes = class.nodes.clone();
+        nodes.sort_by_key(|node| order.get(node).copied().unwrap_or(usize::MAX));
+        let nodes = nodes
+            .into_iter()
+            .map(|node| Ok((cache.op_cost(py, model, &node, class.data.as_ref())?, node)))
+            .collect::<PyResult<Vec<_>>>()?;
+        classes.push((class.id, nodes));
+    }
+    let depth = matches!(model, CostModel::Depth);
+
+    let mut best: SizedTerms = classes
+        .iter()
+        .map(|(class, _)| (*class, vec![None; max_size + 1]))
+        .collect();
+    for size in 1..=max_size {
+        for (class, nodes) in &classes {
+            let mut found: Option<SizedTerm> = None;
+            for (index, (op_cost, node)) in nodes.iter().enumerate() {
+                let children: Vec<Id> = node.children.iter().map(|&c| egraph.find(c)).collect();
+                let (cost, sizes) = match split_size(&best, &children, size - 1, depth) {
+                    Some(split) => split,
+                    None => continue,
+                };
+                let cost = op_cost + cost;
+                if found.as_ref().is_none_or(|found| cost < found.0) {
+                    found = Some((cost, index, sizes));
+                }
+            }
+            best.get_mut(class).unwrap()[size] = found;
+        }
+    }
+
+    let mut fitting = None;
+    for (size, term) in best[&id].iter().enumerate() {
+        if let Some((cost, _, _)) = term {
+            if fitting.is_none_or(|(best_cost, _)| *cost < best_cost) {
+                fitting = Some((*cost, size));
+            }
+        }
+    }
+    let size = match fitting {
+        Some((_, size)) => size,
+        None => return Ok(None),
+    };
+    let nodes: HashMap<Id, &[(f64, PythonNode)]> = classes
+        .iter()
+        .map(|(class, nodes)| (*class, nodes.as_slice()))
+        .collect();
+    let mut built = HashMap::new();
+    Ok(Some(build_sized(
+        py, egraph, &best, &nodes, id, size, &mut built,
+    )))
+}
+
+/// The cheapest term of a class with exactly some number of enodes: its cost,
+/// the index of its root enode among the class's sorted enodes, and the
+/// sizes of the root's children's terms.
+type SizedTerm = (f64, usize, Vec<usize>);
+
+/// [`SizedTerm`]s for each class, by size.
+type SizedTerms = HashMap<Id, Vec<Option<SizedTerm>>>;
+
+/// The cheapest way to give `children` terms of `total` enodes together,
+/// as their costs combined (summed, or with `depth` their maximum) and the
+/// size of each child's term.
+fn split_size(
+    best: &SizedTerms,
+    children: &[Id],
+    total: usize,
+    depth: bool,
+) -> Option<(f64, Vec<usize>)> {
+    // splits[t]: the cheapest split of `t` enodes over the children so far
+    let mut splits: Vec<Option<(f64, Vec<usize>)>> = vec![None; total + 1];
+    splits[0] = Some((0.0, vec![]));
+    for child in children {
+        let terms = &best[child];
+        let mut next: Vec<Option<(f64, Vec<usize>)>> = vec![None; total + 1];
+        for (used, split) in splits.iter().enumerate() {
+            let (cost, sizes) = match split {
+                Some(split) => split,
+                None => continue,
+            };
+            for size in 1..=total - used {
+                if let Some((child_cost, _, _)) = &terms[size] {
+                    let cost = if depth {
+                        cost.max(*child_cost)
+                    } else {
+                        cost + child_cost
+                    };
+                    let slot = &mut next[used + size];
+                    if slot.as_ref().is_none_or(|(best, _)| cost < *best) {
+                        let mut sizes = sizes.clone();
+                        sizes.push(size);
+                        *slot = Some((cost, sizes));
+                    }
+                }
+            }
+        }
+        splits = next;
+    }
+    splits.pop().flatten()
+}
+
+/// The term of `size` enodes chosen for the class `id` in `best`.
+fn build_sized(
+    py: Python,
+    egraph: &EGraph<PythonNode, PythonAnalysis>,
+    best: &SizedTerms,
+    nodes: &HashMap<Id, &[(f64, PythonNode)]>,
+    id: Id,
+    size: usize,
+    built: &mut HashMap<(Id, usize), PyObject>,
+) -> PyObject {
+    if let Some(obj) = built.get(&(id, size)) {
+        return obj.clone_ref(py);
+    }
+    let (_, index, sizes) = best[&id][size].as_ref().unwrap();
+    let node = &nodes[&id][*index].1;
+    let children: Vec<PyObject> = node
+        .children
+        .iter()
+        .zip(sizes)
+        .map(|(&child, &size)| {
+            build_sized(py, egraph, best, nodes, egraph.find(child), size, built)
+        })
+        .collect();
+    let mut children = children.into_iter();
+    let obj = node.to_object(py, |_| children.next().unwrap());
+    built.insert((id, size), obj.clone_ref(py));
+    obj
+}
+
+/// The enodes whose operator is in `ops`: either a node class, or a name
+/// compared with the node's `node_label` (so `Operation`-shaped nodes can be
+/// forbidden by op name, e.g. `"scf.while"`).
+pub fn forbidden_nodes(
+    py: Python,
+    egraph: &EGraph<PythonNode, PythonAnalysis>,
+    ops: &[&PyAny],
+) -> PyResult<HashSet<PythonNode>> {
+    let mut classes = Vec::new();
+    let mut names = HashSet::new();
+    for op in ops {
+        match op.extract::<String>() {
+            Ok(name) => {
+                names.insert(name);
+            }
+            Err(_) => classes.push(op.downcast::<PyType>()?),
+        }
+    }
+    let extractor = Extractor::new(egraph, AstSize);
+    let reconstruct_child = |child_id: Id| {
+        let (_cost, expr) = extractor.find_best(child_id);
+        reconstruct(py, &expr)
+    };
+    Ok(egraph
+        .classes()
+        .flat_map(|class| class.iter())
+        .filter(|node| {
+            classes.iter().any(|ty| node.class.is(*ty))
+                || (!names.is_empty() && names.contains(&node_label(py, node, reconstruct_child)))
+        })
+        .cloned()
+        .collect())
+}
+
+struct Term {
+    node: PythonNode,
+    children: Vec<Rc<Term>>,
+    size: usize,
+    /// The term's cost, when the enumerator has a cost model.
+    cost: f64,
+}
+
+impl Term {
+    fn to_object(&self, py: Python) -> PyObject {
+        let mut children = self.children.iter().map(|child| child.to_object(py));
+        self.node.to_object(py, |_| children.next().unwrap())
+    }
+}
+
+/// Enumerates the distinct terms of e-classes up to a size bound.
+///
+/// Every class keeps only its `max_count` smallest terms per size budget;
+/// that loses nothing for the root, since a term built from a child term
+/// outside its class's smallest `max_count` can't be among the root's. With
+/// a cost model, terms of the same size are ordered by cost, which keeps
+/// that true since a cheaper child term makes a cheaper parent term.
+pub struct TermEnumerator<'a> {
+    egraph: &'a EGraph<PythonNode, PythonAnalysis>,
+    max_count: usize,
+    /// `model.op_cost` of each enode, if there is a cost model.
+    op_costs: HashMap<PythonNode, f64>,
+    depth: bool,
+    memo: HashMap<(Id, usize), Rc<Vec<Rc<Term>>>>,
+}
+
+impl<'a> TermEnumerator<'a> {
+    pub fn new(egraph: &'a EGraph<PythonNode, PythonAnalysis>, max_count: usize) -> Self {
+        Self {
+            egraph,
+            max_count,
+            op_costs: HashMap::new(),
+            depth: false,
+            memo: HashMap::new(),
+        }
+    }
+
+    /// Like `new`, but ordering terms of the same size by their cost.
+    pub fn with_costs(
+        py: Python,
+        egraph: &'a EGraph<PythonNode, PythonAnalysis>,
+        max_count: usize,
+        model: &CostModel,
+    ) -> PyResult<Self> {
+        let mut cache = OpCostCache::default();
+        let mut op_costs = HashMap::new();
+        for class in egraph.classes() {
+            for node in class.iter() {
+                let cost = cache.op_cost(py, model, node, class.data.as_ref())?;
+                op_costs.insert(node.clone(), cost);
+            }
+        }
+        Ok(Self {
+            op_costs,
+            depth: matches!(model, CostModel::Depth),
+            ..Self::new(egraph, max_count)
+        })
+    }
+
+    /// Up to `max_count` terms of `id` with at most `max_size` nodes,
+    /// smallest first.
+    pub fn objects(&mut self, py: Python, id: Id, max_size: usize) -> Vec<PyObject> {
+        let id = self.egraph.find(id);
+        self.terms(id, max_size)
+            .iter()
+            .map(|term| term.to_object(py))
+            .collect()
+    }
+
+    /// The `max_count` smallest terms of `id` of any size, or all of them if
+    /// it has fewer.
+    ///
+    /// The size budget is doubled until enough terms fit. If the e-class has
+    /// only finitely many terms, the budget stops at the size of its largest
+    /// one; otherwise some cycle builds terms of every size, so enough terms
+    /// are found eventually.
+    pub fn smallest_objects(&mut self, py: Python, id: Id) -> Vec<PyObject> {
+        let id = self.egraph.find(id);
+        let largest = self.largest_term(id);
+        let mut budget = 1;
+        loop {
+            let terms = self.terms(id, budget);
+            if terms.len() >= self.max_count || largest.is_some_and(|size| budget >= size) {
+                return terms.iter().map(|term| term.to_object(py)).collect();
+            }
+            budget = budget.saturating_mul(2);
+            if let Some(size) = largest {
+                budget = budget.min(size);
+            }
+        }
+    }
+
+    /// The size of the largest term of `id`, or `None` if it has terms of
+    /// every size: a cycle of enodes that all have terms is reachable from it.
+    fn largest_term(&self, id: Id) -> Option<usize> {
+        // a class has a term if one of its enodes has all children with one
+        let mut has_term: HashSet<Id> = HashSet::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for class in self.egraph.classes() {
+                if !has_term.contains(&class.id)
+                    && class.iter().any(|node| {
+                        node.children
+                            .iter()
+                            .all(|&child| has_term.contains(&self.egraph.find(child)))
+                    })
+                {
+                    has_term.insert(class.id);
+                    changed = true;
+                }
+            }
+        }
+        let mut largest = HashMap::new();
+        self.largest_from(id, &has_term, &mut HashSet::new(), &mut largest)
+    }
+
+    fn largest_from(
+        &self,
+        id: Id,
+        has_term: &HashSet<Id>,
+        path: &mut HashSet<Id>,
+        largest: &mut HashMap<Id, usize>,
+    ) -> Option<usize> {
+        if let Some(&size) = largest.get(&id) {
+            return Some(size);
+        }
+        if !has_term.contains(&id) {
+            return Some(0);
+        }
+        if !path.insert(id) {
+            return None;
+        }
+        let mut size = 0;
+        for node in self.egraph[id].iter() {
+            let children: Vec<Id> = node
+                .children
+                .iter()
+                .map(|&child| self.egraph.find(child))
+                .collect();
+            if !children.iter().all(|child| has_term.contains(child)) {
+                continue;
+            }
+            let mut node_size: usize = 1;
+            for child in children {
+                let child_size = self.largest_from(child, has_term, path, largest)?;
+                node_size = node_size.saturating_add(child_size);
+            }
+            size = size.max(node_size);
+        }
+        path.remove(&id);
+        largest.insert(id, size);
+        Some(size)
+    }
+
+    fn terms(&mut self, id: Id, budget: usize) -> Rc<Vec<Rc<Term>>> {
+        if let Some(terms) = self.memo.get(&(id, budget)) {
+            return terms.clone();
+        }
+        let mut terms: Vec<Rc<Term>> = Vec::new();
+        if budget > 0 {
+            let mut nodes = Vec::new();
+            for node in self.egraph[id].nodes.clone() {
+                let children: Vec<Rc<Vec<Rc<Term>>>> = node
+                    .children
+                    .iter()
+                    .map(|&child| self.terms(self.egraph.find(child), budget - 1))
+                    .collect();
+                nodes.push((node, children));
+            }
+            // one size at a time, so that once `max_count` terms exist no
+            // larger ones are built
+            for size in 1..=budget {
+                for (node, children) in &nodes {
+                    let mut picked = Vec::with_capacity(children.len());
+                    self.combine(node, children, size - 1, &mut picked, &mut terms);
+                }
+                if terms.len() >= self.max_count {
+                    break;
+                }
+            }
+        }
+        terms.sort_by(|a, b| a.size.cmp(&b.size).then(a.cost.total_cmp(&b.cost)));
+        terms.truncate(self.max_count);
+        let terms = Rc::new(terms);
+        self.memo.insert((id, budget), terms.clone());
+        terms
+    }
+
+    /// Push every choice of child terms whose sizes add up to exactly `size`.
+    fn combine(
+        &self,
+        node: &PythonNode,
+        children: &[Rc<Vec<Rc<Term>>>],
+        size: usize,
+        picked: &mut Vec<Rc<Term>>,
+        out: &mut Vec<Rc<Term>>,
+    ) {
+        // without costs, terms of one size come in no particular order, so
+        // any of them do once there are enough
+        if self.op_costs.is_empty() && out.len() >= self.max_count {
+            return;
+        }
+        let depth = picked.len();
+        if depth == children.len() {
+            if size > 0 {
+                return;
+            }
+            let op_cost = self.op_costs.get(node).copied().unwrap_or(0.0);
+            let child_costs = picked.iter().map(|term| term.cost);
+            let cost = if self.depth {
+                child_costs.fold(op_cost, |depth, cost| depth.max(op_cost + cost))
+            } else {
+                op_cost + child_costs.sum::<f64>()
+            };
+            out.push(Rc::new(Term {
+                node: node.clone(),
+                children: picked.clone(),
+                size: 1 + picked.iter().map(|term| term.size).sum::<usize>(),
+                cost,
+            }));
+            return;
+        }
+        // the largest size the remaining children could still add up to
+        let rest: usize = children[depth + 1..]
+            .iter()
+            .map(|terms| terms.last().map_or(0, |term| term.size))
+            .sum();
+        for term in children[depth].iter() {
+            if term.size > size {
+                break;
+            }
+            if term.size + rest < size {
+                continue;
+            }
+            picked.push(term.clone());
+            self.combine(node, children, size - term.size, picked, out);
+            picked.pop();
+        }
+    }
+}
+
+/// No sample may visit more e-classes than this, so one unlucky descent
+/// can't take as long as exact extraction.
+const SAMPLE_STEPS: usize = 100_000;
+
+/// Approximate extraction by randomized descent, for e-graphs too big to
+/// compute every class's best cost.
+///
+/// Each sample walks down from the root choosing one enode per e-class,
+/// skipping enodes that would close a cycle and backtracking out of dead
+/// ends. The first sample is greedy (cheapest enode first, then fewest
+/// children); the others choose uniformly at random. A sample is abandoned
+/// once it costs as much as the best one so far.
+pub struct SampledTerms<'a> {
+    py: Python<'a>,
+    egraph: &'a EGraph<PythonNode, PythonAnalysis>,
+    model: &'a CostModel,
+    op_costs: OpCostCache,
+    rng: u64,
+    steps: usize,
+}
+
+impl<'a> SampledTerms<'a> {
+    pub fn new(
+        py: Python<'a>,
+        egraph: &'a EGraph<PythonNode, PythonAnalysis>,
+        model: &'a CostModel,
+        seed: u64,
+    ) -> Self {
+        // splitmix64 of the seed, which is never zero for xorshift
+        let mut rng = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        rng = (rng ^ (rng >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        rng = (rng ^ (rng >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Self {
+            py,
+            egraph,
+            model,
+            op_costs: OpCostCache::default(),
+            rng: (rng ^ (rng >> 31)) | 1,
+            steps: 0,
+        }
+    }
+
+    /// The cheapest of `samples` sampled terms of `id`.
+    pub fn object(&mut self, id: Id, samples: usize) -> PyResult<PyObject> {
+        let mut best: Option<(f64, Vec<PythonNode>)> = None;
+        for i in 0..samples.max(1) {
+            let bound = best.as_ref().map_or(f64::INFINITY, |(cost, _)| *cost);
+            let mut nodes = vec![];
+            self.steps = 0;
+            let cost = self.sample(id, i == 0, &mut nodes, &mut HashSet::new(), &mut 0.0, bound)?;
+            if let Some(cost) = cost.filter(|&cost| cost < bound) {
+                best = Some((cost, nodes));
+            }
+        }
+        match best {
+            Some((_, nodes)) => Ok(reconstruct(self.py, &RecExpr::from(nodes))),
+            None => Err(PyValueError::new_err(
+                "Can't extract, no sample found an acyclic term",
+            )),
+        }
+    }
+
+    fn op_cost(&mut self, node: &PythonNode, id: Id) -> PyResult<f64> {
+        let data = self.egraph[id].data.as_ref();
+        self.op_costs.op_cost(self.py, self.model, node, data)
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Sample a term of `id` onto `nodes`, returning its cost, or `None` if
+    /// every choice dead-ended or cost at least `bound`. `spent` is the cost
+    /// of the whole sample so far, and `path` the classes being sampled.
+    fn sample(
+        &mut self,
+        id: Id,
+        greedy: bool,
+        nodes: &mut Vec<PythonNode>,
+        path: &mut HashSet<Id>,
+        spent: &mut f64,
+        bound: f64,
+    ) -> PyResult<Option<f64>> {
+        let egraph = self.egraph;
+        let id = egraph.find(id);
+        if self.steps >= SAMPLE_STEPS {
+            return Ok(None);
+        }
+        self.steps += 1;
+        path.insert(id);
+
+        let mut candidates = vec![];
+        for node in egraph[id].iter() {
+            if node
+                .children
+                .iter()
+                .all(|&c| !path.contains(&egraph.find(c)))
+            {
+                candidates.push((self.op_cost(node, id)?, node));
+            }
+        }
+        if greedy {
+            candidates.sort_by(|(a, x), (b, y)| {
+                a.partial_cmp(b)
+                    .unwrap()
+                    .then(x.children.len().cmp(&y.children.len()))
+            });
+        } else {
+            for i in (1..candidates.len()).rev() {
+                let j = (self.next_random() % (i as u64 + 1)) as usize;
+                candidates.swap(i, j);
+            }
+        }
+
+        let depth = matches!(self.model, CostModel::Depth);
+        let mut result = None;
+        'candidates: for (op_cost, node) in candidates {
+            let (mark, spent_mark) = (nodes.len(), *spent);
+            *spent += op_cost;
+            // a depth can't be bounded before the whole term is known
+            if !depth && *spent >= bound {
+                *spent = spent_mark;
+                continue;
+            }
+            let mut cost = op_cost;
+            let mut children = Vec::with_capacity(node.children.len());
+            for &child in &node.children {
+                match self.sample(child, greedy, nodes, path, spent, bound)? {
+                    Some(child_cost) if depth => cost = cost.max(op_cost + child_cost),
+                    Some(child_cost) => cost += child_cost,
+                    None => {
+                        nodes.truncate(mark);
+                        *spent = spent_mark;
+                        continue 'candidates;
+                    }
+                }
+                children.push(Id::from(nodes.len() - 1));
+            }
+            let mut children = children.into_iter();
+            nodes.push(node.clone().map_children(|_| children.next().unwrap()));
+            result = Some(cost);
+            break;
+        }
+        path.remove(&id);
+        Ok(result)
+    }
+}
@@ -1,19 +1,23 @@
 use egg::{Analysis, Applier, AstSize, DidMerge, EGraph, Extractor, PatternAst, Subst, Symbol};
-use egg::{Id, Language, Var};
+use egg::{ENodeOrVar, Id, Language, Pattern, RecExpr, SearchMatches, Searcher, Var};
 use once_cell::sync::Lazy;
+use pyo3::exceptions::{PyKeyError, PyValueError};
 use pyo3::AsPyPointer;
 use pyo3::{
     basic::CompareOp,
     prelude::*,
-    types::{PyDict, PyTuple, PyType},
+    types::{PyBool, PyDict, PyFloat, PyList, PyLong, PyTuple, PyType},
 };
+use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use std::{fmt::Display, hash::Hash};
 
-use crate::core::{reconstruct, PyPattern};
-use crate::util::{build_node, py_eq};
+use crate::core::{class_label, node_label, reconstruct, PyId, PyPattern, PyVar};
+use crate::util::{add_instantiation, build_node, py_eq, BuildLimits};
 
+#[derive(Clone)]
 struct PythonHashable {
     obj: PyObject,
     hash: isize,
@@ -26,6 +30,14 @@ impl PythonHashable {
             hash: obj.hash().expect("Failed to hash"),
         }
     }
+
+    /// Like `new`, but raising if `obj` can't be hashed.
+    pub fn try_new(obj: &PyAny) -> PyResult<Self> {
+        Ok(Self {
+            obj: obj.into(),
+            hash: obj.hash()?,
+        })
+    }
 }
 
 impl Hash for PythonHashable {
@@ -49,6 +61,12 @@ impl PartialEq for PythonHashable {
 
 impl Eq for PythonHashable {}
 
+/// An enode whose operator tag is a Python object.
+///
+/// Tags are already shared rather than copied: operators store their type
+/// object, which is unique per class, and leaves are interned through a
+/// value-keyed table so equal leaf objects share one `PyObject`. Equality,
+/// hashing and ordering therefore only compare tag pointers.
 #[derive(Debug, Clone)]
 pub struct PythonNode {
     pub class: PyObject,
@@ -140,17 +158,16 @@ impl Hash for PythonNode {
 
 impl Ord for PythonNode {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).expect("comparison failed")
+        self.class
+            .as_ptr()
+            .cmp(&other.class.as_ptr())
+            .then_with(|| self.children.cmp(&other.children))
     }
 }
 
 impl PartialOrd for PythonNode {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match self.class.as_ptr().partial_cmp(&other.class.as_ptr()) {
-            Some(Ordering::Equal) => {}
-            ord => return ord,
-        }
-        self.children.partial_cmp(&other.children)
+        Some(self.cmp(other))
     }
 }
 
@@ -165,17 +182,337 @@ impl Display for PythonNode {
     }
 }
 
-#[derive(Default)]
+/// An analysis computed in Rust with no Python callback, chosen by name
+/// with `EGraph(analysis=...)`. Its data is still stored as Python ints.
+#[derive(Debug, Clone, Copy)]
+pub enum BuiltinAnalysis {
+    /// `"depth"`: the depth of the class's shallowest term, a leaf being 1.
+    Depth,
+    /// `"const_int"`: the int the class is known to equal, from an int leaf
+    /// in it (bools don't count). Nothing is folded.
+    ConstInt,
+    /// `"fold"`: the number the class is known to equal, from an int or
+    /// float leaf or by folding a two-child `+`, `-`, `*` or `/` enode (by
+    /// node class name, which may also be `Add`, `Sub`, `Mul` or `Div`)
+    /// whose children are known. `/` is true division, as in Python. The
+    /// class is then unioned with that number's leaf. Ints are folded only
+    /// within 64 bits and floats only to finite results; anything else is
+    /// left unfolded.
+    Fold,
+}
+
+/// A number the `"fold"` analysis can compute with.
+#[derive(Debug, Clone, Copy)]
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn from_py(obj: &PyAny) -> Option<Self> {
+        if obj.is_instance_of::<PyBool>().unwrap_or(false) {
+            None
+        } else if obj.is_instance_of::<PyLong>().unwrap_or(false) {
+            obj.extract().ok().map(Number::Int)
+        } else if obj.is_instance_of::<PyFloat>().unwrap_or(false) {
+            obj.extract().ok().map(Number::Float)
+        } else {
+            None
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(int) => int as f64,
+            Number::Float(float) => float,
+        }
+    }
+
+    /// `self op other`, for an operator label of [`BuiltinAnalysis::Fold`].
+    fn apply(self, op: &str, other: Self) -> Option<Self> {
+        let ints = match (self, other) {
+            (Number::Int(a), Number::Int(b)) => Some((a, b)),
+            _ => None,
+        };
+        let (a, b) = (self.as_f64(), other.as_f64());
+        let result = match (op, ints) {
+            ("+" | "Add", Some((a, b))) => Number::Int(a.checked_add(b)?),
+            ("-" | "Sub", Some((a, b))) => Number::Int(a.checked_sub(b)?),
+            ("*" | "Mul", Some((a, b))) => Number::Int(a.checked_mul(b)?),
+            ("+" | "Add", None) => Number::Float(a + b),
+            ("-" | "Sub", None) => Number::Float(a - b),
+            ("*" | "Mul", None) => Number::Float(a * b),
+            ("/" | "Div", _) if b != 0.0 => Number::Float(a / b),
+            _ => return None,
+        };
+        match result {
+            Number::Float(float) if !float.is_finite() => None,
+            result => Some(result),
+        }
+    }
+
+    fn into_py(self, py: Python) -> PyObject {
+        match self {
+            Number::Int(int) => int.into_py(py),
+            Number::Float(float) => float.into_py(py),
+        }
+    }
+}
+
+impl BuiltinAnalysis {
+    pub fn from_name(name: &str) -> PyResult<Self> {
+        match name {
+            "depth" => Ok(BuiltinAnalysis::Depth),
+            "const_int" => Ok(BuiltinAnalysis::ConstInt),
+            "fold" => Ok(BuiltinAnalysis::Fold),
+            _ => Err(PyValueError::new_err(format!(
+                "Unknown analysis {:?}, expected \"depth\", \"const_int\" or \"fold\"",
+                name
+            ))),
+        }
+    }
+
+    fn make(
+        self,
+        py: Python,
+        egraph: &EGraph<PythonNode, PythonAnalysis>,
+        enode: &PythonNode,
+    ) -> Option<PyObject> {
+        match self {
+            BuiltinAnalysis::Depth => {
+                let depth = enode
+                    .children
+                    .iter()
+                    .filter_map(|&child| egraph[child].data.as_ref())
+                    .filter_map(|depth| depth.extract::<usize>(py).ok())
+                    .max()
+                    .unwrap_or(0);
+                Some((depth + 1).into_py(py))
+            }
+            BuiltinAnalysis::ConstInt => {
+                let leaf = enode.class.as_ref(py);
+                let int = enode.is_leaf() && leaf.is_instance_of::<PyLong>().unwrap_or(false);
+                (int && !leaf.is_instance_of::<PyBool>().unwrap_or(false))
+                    .then(|| enode.class.clone_ref(py))
+            }
+            BuiltinAnalysis::Fold if enode.is_leaf() => {
+                Number::from_py(enode.class.as_ref(py)).map(|_| enode.class.clone_ref(py))
+            }
+            BuiltinAnalysis::Fold => {
+                let op = enode
+                    .class
+                    .as_ref(py)
+                    .downcast::<PyType>()
+                    .ok()?
+                    .name()
+                    .ok()?;
+                let [a, b] = enode.children.as_slice() else {
+                    return None;
+                };
+                let operand = |id: Id| Number::from_py(egraph[id].data.as_ref()?.as_ref(py));
+                Some(operand(*a)?.apply(op, operand(*b)?)?.into_py(py))
+            }
+        }
+    }
+
+    fn merge(
+        self,
+        py: Python,
+        a: &mut Option<PyObject>,
+        b: Option<PyObject>,
+    ) -> PyResult<DidMerge> {
+        Ok(match (self, a.as_ref(), b) {
+            (_, None, None) => DidMerge(false, false),
+            (_, None, Some(b)) => {
+                *a = Some(b);
+                DidMerge(true, false)
+            }
+            (_, Some(_), None) => DidMerge(false, true),
+            (BuiltinAnalysis::Depth, Some(depth), Some(other)) => {
+                let depth: usize = depth.extract(py).unwrap();
+                let other_depth: usize = other.extract(py).unwrap();
+                if other_depth < depth {
+                    *a = Some(other);
+                }
+                DidMerge(other_depth < depth, depth < other_depth)
+            }
+            (BuiltinAnalysis::ConstInt, Some(int), Some(other)) => {
+                if !py_eq(int.as_ref(py), other.as_ref(py)) {
+                    return Err(unequal_data(int.as_ref(py), other.as_ref(py)));
+                }
+                DidMerge(false, false)
+            }
+            // float rounding can fold one class to slightly different numbers
+            (BuiltinAnalysis::Fold, Some(_), Some(_)) => DidMerge(false, false),
+        })
+    }
+}
+
+/// The error for merging e-classes whose analysis data are unequal.
+fn unequal_data(a: &PyAny, b: &PyAny) -> PyErr {
+    PyValueError::new_err(format!(
+        "Failed to merge e-classes with unequal data {} and {}",
+        a, b
+    ))
+}
+
+#[derive(Default, Clone)]
 pub struct PythonAnalysis {
     pub eval: Option<PyObject>,
+    /// Used instead of `eval` when set.
+    pub builtin: Option<BuiltinAnalysis>,
+    /// Maps leaf objects to a hashable key; leaves with equal keys are treated
+    /// as the same node, so equal keys must mean interchangeable objects.
+    pub key: Option<PyObject>,
+    /// Maps each leaf object to the object stored for it, before `key`.
+    pub canonicalize: Option<PyObject>,
+    /// The first leaf object seen for each key.
+    leaf_reps: HashMap<PythonHashable, PyObject>,
+    /// Told of the unions made through [`observed_union`] during a `run()`.
+    pub on_union: Option<UnionObserver>,
+    /// Enodes added from Python terms, whether or not they were new.
+    pub insertions: usize,
+    /// When each enode was added, counting from 0, with its children as they
+    /// were then. `make` only gets shared access, hence the `RefCell`.
+    added: RefCell<HashMap<PythonNode, usize>>,
+    /// The e-class ids egg has handed out, which are numbered from 0: it
+    /// makes one for each new enode, calling `make` for it.
+    pub id_count: Cell<usize>,
+    /// The rule a [`TracedApplier`] is applying right now.
+    pub applying: Option<Symbol>,
+    /// The rule whose application added each enode that a rule added, with
+    /// its children as they were then.
+    introduced: RefCell<HashMap<PythonNode, Symbol>>,
+    /// The rewrite applications `run(apply_limit=...)` has left, counted down
+    /// by [`TracedApplier`].
+    pub apply_budget: Option<usize>,
+    /// The limits on the terms added from Python objects.
+    pub build_limits: BuildLimits,
+    /// The first exception raised by Python code run where egg can't take an
+    /// error back, such as adding a class's analysis data as a term in
+    /// `modify`; raised by [`raise_pending`](Self::raise_pending).
+    error: PendingError,
+}
+
+/// An exception waiting to be raised. A copy of the e-graph starts without
+/// one.
+#[derive(Default)]
+struct PendingError(RefCell<Option<PyErr>>);
+
+impl Clone for PendingError {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl PythonAnalysis {
+    pub fn new(eval: Option<PyObject>, key: Option<PyObject>) -> Self {
+        Self {
+            eval,
+            builtin: None,
+            key,
+            canonicalize: None,
+            leaf_reps: HashMap::new(),
+            on_union: None,
+            insertions: 0,
+            added: RefCell::default(),
+            id_count: Cell::new(0),
+            applying: None,
+            introduced: RefCell::default(),
+            apply_budget: None,
+            build_limits: BuildLimits::default(),
+            error: PendingError::default(),
+        }
+    }
+
+    /// Keep `err` to raise once egg returns, unless an earlier one is already
+    /// waiting.
+    pub fn fail(&self, err: PyErr) {
+        self.error.0.borrow_mut().get_or_insert(err);
+    }
+
+    /// Whether an exception is waiting to be raised.
+    pub fn failed(&self) -> bool {
+        self.error.0.borrow().is_some()
+    }
+
+    /// Raise the exception kept by `fail`, if any.
+    pub fn raise_pending(&mut self) -> PyResult<()> {
+        match self.error.0.get_mut().take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Make room for `capacity` more enodes in the per-enode bookkeeping.
+    pub fn reserve(&mut self, capacity: usize) {
+        self.added.get_mut().reserve(capacity);
+    }
+
+    /// A new analysis with this one's `eval` or built-in analysis, `key`,
+    /// `canonicalize` and leaf representatives, for an e-graph built from this
+    /// one's enodes.
+    pub fn fresh(&self) -> Self {
+        Self {
+            builtin: self.builtin,
+            canonicalize: self.canonicalize.clone(),
+            leaf_reps: self.leaf_reps.clone(),
+            build_limits: self.build_limits,
+            ..Self::new(self.eval.clone(), self.key.clone())
+        }
+    }
+
+    /// The object to store for a leaf: `obj` itself or what `canonicalize`
+    /// maps it to, and then with a `key` the first leaf that was added under
+    /// the same key. Raises what `canonicalize` or `key` raises.
+    pub fn canonical_leaf(&mut self, obj: &PyAny) -> PyResult<PyObject> {
+        let py = obj.py();
+        let canonical;
+        let obj = match &self.canonicalize {
+            Some(canonicalize) => {
+                canonical = canonicalize.call1(py, (obj,))?;
+                canonical.as_ref(py)
+            }
+            None => obj,
+        };
+        let key = match &self.key {
+            Some(key) => key,
+            None => return Ok(obj.into()),
+        };
+        let key = key.call1(py, (obj,))?;
+        let key = PythonHashable::try_new(key.as_ref(py))?;
+        Ok(self
+            .leaf_reps
+            .entry(key)
+            .or_insert_with(|| obj.into())
+            .clone_ref(py))
+    }
 }
 
 impl Analysis<PythonNode> for PythonAnalysis {
     type Data = Option<PyObject>;
 
     fn make(egraph: &EGraph<PythonNode, Self>, enode: &PythonNode) -> Self::Data {
-        let eval = egraph.analysis.eval.as_ref()?;
+        // egg also remakes the data of enodes it already has when rebuilding;
+        // a new enode isn't in its hash-cons yet
+        if egraph.lookup(enode.clone()).is_none() {
+            let ids = &egraph.analysis.id_count;
+            ids.set(ids.get() + 1);
+            let mut added = egraph.analysis.added.borrow_mut();
+            let order = added.len();
+            added.insert(enode.clone(), order);
+            drop(added);
+            if let Some(rule) = egraph.analysis.applying {
+                let mut introduced = egraph.analysis.introduced.borrow_mut();
+                introduced.insert(enode.clone(), rule);
+            }
+        }
+
         let py = unsafe { Python::assume_gil_acquired() };
+        if let Some(builtin) = egraph.analysis.builtin {
+            return builtin.make(py, egraph, enode);
+        }
+        let eval = egraph.analysis.eval.as_ref()?;
 
         // collect the children if they are not `None` in python
         let mut children = Vec::with_capacity(enode.len());
@@ -200,8 +537,21 @@ impl Analysis<PythonNode> for PythonAnalysis {
 
     fn merge(&mut self, a: &mut Self::Data, b: Self::Data) -> DidMerge {
         let py = unsafe { Python::assume_gil_acquired() };
-        let aa = a.as_ref().map(|obj| obj.as_ref(py)).filter(|r| r.is_none());
-        let bb = b.as_ref().map(|obj| obj.as_ref(py)).filter(|r| r.is_none());
+        if let Some(builtin) = self.builtin {
+            return builtin.merge(py, a, b).unwrap_or_else(|err| {
+                self.fail(err);
+                DidMerge(false, false)
+            });
+        }
+        // `None` means no value yet, so a value on the other side wins
+        let aa = a
+            .as_ref()
+            .map(|obj| obj.as_ref(py))
+            .filter(|r| !r.is_none());
+        let bb = b
+            .as_ref()
+            .map(|obj| obj.as_ref(py))
+            .filter(|r| !r.is_none());
         match (aa, bb) {
             (None, None) => DidMerge(false, false),
             (None, Some(bb)) => {
@@ -211,7 +561,7 @@ impl Analysis<PythonNode> for PythonAnalysis {
             (Some(_), None) => egg::DidMerge(false, true),
             (Some(aa), Some(bb)) => {
                 if !py_eq(aa, bb) {
-                    panic!("Failed to merge")
+                    self.fail(unequal_data(aa, bb));
                 }
                 DidMerge(false, false)
             }
@@ -219,22 +569,243 @@ impl Analysis<PythonNode> for PythonAnalysis {
     }
 
     fn modify(egraph: &mut EGraph<PythonNode, Self>, id: Id) {
+        // a built-in analysis's data describes the class, it isn't a term,
+        // except for a folded number
+        match egraph.analysis.builtin {
+            None | Some(BuiltinAnalysis::Fold) => {}
+            Some(_) => return,
+        }
         let obj = egraph[id].data.clone();
         if let Some(obj) = obj {
             let py = unsafe { Python::assume_gil_acquired() };
-            let id2 = build_node(egraph, obj.as_ref(py));
-            egraph.union(id, id2);
+            match build_node(egraph, obj.as_ref(py)) {
+                Ok(id2) => {
+                    observed_union(egraph, id, id2);
+                }
+                Err(err) => egraph.analysis.fail(err),
+            }
         }
     }
 }
 
-pub struct PythonApplier {
-    pub eval: PyObject,
-    /// List of vars in the pattern which this is used with
-    pub vars: Vec<Var>,
+/// When each enode of `egraph` was first added, with canonical children.
+/// Enodes that became equal through unions get the earliest of their times.
+pub fn insertion_order(egraph: &EGraph<PythonNode, PythonAnalysis>) -> HashMap<PythonNode, usize> {
+    let mut order: HashMap<PythonNode, usize> = HashMap::new();
+    for (node, &added) in egraph.analysis.added.borrow().iter() {
+        let node = node.clone().map_children(|child| egraph.find(child));
+        order
+            .entry(node)
+            .and_modify(|first| *first = added.min(*first))
+            .or_insert(added);
+    }
+    order
 }
 
-impl Applier<PythonNode, PythonAnalysis> for PythonApplier {
+/// The rules whose application added an enode now in e-class `id`. The
+/// e-graph must be rebuilt, so that the class's nodes are canonical.
+pub fn provenance(egraph: &EGraph<PythonNode, PythonAnalysis>, id: Id) -> HashSet<Symbol> {
+    let nodes: HashSet<&PythonNode> = egraph[id].nodes.iter().collect();
+    egraph
+        .analysis
+        .introduced
+        .borrow()
+        .iter()
+        .filter(|(node, _)| {
+            let node = (*node).clone().map_children(|child| egraph.find(child));
+            nodes.contains(&node)
+        })
+        .map(|(_, &rule)| rule)
+        .collect()
+}
+
+/// A Python `on_union(a, b)` callback, and a `guard(a, b)` that may refuse
+/// a union.
+///
+/// Both are called with the canonical ids of the two e-classes whenever a
+/// rewrite or the hook unions distinct classes, before they are merged;
+/// merges found while rebuilding aren't seen. The guard also gets a
+/// `(labels, data)` pair for each class, the sorted labels of its enodes (as
+/// in `class_node_structure`) and its analysis data. If the callback raises
+/// or the guard returns false, the run makes no more unions, stops before its
+/// next iteration and raises the exception, or a `ValueError` naming the two
+/// classes.
+#[derive(Clone)]
+pub struct UnionObserver {
+    callback: Option<PyObject>,
+    guard: Option<PyObject>,
+    /// The first exception a callback raised, or the guard's refusal; neither
+    /// is called after that, and with a guard no more unions are made.
+    pub error: Arc<Mutex<Option<PyErr>>>,
+}
+
+impl UnionObserver {
+    pub fn new(callback: Option<PyObject>, guard: Option<PyObject>) -> Self {
+        Self {
+            callback,
+            guard,
+            error: Arc::default(),
+        }
+    }
+}
+
+/// What a union guard is told of the e-class `id`: the sorted labels (see
+/// `node_label`) of its enodes and its analysis data.
+fn guarded_class(py: Python, egraph: &EGraph<PythonNode, PythonAnalysis>, id: Id) -> PyObject {
+    // only an `Operation`-shaped enode's label needs a child's term
+    let extractor = std::cell::OnceCell::new();
+    let mut labels: Vec<String> = egraph[id]
+        .iter()
+        .map(|node| {
+            node_label(py, node, |child| {
+                let extractor = extractor.get_or_init(|| Extractor::new(egraph, AstSize));
+                reconstruct(py, &extractor.find_best(child).1)
+            })
+        })
+        .collect();
+    labels.sort();
+    (labels, egraph[id].data.clone()).into_py(py)
+}
+
+/// `egraph.union(a, b)`, first calling the analysis's `guard` and `on_union`
+/// callbacks if `a` and `b` are in different e-classes. A union the guard
+/// refuses, or any union after it, isn't made.
+pub fn observed_union(egraph: &mut EGraph<PythonNode, PythonAnalysis>, a: Id, b: Id) -> bool {
+    if let Some(observer) = egraph.analysis.on_union.clone() {
+        let (a, b) = (egraph.find(a), egraph.find(b));
+        let mut error = observer.error.lock().unwrap();
+        if observer.guard.is_some() && error.is_some() {
+            return false;
+        }
+        if a != b && error.is_none() {
+            let py = unsafe { Python::assume_gil_acquired() };
+            if let Some(guard) = &observer.guard {
+                let allowed = guard
+                    .call1(
+                        py,
+                        (guarded_class(py, egraph, a), guarded_class(py, egraph, b)),
+                    )
+                    .and_then(|allowed| allowed.as_ref(py).is_true());
+                match allowed {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        *error = Some(PyValueError::new_err(format!(
+                            "guard refused to merge e-classes {} and {}",
+                            a, b
+                        )));
+                        return false;
+                    }
+                    Err(err) => {
+                        *error = Some(err);
+                        return false;
+                    }
+                }
+            }
+            if let Some(callback) = &observer.callback {
+                if let Err(err) = callback.call1(py, (PyId(a), PyId(b))) {
+                    *error = Some(err);
+                }
+            }
+        }
+    }
+    egraph.union(a, b)
+}
+
+/// The applier of a rewrite with a pattern right-hand side: egg's, but
+/// adding the pattern's leaves as `EGraph.add` would (see
+/// [`PythonAnalysis::canonical_leaf`]) and making its unions with
+/// [`observed_union`].
+pub struct PatternApplier {
+    pub pattern: Pattern<PythonNode>,
+}
+
+impl Applier<PythonNode, PythonAnalysis> for PatternApplier {
+    fn apply_one(
+        &self,
+        egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+        eclass: Id,
+        subst: &Subst,
+        _searcher_ast: Option<&PatternAst<PythonNode>>,
+        _rule_name: Symbol,
+    ) -> Vec<Id> {
+        if egraph.analysis.failed() {
+            return vec![];
+        }
+        let id = match add_instantiation(egraph, &self.pattern.ast, subst) {
+            Ok(id) => id,
+            Err(err) => {
+                egraph.analysis.fail(err);
+                return vec![];
+            }
+        };
+        if observed_union(egraph, eclass, id) {
+            vec![eclass]
+        } else {
+            vec![]
+        }
+    }
+
+    fn get_pattern_ast(&self) -> Option<&PatternAst<PythonNode>> {
+        Some(&self.pattern.ast)
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        self.pattern.vars()
+    }
+}
+
+/// An applier recording its rule as the one [`PythonAnalysis::make`] sees
+/// adding enodes, for `class_provenance`. With an
+/// [`apply_budget`](PythonAnalysis::apply_budget), it applies the matches one
+/// at a time, skipping the rest once the budget is spent.
+pub struct TracedApplier {
+    pub applier: Arc<dyn Applier<PythonNode, PythonAnalysis> + Send + Sync>,
+}
+
+impl TracedApplier {
+    fn apply_within_budget(
+        &self,
+        egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+        matches: &[SearchMatches<PythonNode>],
+        rule_name: Symbol,
+    ) -> Vec<Id> {
+        let mut changed = vec![];
+        for found in matches {
+            for subst in &found.substs {
+                let budget = egraph.analysis.apply_budget.unwrap_or(0);
+                if budget == 0 {
+                    return changed;
+                }
+                let one = SearchMatches {
+                    eclass: found.eclass,
+                    substs: vec![subst.clone()],
+                    ast: found.ast.clone(),
+                };
+                let ids = self.applier.apply_matches(egraph, &[one], rule_name);
+                egraph.analysis.apply_budget = Some(budget.saturating_sub(ids.len()));
+                changed.extend(ids);
+            }
+        }
+        changed
+    }
+}
+
+impl Applier<PythonNode, PythonAnalysis> for TracedApplier {
+    fn apply_matches(
+        &self,
+        egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+        matches: &[SearchMatches<PythonNode>],
+        rule_name: Symbol,
+    ) -> Vec<Id> {
+        egraph.analysis.applying = Some(rule_name);
+        let changed = match egraph.analysis.apply_budget {
+            None => self.applier.apply_matches(egraph, matches, rule_name),
+            Some(_) => self.apply_within_budget(egraph, matches, rule_name),
+        };
+        egraph.analysis.applying = None;
+        changed
+    }
+
     fn apply_one(
         &self,
         egraph: &mut EGraph<PythonNode, PythonAnalysis>,
@@ -243,27 +814,518 @@ impl Applier<PythonNode, PythonAnalysis> for PythonApplier {
         searcher_ast: Option<&PatternAst<PythonNode>>,
         rule_name: Symbol,
     ) -> Vec<Id> {
-        let py = unsafe { Python::assume_gil_acquired() };
+        egraph.analysis.applying = Some(rule_name);
+        let changed = self
+            .applier
+            .apply_one(egraph, eclass, subst, searcher_ast, rule_name);
+        egraph.analysis.applying = None;
+        changed
+    }
+
+    fn get_pattern_ast(&self) -> Option<&PatternAst<PythonNode>> {
+        self.applier.get_pattern_ast()
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        self.applier.vars()
+    }
+}
+
+/// Applies a Python callback, called with the matched variables as keyword
+/// arguments. It returns either a term (which may use the pattern's vars) to
+/// union with the matched e-class, or a list of unions to make for the match:
+/// each item is an `(a, b)` pair to union with each other, or a term to union
+/// with the matched e-class. Either side of a pair may be an `Id`. Since every
+/// plain 2-tuple item is read as a pair, a plain 2-tuple term meant for the
+/// matched e-class has to be returned on its own, not in a list.
+///
+/// A returned object that isn't a term, such as a folded constant, becomes a
+/// leaf enode, interned under the e-graph's `key` just as `EGraph.add` would.
+///
+/// With `context`, the callback is instead called as `callback(ctx)` with a
+/// [`PyApplierContext`] for the match, and may also return `None`.
+pub struct PythonApplier {
+    pub eval: PyObject,
+    /// List of vars in the pattern which this is used with
+    pub vars: Vec<Var>,
+    /// Whether to call `eval` with a [`PyApplierContext`].
+    pub context: bool,
+}
+
+impl PythonApplier {
+    /// The e-class of one side of a union: an `Id`, or a term instantiated
+    /// with `subst`. Any other object is added as a leaf, like `EGraph.add`
+    /// would add it.
+    fn side(
+        egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+        subst: &Subst,
+        obj: &PyAny,
+    ) -> PyResult<Id> {
+        if let Ok(PyId(id)) = obj.extract() {
+            return Ok(egraph.find(id));
+        }
+        let pattern = PyPattern::build(obj, egraph.analysis.build_limits)?;
+        add_instantiation(egraph, &pattern.pattern.ast, subst)
+    }
+
+    /// The matched terms, by var name, as keyword arguments for `eval`.
+    fn kwargs<'py>(
+        &self,
+        py: Python<'py>,
+        egraph: &EGraph<PythonNode, PythonAnalysis>,
+        subst: &Subst,
+    ) -> &'py PyDict {
         let kwargs = PyDict::new(py);
 
         // reconstruct Python objects for each matched variable using the
         // best expression frEm the e-graph. before, it just pulled the
         // analysis data (None by default for dynamic rules)
-        {
-            let extractor = Extractor::new(&*egraph, AstSize);
-            for var in &self.vars {
-                let id = subst[*var];
-                let (_cost, expr) = extractor.find_best(id);
-                let obj = reconstruct(py, &expr);
-                let key = &var.to_string()[1..];
-                kwargs.set_item(key, obj).unwrap();
+        let extractor = Extractor::new(egraph, AstSize);
+        for var in &self.vars {
+            let id = subst[*var];
+            let (_cost, expr) = extractor.find_best(id);
+            let obj = reconstruct(py, &expr);
+            let key = &var.to_string()[1..];
+            kwargs.set_item(key, obj).unwrap();
+        }
+        kwargs
+    }
+}
+
+impl PythonApplier {
+    fn apply(
+        &self,
+        egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+        eclass: Id,
+        subst: &Subst,
+    ) -> PyResult<Vec<Id>> {
+        let py = unsafe { Python::assume_gil_acquired() };
+        let mut changed = vec![];
+        let result = if self.context {
+            let ctx = PyApplierContext {
+                egraph,
+                eclass,
+                subst: subst.clone(),
+                changed: vec![],
+            };
+            let ctx = PyCell::new(py, ctx)?;
+            let result = self.eval.as_ref(py).call1((ctx,));
+            // the context must not reach the e-graph once we use it again
+            let mut ctx = ctx.borrow_mut();
+            ctx.egraph = std::ptr::null_mut();
+            changed.append(&mut ctx.changed);
+            let result = result?;
+            if result.is_none() {
+                return Ok(changed);
+            }
+            result
+        } else {
+            let kwargs = self.kwargs(py, egraph, subst);
+            self.eval.as_ref(py).call((), Some(kwargs))?
+        };
+        if let Ok(unions) = result.downcast::<PyList>() {
+            for item in unions {
+                // only a plain tuple is a pair: NamedTuple terms are tuples too
+                let pair = item
+                    .downcast::<PyTuple>()
+                    .ok()
+                    .filter(|pair| pair.get_type().is(py.get_type::<PyTuple>()) && pair.len() == 2);
+                let (a, b) = match pair {
+                    Some(pair) => (
+                        Self::side(egraph, subst, pair.get_item(0)?)?,
+                        Self::side(egraph, subst, pair.get_item(1)?)?,
+                    ),
+                    None => (eclass, Self::side(egraph, subst, item)?),
+                };
+                if observed_union(egraph, a, b) {
+                    changed.push(egraph.find(a));
+                }
+            }
+            return Ok(changed);
+        }
+        let id = Self::side(egraph, subst, result)?;
+        if observed_union(egraph, eclass, id) {
+            changed.push(eclass);
+        }
+        Ok(changed)
+    }
+}
+
+impl Applier<PythonNode, PythonAnalysis> for PythonApplier {
+    /// Applies the callback to one match. An exception it leads to is kept
+    /// by the analysis (see [`PythonAnalysis::fail`]), stopping the run, and
+    /// no more matches are applied.
+    fn apply_one(
+        &self,
+        egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+        eclass: Id,
+        subst: &Subst,
+        _searcher_ast: Option<&PatternAst<PythonNode>>,
+        _rule_name: Symbol,
+    ) -> Vec<Id> {
+        if egraph.analysis.failed() {
+            return vec![];
+        }
+        match self.apply(egraph, eclass, subst) {
+            Ok(changed) => changed,
+            Err(err) => {
+                egraph.analysis.fail(err);
+                vec![]
+            }
+        }
+    }
+}
+
+/// What a context applier (`Rewrite(lhs, rhs, context=True)`) is called with:
+/// one match of its rewrite, and access to the e-graph while it runs.
+///
+/// `ctx[var]` (a `Var` or its name) is the `Id` matched by a var and `eclass`
+/// the matched e-class; `data` gives their analysis data. `add`, `lookup` and
+/// `union` take `Id`s or terms, which may use the pattern's vars. The context
+/// can't be used after the callback returns.
+#[pyclass(unsendable, name = "ApplierContext")]
+pub struct PyApplierContext {
+    /// Null once the callback has returned.
+    egraph: *mut EGraph<PythonNode, PythonAnalysis>,
+    eclass: Id,
+    subst: Subst,
+    /// Classes changed by `union`.
+    changed: Vec<Id>,
+}
+
+impl PyApplierContext {
+    fn egraph(&mut self) -> PyResult<&mut EGraph<PythonNode, PythonAnalysis>> {
+        // SAFETY: the pointer is only set while the applier's callback runs,
+        // and the e-graph isn't otherwise used until it returns
+        unsafe { self.egraph.as_mut() }
+            .ok_or_else(|| PyValueError::new_err("ApplierContext used after its callback returned"))
+    }
+
+    fn side(&mut self, obj: &PyAny) -> PyResult<Id> {
+        let subst = self.subst.clone();
+        let egraph = self.egraph()?;
+        PythonApplier::side(egraph, &subst, obj)
+    }
+}
+
+#[pymethods]
+impl PyApplierContext {
+    #[getter]
+    fn eclass(&self) -> PyId {
+        PyId(self.eclass)
+    }
+
+    fn __getitem__(&self, var: &PyAny) -> PyResult<PyId> {
+        let var = match var.extract::<PyVar>() {
+            Ok(var) => var,
+            Err(_) => PyVar::from_str(var.extract()?),
+        };
+        match self.subst.get(var.0) {
+            Some(&id) => Ok(PyId(id)),
+            None => Err(PyKeyError::new_err(var.0.to_string())),
+        }
+    }
+
+    /// The analysis data (see `EGraph(eval=...)`) of an `Id`'s e-class, or of
+    /// the e-class a var matched; `None` if the class has none.
+    fn data(&mut self, py: Python, target: &PyAny) -> PyResult<Option<PyObject>> {
+        let id = match target.extract::<PyId>() {
+            Ok(PyId(id)) => id,
+            Err(_) => self.__getitem__(target)?.0,
+        };
+        let egraph = self.egraph()?;
+        Ok(egraph[id].data.as_ref().map(|data| data.clone_ref(py)))
+    }
+
+    /// Add a term, instantiating its vars, and return its `Id`.
+    fn add(&mut self, expr: &PyAny) -> PyResult<PyId> {
+        self.side(expr).map(PyId)
+    }
+
+    /// The `Id` of a term if it is already in the e-graph, else `None`.
+    fn lookup(&mut self, expr: &PyAny) -> PyResult<Option<PyId>> {
+        let subst = self.subst.clone();
+        let egraph = self.egraph()?;
+        if let Ok(PyId(id)) = expr.extract() {
+            return Ok(Some(PyId(egraph.find(id))));
+        }
+        let pattern = expr.extract::<PyPattern>()?;
+        let mut ids: Vec<Id> = vec![];
+        for node in pattern.pattern.ast.as_ref() {
+            let id = match node {
+                ENodeOrVar::Var(var) => subst.get(*var).copied(),
+                ENodeOrVar::ENode(node) => {
+                    egraph.lookup(node.clone().map_children(|child| ids[usize::from(child)]))
+                }
+            };
+            match id {
+                Some(id) => ids.push(id),
+                None => return Ok(None),
+            }
+        }
+        Ok(ids.last().map(|&id| PyId(egraph.find(id))))
+    }
+
+    /// Union two terms or `Id`s, returning whether anything changed.
+    fn union(&mut self, a: &PyAny, b: &PyAny) -> PyResult<bool> {
+        let a = self.side(a)?;
+        let b = self.side(b)?;
+        let egraph = self.egraph()?;
+        let changed = observed_union(egraph, a, b);
+        if changed {
+            let id = egraph.find(a);
+            self.changed.push(id);
+        }
+        Ok(changed)
+    }
+}
+
+/// A searcher that can be shared between rewrites.
+pub type SharedSearcher = Arc<dyn Searcher<PythonNode, PythonAnalysis> + Send + Sync>;
+
+/// A searcher whose matches must bind each var in `kinds` to an e-class with
+/// an enode of its kind: an operator of that class, or a leaf that is an
+/// instance of it, or for a kind that isn't a type, an enode whose operator
+/// object it returns true for. Each `(copy, var)` in `same` must also be
+/// bound to e-classes with terms of the same shape.
+///
+/// A predicate that raises rejects the match; the exception is kept by the
+/// analysis (see [`PythonAnalysis::fail`]) and nothing more is matched.
+pub struct KindedSearcher {
+    pub searcher: SharedSearcher,
+    pub kinds: Vec<(Var, PyObject)>,
+    pub same: Vec<(Var, Var)>,
+}
+
+impl KindedSearcher {
+    /// `searcher`, wrapped if there are any `kinds` or `same` to enforce.
+    pub fn wrap(
+        searcher: SharedSearcher,
+        kinds: &[(Var, PyObject)],
+        same: &[(Var, Var)],
+    ) -> SharedSearcher {
+        if kinds.is_empty() && same.is_empty() {
+            return searcher;
+        }
+        Arc::new(Self {
+            searcher,
+            kinds: kinds.to_vec(),
+            same: same.to_vec(),
+        })
+    }
+
+    /// Whether e-classes `a` and `b` have terms of the same shape: they are
+    /// the same class, or have enodes with the same label and arity whose
+    /// children are pairwise alike. A pair already being compared further up
+    /// is taken as unlike, so cycles don't recurse forever; `assumed` is set
+    /// when that happened. `memo` keeps every answer that didn't rest on such
+    /// an assumption, and every positive one, which an assumption can't make.
+    fn shaped_alike(
+        py: Python,
+        egraph: &EGraph<PythonNode, PythonAnalysis>,
+        a: Id,
+        b: Id,
+        visiting: &mut HashSet<(Id, Id)>,
+        memo: &mut HashMap<(Id, Id), bool>,
+        assumed: &mut bool,
+    ) -> bool {
+        let (a, b) = (egraph.find(a), egraph.find(b));
+        if a == b {
+            return true;
+        }
+        if let Some(&shaped) = memo.get(&(a, b)) {
+            return shaped;
+        }
+        if !visiting.insert((a, b)) {
+            *assumed = true;
+            return false;
+        }
+        let outer = std::mem::replace(assumed, false);
+        let shaped = egraph[a].iter().any(|x| {
+            let label = class_label(py, x);
+            egraph[b].iter().any(|y| {
+                x.children().len() == y.children().len()
+                    && class_label(py, y) == label
+                    && x.children().iter().zip(y.children()).all(|(&cx, &cy)| {
+                        Self::shaped_alike(py, egraph, cx, cy, visiting, memo, assumed)
+                    })
+            })
+        });
+        visiting.remove(&(a, b));
+        if shaped || !*assumed {
+            memo.insert((a, b), shaped);
+        }
+        *assumed |= outer;
+        shaped
+    }
+
+    fn has_kind(
+        py: Python,
+        egraph: &EGraph<PythonNode, PythonAnalysis>,
+        id: Id,
+        kind: &PyAny,
+    ) -> bool {
+        let kind = match kind.downcast::<PyType>() {
+            Ok(kind) => kind,
+            Err(_) => {
+                return egraph[id].iter().any(|node| {
+                    let matched = kind
+                        .call1((node.class.clone_ref(py),))
+                        .and_then(|matched| matched.is_true());
+                    matched.unwrap_or_else(|err| {
+                        egraph.analysis.fail(err);
+                        false
+                    })
+                })
+            }
+        };
+        egraph[id].iter().any(|node| {
+            node.class.is(kind)
+                || (node.is_leaf() && node.class.as_ref(py).is_instance(kind).unwrap_or(false))
+        })
+    }
+}
+
+impl Searcher<PythonNode, PythonAnalysis> for KindedSearcher {
+    fn search_eclass_with_limit(
+        &self,
+        egraph: &EGraph<PythonNode, PythonAnalysis>,
+        eclass: Id,
+        limit: usize,
+    ) -> Option<SearchMatches<'_, PythonNode>> {
+        let py = unsafe { Python::assume_gil_acquired() };
+        if egraph.analysis.failed() {
+            return None;
+        }
+        // the limit applies to the matches left after filtering
+        let mut matches = self
+            .searcher
+            .search_eclass_with_limit(egraph, eclass, usize::MAX)?;
+        let mut memo = HashMap::new();
+        matches.substs.retain(|subst| {
+            !egraph.analysis.failed()
+                && self
+                    .kinds
+                    .iter()
+                    .all(|(var, kind)| Self::has_kind(py, egraph, subst[*var], kind.as_ref(py)))
+                && self.same.iter().all(|(copy, var)| {
+                    let mut visiting = HashSet::new();
+                    Self::shaped_alike(
+                        py,
+                        egraph,
+                        subst[*copy],
+                        subst[*var],
+                        &mut visiting,
+                        &mut memo,
+                        &mut false,
+                    )
+                })
+        });
+        matches.substs.truncate(limit);
+        (!matches.substs.is_empty()).then_some(matches)
+    }
+
+    fn get_pattern_ast(&self) -> Option<&PatternAst<PythonNode>> {
+        self.searcher.get_pattern_ast()
+    }
+
+    fn vars(&self) -> Vec<Var> {
+        self.searcher.vars()
+    }
+}
+
+/// A searcher for a pattern whose binary operators in `commutative_ops` also
+/// match with their arguments swapped.
+///
+/// Each commutative node is swapped independently, so a pattern with `k`
+/// nested commutative nodes is searched as up to `2^k` variants; substitutions
+/// found by more than one variant are reported once.
+pub struct CommutativeSearcher {
+    pattern: Pattern<PythonNode>,
+    variants: Vec<Pattern<PythonNode>>,
+}
+
+impl CommutativeSearcher {
+    /// `None` if no node of `ast` is a binary operator in `commutative_ops`.
+    pub fn new(ast: &PatternAst<PythonNode>, commutative_ops: &[PyObject]) -> Option<Self> {
+        let nodes = ast.as_ref();
+        let swappable: Vec<usize> = nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, node)| match node {
+                ENodeOrVar::ENode(n)
+                    if n.children.len() == 2
+                        && commutative_ops.iter().any(|op| op.is(&n.class)) =>
+                {
+                    Some(i)
+                }
+                _ => None,
+            })
+            .collect();
+        if swappable.is_empty() {
+            return None;
+        }
+
+        let mut seen = HashSet::new();
+        let mut variants = Vec::new();
+        for mask in 0..1usize << swappable.len() {
+            let mut variant = nodes.to_vec();
+            for (bit, &i) in swappable.iter().enumerate() {
+                if mask & (1 << bit) != 0 {
+                    if let ENodeOrVar::ENode(n) = &mut variant[i] {
+                        n.children.swap(0, 1);
+                    }
+                }
+            }
+            if seen.insert(variant.clone()) {
+                variants.push(Pattern::new(RecExpr::from(variant)));
             }
         }
+        Some(Self {
+            pattern: Pattern::new(ast.clone()),
+            variants,
+        })
+    }
+}
+
+impl Searcher<PythonNode, PythonAnalysis> for CommutativeSearcher {
+    fn search_eclass_with_limit(
+        &self,
+        egraph: &EGraph<PythonNode, PythonAnalysis>,
+        eclass: Id,
+        limit: usize,
+    ) -> Option<SearchMatches<'_, PythonNode>> {
+        let mut seen = HashSet::new();
+        let mut substs = Vec::new();
+        for variant in &self.variants {
+            if substs.len() >= limit {
+                break;
+            }
+            if let Some(matches) =
+                variant.search_eclass_with_limit(egraph, eclass, limit - substs.len())
+            {
+                for subst in matches.substs {
+                    if seen.insert(subst.clone()) {
+                        substs.push(subst);
+                    }
+                }
+            }
+        }
+        if substs.is_empty() {
+            return None;
+        }
+        Some(SearchMatches {
+            eclass,
+            substs,
+            ast: Some(std::borrow::Cow::Borrowed(&self.pattern.ast)),
+        })
+    }
+
+    fn get_pattern_ast(&self) -> Option<&PatternAst<PythonNode>> {
+        Some(&self.pattern.ast)
+    }
 
-        let result = self.eval.as_ref(py).call((), Some(kwargs)).unwrap();
-        let pattern = result.extract::<PyPattern>().unwrap();
-        pattern
-            .pattern
-            .apply_one(egraph, eclass, subst, searcher_ast, rule_name)
+    fn vars(&self) -> Vec<Var> {
+        self.pattern.vars()
     }
 }
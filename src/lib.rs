@@ -1,9 +1,15 @@
+// pyo3 0.16's `#[pymethods]` expands to impls nested in a const item.
+#![allow(non_local_definitions)]
+
 mod core;
+mod extract;
 mod lang;
+mod run;
 mod util;
 
 use crate::core::*;
 use crate::lang::*;
+use crate::run::{PyRunResult, PyStopReason};
 
 use pyo3::{prelude::*, types::PyString};
 
@@ -18,11 +24,27 @@ fn _internal(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyVar>()?;
     m.add_class::<PyPattern>()?;
     m.add_class::<PyRewrite>()?;
+    m.add_class::<PyRuleSet>()?;
+    m.add_class::<PySearchIter>()?;
+    m.add_class::<PyRunSteps>()?;
+    m.add_class::<PyApplierContext>()?;
+    m.add_class::<PyStopReason>()?;
+    m.add_class::<PyRunResult>()?;
 
-    #[pyfn(m)]
-    fn vars(vars: &PyString) -> Vec<PyVar> {
+    /// Vars for each of the space-separated names, all of the given `kind`.
+    #[pyfn(m, kind = "None")]
+    fn vars(vars: &PyString, kind: Option<&PyAny>) -> PyResult<Vec<PyVar>> {
         let s = vars.to_string_lossy();
-        s.split_whitespace().map(|s| PyVar::from_str(s)).collect()
+        s.split_whitespace()
+            .map(|name| PyVar::from_str(name).with_kind(kind))
+            .collect()
+    }
+
+    /// `rewrites` with each rule that is the same as an earlier one up to
+    /// renaming its vars removed; the first occurrence keeps its name.
+    #[pyfn(m)]
+    fn dedup_rewrites(py: Python, rewrites: &PyAny) -> PyResult<Vec<Py<PyRewrite>>> {
+        PyRewrite::dedup(py, rewrites)
     }
     Ok(())
 }
@@ -0,0 +1,546 @@
+use egg::{BackoffScheduler, EGraph, Iteration, Runner, StopReason, Symbol};
+use pyo3::exceptions::{PyOSError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList, PyTuple};
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::lang::{observed_union, PythonAnalysis, PythonNode};
+use crate::util::build_node;
+
+pub type PythonRunner = Runner<PythonNode, PythonAnalysis>;
+
+/// Why a `run()` stopped.
+///
+/// `kind` is one of `"saturated"`, `"iteration_limit"`, `"node_limit"`,
+/// `"time_limit"` or `"other"`; `value` carries the limit data egg reports
+/// (iterations, nodes or elapsed seconds) and `message` the text of an
+/// `"other"` reason. `partial_iteration` is true when a time limit stopped the
+/// run while it was applying an iteration's rewrites, so some of that
+/// iteration's matches may not have been applied. A node limit never does:
+/// it is only checked between iterations.
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct PyStopReason {
+    #[pyo3(get)]
+    pub kind: String,
+    #[pyo3(get)]
+    pub value: Option<f64>,
+    #[pyo3(get)]
+    pub message: Option<String>,
+    #[pyo3(get)]
+    pub partial_iteration: bool,
+}
+
+#[pymethods]
+impl PyStopReason {
+    fn __repr__(&self) -> String {
+        match (&self.value, &self.message) {
+            (Some(value), _) => format!("StopReason({}, {})", self.kind, value),
+            (None, Some(message)) => format!("StopReason({}, {:?})", self.kind, message),
+            (None, None) => format!("StopReason({})", self.kind),
+        }
+    }
+}
+
+impl PyStopReason {
+    pub fn new(reason: &StopReason, partial_iteration: bool) -> Self {
+        let (kind, value, message) = match reason {
+            StopReason::Saturated => ("saturated", None, None),
+            StopReason::IterationLimit(n) => ("iteration_limit", Some(*n as f64), None),
+            StopReason::NodeLimit(n) => ("node_limit", Some(*n as f64), None),
+            StopReason::TimeLimit(secs) => ("time_limit", Some(*secs), None),
+            StopReason::Other(message) => ("other", None, Some(message.clone())),
+        };
+        Self {
+            kind: kind.to_string(),
+            value,
+            message,
+            partial_iteration,
+        }
+    }
+}
+
+/// What a `run()` did, returned by it: why it stopped, how many iterations it
+/// ran, the e-graph's nodes and classes when it stopped, how many times each
+/// rule was applied (rules sharing a name counted together), the `metadata`
+/// of each rule by name (the first one's, for rules sharing a name), and the
+/// seconds it spent searching, applying and rebuilding in total.
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct PyRunResult {
+    #[pyo3(get)]
+    pub stop_reason: PyStopReason,
+    #[pyo3(get)]
+    pub iterations: usize,
+    #[pyo3(get)]
+    pub nodes: usize,
+    #[pyo3(get)]
+    pub classes: usize,
+    #[pyo3(get)]
+    pub applied: HashMap<String, usize>,
+    #[pyo3(get)]
+    pub metadata: HashMap<String, Py<PyDict>>,
+    #[pyo3(get)]
+    pub search_time: f64,
+    #[pyo3(get)]
+    pub apply_time: f64,
+    #[pyo3(get)]
+    pub rebuild_time: f64,
+    /// What each iteration did, for `to_json`.
+    pub per_iteration: Vec<IterationStats>,
+}
+
+/// One iteration of a run, as egg reported it.
+#[derive(Debug, Clone)]
+pub struct IterationStats {
+    nodes: usize,
+    classes: usize,
+    applied: HashMap<String, usize>,
+    search_time: f64,
+    apply_time: f64,
+    rebuild_time: f64,
+    total_time: f64,
+    rebuilds: usize,
+}
+
+impl IterationStats {
+    fn new(iteration: &Iteration<()>) -> Self {
+        Self {
+            nodes: iteration.egraph_nodes,
+            classes: iteration.egraph_classes,
+            applied: iteration
+                .applied
+                .iter()
+                .map(|(name, &count)| (name.to_string(), count))
+                .collect(),
+            search_time: iteration.search_time,
+            apply_time: iteration.apply_time,
+            rebuild_time: iteration.rebuild_time,
+            total_time: iteration.total_time,
+            rebuilds: iteration.n_rebuilds,
+        }
+    }
+
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let dict = PyDict::new(py);
+        dict.set_item("nodes", self.nodes)?;
+        dict.set_item("classes", self.classes)?;
+        dict.set_item("applied", self.applied.clone())?;
+        dict.set_item("search_time", self.search_time)?;
+        dict.set_item("apply_time", self.apply_time)?;
+        dict.set_item("rebuild_time", self.rebuild_time)?;
+        dict.set_item("total_time", self.total_time)?;
+        dict.set_item("rebuilds", self.rebuilds)?;
+        Ok(dict)
+    }
+}
+
+#[pymethods]
+impl PyRunResult {
+    fn __repr__(&self) -> String {
+        format!(
+            "RunResult({}, iterations={}, nodes={}, classes={})",
+            self.stop_reason.__repr__(),
+            self.iterations,
+            self.nodes,
+            self.classes
+        )
+    }
+
+    /// Write the result to the file at `path` as a JSON object with keys
+    /// sorted, under this schema (version 1; later versions only add keys):
+    /// - `"version"`: 1
+    /// - `"stop_reason"`: `{"kind", "value", "message", "partial_iteration"}`,
+    ///   as in `StopReason`, with `null` for a missing value or message
+    /// - `"iterations"`, `"nodes"`, `"classes"`, `"applied"`,
+    ///   `"search_time"`, `"apply_time"`, `"rebuild_time"`: as the attributes
+    /// - `"per_iteration"`: a list with, for each iteration in order, its
+    ///   `"nodes"` and `"classes"` when it started, its `"applied"` counts by
+    ///   rule name, its `"search_time"`, `"apply_time"`, `"rebuild_time"` and
+    ///   `"total_time"` in seconds, and the `"rebuilds"` it made
+    ///
+    /// `metadata` is left out, as it needn't be JSON-serializable.
+    fn to_json(&self, py: Python, path: PathBuf) -> PyResult<()> {
+        let reason = PyDict::new(py);
+        reason.set_item("kind", &self.stop_reason.kind)?;
+        reason.set_item("value", self.stop_reason.value)?;
+        reason.set_item("message", &self.stop_reason.message)?;
+        reason.set_item("partial_iteration", self.stop_reason.partial_iteration)?;
+        let per_iteration = self
+            .per_iteration
+            .iter()
+            .map(|iteration| iteration.to_dict(py))
+            .collect::<PyResult<Vec<_>>>()?;
+        let out = PyDict::new(py);
+        out.set_item("version", 1)?;
+        out.set_item("stop_reason", reason)?;
+        out.set_item("iterations", self.iterations)?;
+        out.set_item("nodes", self.nodes)?;
+        out.set_item("classes", self.classes)?;
+        out.set_item("applied", self.applied.clone())?;
+        out.set_item("search_time", self.search_time)?;
+        out.set_item("apply_time", self.apply_time)?;
+        out.set_item("rebuild_time", self.rebuild_time)?;
+        out.set_item("per_iteration", per_iteration)?;
+
+        let kwargs = PyDict::new(py);
+        kwargs.set_item("indent", 2)?;
+        kwargs.set_item("sort_keys", true)?;
+        let json: String = py
+            .import("json")?
+            .call_method("dumps", (out,), Some(kwargs))?
+            .extract()?;
+        std::fs::write(&path, json + "\n")
+            .map_err(|err| PyOSError::new_err(format!("{}: {}", path.display(), err)))
+    }
+}
+
+impl PyRunResult {
+    pub fn new(
+        stop_reason: PyStopReason,
+        iterations: &[Iteration<()>],
+        egraph: &EGraph<PythonNode, PythonAnalysis>,
+    ) -> Self {
+        let mut applied = HashMap::new();
+        for iteration in iterations {
+            for (name, count) in &iteration.applied {
+                *applied.entry(name.to_string()).or_default() += count;
+            }
+        }
+        let total = |time: fn(&Iteration<()>) -> f64| iterations.iter().map(time).sum();
+        Self {
+            stop_reason,
+            iterations: iterations.len(),
+            nodes: egraph.total_number_of_nodes(),
+            classes: egraph.number_of_classes(),
+            applied,
+            metadata: HashMap::new(),
+            search_time: total(|i| i.search_time),
+            apply_time: total(|i| i.apply_time),
+            rebuild_time: total(|i| i.rebuild_time),
+            per_iteration: iterations.iter().map(IterationStats::new).collect(),
+        }
+    }
+}
+
+/// The backoff parameters of a `(rewrites, config)` group passed to `run()`.
+///
+/// `config` may set `match_limit` and `ban_length`, overriding egg's defaults
+/// (1000 and 5) for the group's rules, and `ban=False` to never ban them.
+/// Bans last only for the run that made them: every run starts with no rule
+/// banned, so a speculative run on a `copy()` leaves nothing behind.
+#[derive(Debug, Clone, Copy)]
+pub struct GroupConfig {
+    match_limit: Option<usize>,
+    ban_length: Option<usize>,
+    ban: bool,
+}
+
+impl GroupConfig {
+    pub fn from_dict(config: &PyDict) -> PyResult<Self> {
+        let mut group = GroupConfig {
+            match_limit: None,
+            ban_length: None,
+            ban: true,
+        };
+        for (key, value) in config {
+            match key.extract::<&str>()? {
+                "match_limit" => group.match_limit = Some(value.extract()?),
+                "ban_length" => group.ban_length = Some(value.extract()?),
+                "ban" => group.ban = value.extract()?,
+                key => {
+                    return Err(PyValueError::new_err(format!(
+                        "Unknown rule group option {:?}",
+                        key
+                    )))
+                }
+            }
+        }
+        Ok(group)
+    }
+}
+
+/// The group config of each grouped rule, by rule name.
+pub type RuleGroups = Vec<(Symbol, GroupConfig)>;
+
+/// egg's default scheduler, with the parameters of each rule's group.
+pub fn backoff_scheduler(groups: &[(Symbol, GroupConfig)]) -> BackoffScheduler {
+    let mut scheduler = BackoffScheduler::default();
+    for &(name, group) in groups {
+        if let Some(limit) = group.match_limit {
+            scheduler = scheduler.rule_match_limit(name, limit);
+        }
+        if let Some(length) = group.ban_length {
+            scheduler = scheduler.rule_ban_length(name, length);
+        }
+        if !group.ban {
+            scheduler = scheduler.do_not_ban(name);
+        }
+    }
+    scheduler
+}
+
+/// Enforce `node_limit` between iterations instead of inside them.
+///
+/// egg checks the node limit after every rewrite it applies, so hitting it
+/// leaves an iteration partly applied. The runner is given no node limit of
+/// its own; this hook stops the run before the next iteration once the limit
+/// is exceeded, so the iteration that crossed it is always applied in full.
+pub fn with_node_limit(runner: PythonRunner, node_limit: usize) -> (PythonRunner, NodeLimitHit) {
+    let hit = NodeLimitHit(Rc::new(Cell::new(None)));
+    let flag = hit.0.clone();
+    let runner = runner.with_node_limit(usize::MAX).with_hook(move |runner| {
+        let size = runner.egraph.total_size();
+        if size > node_limit {
+            flag.set(Some(size));
+            Err("node limit".to_string())
+        } else {
+            Ok(())
+        }
+    });
+    (runner, hit)
+}
+
+/// Stop the run before the next iteration once the `on_union` callback, or
+/// other Python code egg called into, has raised; the exception is left
+/// with the callback's observer or the analysis.
+pub fn stop_on_callback_error(runner: PythonRunner) -> PythonRunner {
+    runner.with_hook(|runner| match &runner.egraph.analysis.on_union {
+        Some(observer) if observer.error.lock().unwrap().is_some() => {
+            Err("on_union raised an exception".to_string())
+        }
+        _ if runner.egraph.analysis.failed() => Err("a callback raised an exception".to_string()),
+        _ => Ok(()),
+    })
+}
+
+/// Stop the run before the next iteration once the budget of
+/// `run(apply_limit=...)` is spent. Applications are unions that changed the
+/// e-graph, as counted in `RunResult.applied`; once the budget is spent the
+/// rest of the iteration's matches are skipped, and the e-graph is rebuilt
+/// as after any iteration. With a budget matches are applied one at a time,
+/// which is slower.
+pub fn stop_at_apply_limit(runner: PythonRunner) -> PythonRunner {
+    runner.with_hook(|runner| match runner.egraph.analysis.apply_budget {
+        Some(0) => Err("apply_limit".to_string()),
+        _ => Ok(()),
+    })
+}
+
+/// Set by the [`with_node_limit`] hook when it stopped the run.
+pub struct NodeLimitHit(Rc<Cell<Option<usize>>>);
+
+impl NodeLimitHit {
+    /// The runner's stop reason, with our hook's stop reported as `NodeLimit`.
+    pub fn stop_reason(&self, runner: &PythonRunner) -> StopReason {
+        match (self.0.get(), runner.stop_reason.clone()) {
+            (Some(size), Some(StopReason::Other(_))) => StopReason::NodeLimit(size),
+            (_, reason) => reason.expect("runner has not stopped"),
+        }
+    }
+}
+
+/// Whether the run stopped partway through applying the last iteration.
+pub fn stopped_mid_apply(iterations: &[Iteration<()>]) -> bool {
+    iterations.last().is_some_and(|last| {
+        matches!(last.stop_reason, Some(StopReason::TimeLimit(_))) && !last.applied.is_empty()
+    })
+}
+
+/// Call the Python `hook(iteration)` at the start of every iteration, before
+/// searching. It may return `None` or a list whose items are terms to add or
+/// `(a, b)` pairs (terms or `Id`s) to union; the e-graph is rebuilt after.
+///
+/// The returned [`PythonHook`] records how many nodes each call added, and
+/// an exception raised by the hook stops the run and is re-raised by
+/// [`PythonHook::finish`].
+///
+/// The whole run holds the GIL, not just the hook: egg's `Runner` isn't
+/// `Send`, and leaf comparisons, analysis merges and Python appliers call
+/// into Python from inside its loop, so it can't go under `allow_threads`.
+pub fn with_python_hook(runner: PythonRunner, hook: PyObject) -> (PythonRunner, PythonHook) {
+    let record = PythonHook::default();
+    let nodes = record.nodes.clone();
+    let error = record.error.clone();
+    let runner = runner.with_hook(move |runner| {
+        let py = unsafe { Python::assume_gil_acquired() };
+        let before = runner.egraph.total_size();
+        let result = hook
+            .call1(py, (runner.iterations.len(),))
+            .and_then(|result| add_hook_terms(runner, result.as_ref(py)));
+        if let Err(err) = result {
+            *error.borrow_mut() = Some(err);
+            return Err("hook raised an exception".to_string());
+        }
+        runner.egraph.rebuild();
+        nodes
+            .borrow_mut()
+            .push(runner.egraph.total_size().saturating_sub(before));
+        Ok(())
+    });
+    (runner, record)
+}
+
+fn add_hook_terms(runner: &mut PythonRunner, result: &PyAny) -> PyResult<()> {
+    if result.is_none() {
+        return Ok(());
+    }
+    for item in result.downcast::<PyList>()? {
+        // only a plain tuple is a pair: NamedTuple terms are tuples too
+        match item.downcast::<PyTuple>() {
+            Ok(pair) if pair.get_type().is(item.py().get_type::<PyTuple>()) && pair.len() == 2 => {
+                let a = build_node(&mut runner.egraph, pair.get_item(0)?)?;
+                let b = build_node(&mut runner.egraph, pair.get_item(1)?)?;
+                observed_union(&mut runner.egraph, a, b);
+            }
+            _ => {
+                build_node(&mut runner.egraph, item)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What a [`with_python_hook`] hook did during a run.
+#[derive(Default)]
+pub struct PythonHook {
+    nodes: Rc<RefCell<Vec<usize>>>,
+    error: Rc<RefCell<Option<PyErr>>>,
+}
+
+impl PythonHook {
+    /// The nodes added by each call of the hook, or the exception it raised.
+    pub fn finish(self) -> PyResult<Vec<usize>> {
+        if let Some(err) = self.error.borrow_mut().take() {
+            return Err(err);
+        }
+        Ok(self.nodes.take())
+    }
+}
+
+/// Makes the Python object `run(until=...)`'s predicate is given for the
+/// runner's e-graph.
+pub type EGraphView = Rc<dyn Fn(Python, &EGraph<PythonNode, PythonAnalysis>) -> PyResult<PyObject>>;
+
+/// Call the Python `until(egraph)` before every iteration but the first, so
+/// after each rebuild, with the object `view` makes of the e-graph; the run
+/// stops with `StopReason::Other("until")` once it returns true. An exception
+/// raised by it stops the run and is re-raised by [`Until::finish`]. Making
+/// the object copies the e-graph, which is costly on large graphs.
+pub fn with_until(
+    runner: PythonRunner,
+    until: PyObject,
+    view: EGraphView,
+) -> (PythonRunner, Until) {
+    let record = Until::default();
+    let error = record.error.clone();
+    let runner = runner.with_hook(move |runner| {
+        if runner.iterations.is_empty() {
+            return Ok(());
+        }
+        let py = unsafe { Python::assume_gil_acquired() };
+        let done = view(py, &runner.egraph)
+            .and_then(|egraph| until.call1(py, (egraph,)))
+            .and_then(|done| done.as_ref(py).is_true());
+        match done {
+            Ok(true) => Err("until".to_string()),
+            Ok(false) => Ok(()),
+            Err(err) => {
+                *error.borrow_mut() = Some(err);
+                Err("until raised an exception".to_string())
+            }
+        }
+    });
+    (runner, record)
+}
+
+/// What a [`with_until`] predicate did during a run.
+#[derive(Default)]
+pub struct Until {
+    error: Rc<RefCell<Option<PyErr>>>,
+}
+
+impl Until {
+    /// Re-raise the exception the predicate raised, if it did.
+    pub fn finish(self) -> PyResult<()> {
+        match self.error.borrow_mut().take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A Python `logging` logger for `run()`'s progress, and the level to log at.
+#[derive(Clone)]
+pub struct RunLog {
+    pub log: PyObject,
+    pub level: i64,
+    /// The number of iterations already run, for the ordered runs made of
+    /// several runners.
+    pub first: usize,
+}
+
+impl RunLog {
+    fn emit(&self, iterations: usize, egraph: &EGraph<PythonNode, PythonAnalysis>) -> PyResult<()> {
+        Python::with_gil(|py| {
+            self.log.call_method1(
+                py,
+                "log",
+                (
+                    self.level,
+                    "iteration %d: %d nodes, %d classes",
+                    self.first + iterations,
+                    egraph.total_number_of_nodes(),
+                    egraph.number_of_classes(),
+                ),
+            )?;
+            Ok(())
+        })
+    }
+}
+
+/// Log the iteration count and the e-graph's size through `log` after every
+/// iteration. The runner only calls hooks before an iteration, so the last
+/// one is logged by [`RunLogger::finish`]; an exception raised by the logger
+/// stops the run and is re-raised there.
+pub fn with_logger(runner: PythonRunner, log: RunLog) -> (PythonRunner, RunLogger) {
+    let logger = RunLogger {
+        log: log.clone(),
+        error: Rc::default(),
+    };
+    let error = logger.error.clone();
+    let runner = runner.with_hook(move |runner| {
+        if runner.iterations.is_empty() {
+            return Ok(());
+        }
+        if let Err(err) = log.emit(runner.iterations.len(), &runner.egraph) {
+            *error.borrow_mut() = Some(err);
+            return Err("log raised an exception".to_string());
+        }
+        Ok(())
+    });
+    (runner, logger)
+}
+
+/// The logging of a [`with_logger`] run.
+pub struct RunLogger {
+    log: RunLog,
+    error: Rc<RefCell<Option<PyErr>>>,
+}
+
+impl RunLogger {
+    /// Log the run's last iteration, or re-raise the logger's exception.
+    pub fn finish(self, runner: &PythonRunner) -> PyResult<()> {
+        if let Some(err) = self.error.borrow_mut().take() {
+            return Err(err);
+        }
+        if runner.iterations.is_empty() {
+            return Ok(());
+        }
+        self.log.emit(runner.iterations.len(), &runner.egraph)
+    }
+}
@@ -1,7 +1,10 @@
-use egg::{EGraph, ENodeOrVar, Id, PatternAst};
-use pyo3::types::{PyTuple, PyType};
+use egg::{EGraph, ENodeOrVar, Id, Language, PatternAst, RecExpr, Subst, Var};
+use pyo3::exceptions::PyValueError;
+use pyo3::types::{PyDict, PyString, PyTuple, PyType};
 use pyo3::{basic::CompareOp, prelude::*};
 
+use std::collections::HashMap;
+
 use crate::{PyId, PyVar, PythonAnalysis, PythonNode};
 
 pub fn py_eq(a: &PyAny, b: impl ToPyObject) -> bool {
@@ -11,11 +14,98 @@ pub fn py_eq(a: &PyAny, b: impl ToPyObject) -> bool {
         .expect("Failed to extract bool")
 }
 
+/// Add `enode`, counting the insertion towards `insertion_stats`.
+fn add_node(egraph: &mut EGraph<PythonNode, PythonAnalysis>, enode: PythonNode) -> Id {
+    egraph.analysis.insertions += 1;
+    egraph.add(enode)
+}
+
+/// How deeply a term added from Python ([`build_node`], [`build_dict_node`],
+/// [`build_sexpr_node`]) may nest, and how many children each of its nodes may
+/// have; beyond either it raises `ValueError`, so a malformed or cyclic
+/// object can't overflow the stack or build an enormous node.
+#[derive(Debug, Clone, Copy)]
+pub struct BuildLimits {
+    pub depth: usize,
+    pub arity: usize,
+}
+
+impl Default for BuildLimits {
+    fn default() -> Self {
+        Self {
+            depth: 1_000,
+            arity: usize::MAX,
+        }
+    }
+}
+
+impl BuildLimits {
+    fn check_depth(&self, depth: usize) -> PyResult<()> {
+        if depth > self.depth {
+            return Err(PyValueError::new_err(format!(
+                "Term is nested more than max_depth={} deep",
+                self.depth
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_arity(&self, class: &PyType, arity: usize) -> PyResult<()> {
+        if arity > self.arity {
+            return Err(PyValueError::new_err(format!(
+                "{} node has {} children, more than max_arity={}",
+                class.name()?,
+                arity,
+                self.arity
+            )));
+        }
+        Ok(())
+    }
+}
+
 // TODO(kszucs): proper error handling
-pub fn build_node(egraph: &mut EGraph<PythonNode, PythonAnalysis>, expr: &PyAny) -> Id {
+pub fn build_node(egraph: &mut EGraph<PythonNode, PythonAnalysis>, expr: &PyAny) -> PyResult<Id> {
+    let id = build_node_at(egraph, expr, 1, true)?;
+    egraph.analysis.raise_pending()?;
+    Ok(id.unwrap())
+}
+
+/// The e-class of `expr` if all its enodes are already in the e-graph, found
+/// as [`build_node`] would add it but without adding anything.
+pub fn lookup_node(
+    egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+    expr: &PyAny,
+) -> PyResult<Option<Id>> {
+    let id = build_node_at(egraph, expr, 1, false)?;
+    egraph.analysis.raise_pending()?;
+    Ok(id)
+}
+
+/// Add `expr`'s enodes, or with `add` false, only look them up, giving
+/// `None` at the first one that is missing.
+fn build_node_at(
+    egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+    expr: &PyAny,
+    depth: usize,
+    add: bool,
+) -> PyResult<Option<Id>> {
+    let limits = egraph.analysis.build_limits;
+    limits.check_depth(depth)?;
+    let op =
+        |egraph: &mut EGraph<PythonNode, PythonAnalysis>, class: &PyType, children: Vec<&PyAny>| {
+            limits.check_arity(class, children.len())?;
+            let mut ids = Vec::with_capacity(children.len());
+            for child in children {
+                match build_node_at(egraph, child, depth + 1, add)? {
+                    Some(id) => ids.push(id),
+                    None => return Ok(None),
+                }
+            }
+            Ok(insert(egraph, PythonNode::op(class, ids), add))
+        };
     if let Ok(PyId(id)) = expr.extract() {
-        egraph.find(id)
-    } else if let Ok(PyVar(var)) = expr.extract() {
+        Ok(Some(egraph.find(id)))
+    } else if let Ok(PyVar(var, ..)) = expr.extract() {
         panic!("Can't add a var: {}", var)
     } else if let Ok(args) = expr.getattr("__egg_args__") {
         let args = args.downcast::<PyTuple>().unwrap();
@@ -24,36 +114,399 @@ pub fn build_node(egraph: &mut EGraph<PythonNode, PythonAnalysis>, expr: &PyAny)
         } else {
             expr.get_type()
         };
-        let enode = PythonNode::op(class, args.iter().map(|child| build_node(egraph, child)));
-        egraph.add(enode)
+        op(egraph, class, args.iter().collect())
     } else if let Ok(args) = expr.getattr("__match_args__") {
         let args = args.downcast::<PyTuple>().unwrap();
-        let class = expr.get_type();
-
-        //let children = args.iter().map(|arg| expr.getattr(arg).unwrap());
-        let enode = PythonNode::op(
-            class,
-            args.iter()
-                .map(|child| build_node(egraph, expr.getattr(child).unwrap())),
-        );
-        egraph.add(enode)
+        let children = args
+            .iter()
+            .map(|child| expr.getattr(child.downcast::<PyString>()?))
+            .collect::<PyResult<Vec<&PyAny>>>()?;
+        op(egraph, expr.get_type(), children)
     } else if let Ok(tuple) = expr.downcast::<PyTuple>() {
-        let enode = PythonNode::op(
-            expr.get_type(),
-            tuple.iter().map(|child| build_node(egraph, child)),
-        );
-        egraph.add(enode)
+        op(egraph, expr.get_type(), tuple.iter().collect())
+    } else {
+        let leaf = egraph.analysis.canonical_leaf(expr)?;
+        let leaf = PythonNode::leaf(leaf.as_ref(expr.py()));
+        Ok(insert(egraph, leaf, add))
+    }
+}
+
+fn insert(
+    egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+    enode: PythonNode,
+    add: bool,
+) -> Option<Id> {
+    match add {
+        true => Some(add_node(egraph, enode)),
+        false => egraph.lookup(enode),
+    }
+}
+
+/// Add `obj` as a leaf, even if it looks like a term, interned under the
+/// e-graph's `key`.
+pub fn add_leaf(egraph: &mut EGraph<PythonNode, PythonAnalysis>, obj: &PyAny) -> PyResult<Id> {
+    let leaf = egraph.analysis.canonical_leaf(obj)?;
+    Ok(add_node(egraph, PythonNode::leaf(leaf.as_ref(obj.py()))))
+}
+
+/// Build a node from plain `{"op": ..., "children": [...]}` dicts. `op` is either
+/// a node class or a key into `ops`; anything that isn't a dict is a leaf.
+pub fn build_dict_node(
+    egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+    expr: &PyAny,
+    ops: Option<&PyDict>,
+) -> PyResult<Id> {
+    let id = build_dict_node_at(egraph, expr, ops, 1)?;
+    egraph.analysis.raise_pending()?;
+    Ok(id)
+}
+
+fn build_dict_node_at(
+    egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+    expr: &PyAny,
+    ops: Option<&PyDict>,
+    depth: usize,
+) -> PyResult<Id> {
+    let limits = egraph.analysis.build_limits;
+    limits.check_depth(depth)?;
+    let dict = match expr.downcast::<PyDict>() {
+        Ok(dict) => dict,
+        Err(_) => return add_leaf(egraph, expr),
+    };
+    let op = dict
+        .get_item("op")
+        .ok_or_else(|| PyValueError::new_err("Node dict is missing \"op\""))?;
+    let class = if let Ok(class) = op.downcast::<PyType>() {
+        class
     } else {
-        egraph.add(PythonNode::leaf(expr))
+        ops.and_then(|ops| ops.get_item(op))
+            .ok_or_else(|| PyValueError::new_err(format!("Unknown op: {}", op)))?
+            .downcast::<PyType>()?
+    };
+    let children = match dict.get_item("children") {
+        Some(children) => children.iter()?.collect::<PyResult<Vec<&PyAny>>>()?,
+        None => vec![],
+    };
+    limits.check_arity(class, children.len())?;
+    let children = children
+        .into_iter()
+        .map(|child| build_dict_node_at(egraph, child, ops, depth + 1))
+        .collect::<PyResult<Vec<Id>>>()?;
+    Ok(add_node(egraph, PythonNode::op(class, children)))
+}
+
+enum Sexp {
+    Atom(String),
+    Quoted(String),
+    List(Vec<Sexp>),
+}
+
+/// Parse one s-expression: parenthesised lists, `"quoted"` strings and bare
+/// atoms, nested at most as deep as `limits` allow.
+fn parse_sexp(input: &str, limits: BuildLimits) -> PyResult<Sexp> {
+    fn parse(
+        chars: &mut std::iter::Peekable<std::str::Chars>,
+        limits: BuildLimits,
+        depth: usize,
+    ) -> PyResult<Sexp> {
+        limits.check_depth(depth)?;
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        match chars.next() {
+            None => Err(PyValueError::new_err("Unexpected end of s-expression")),
+            Some(')') => Err(PyValueError::new_err("Unexpected ')' in s-expression")),
+            Some('(') => {
+                let mut items = vec![];
+                loop {
+                    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                        chars.next();
+                    }
+                    if chars.peek() == Some(&')') {
+                        chars.next();
+                        return Ok(Sexp::List(items));
+                    }
+                    items.push(parse(chars, limits, depth + 1)?);
+                }
+            }
+            Some('"') => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(PyValueError::new_err("Unterminated string")),
+                        Some('"') => return Ok(Sexp::Quoted(text)),
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                    }
+                }
+            }
+            Some(c) => {
+                let mut atom = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    }
+                    atom.push(c);
+                    chars.next();
+                }
+                Ok(Sexp::Atom(atom))
+            }
+        }
+    }
+    let mut chars = input.chars().peekable();
+    let sexp = parse(&mut chars, limits, 1)?;
+    if chars.any(|c| !c.is_whitespace()) {
+        return Err(PyValueError::new_err(format!(
+            "Trailing input after s-expression: {}",
+            input
+        )));
+    }
+    Ok(sexp)
+}
+
+/// Parse an s-expression like `(Add x (Mul 2 y))` and add it.
+///
+/// The head of each list names an operator class in `ops`. Other atoms are
+/// leaves: integers and floats become numbers, anything else (or anything
+/// `"quoted"`) a string.
+pub fn build_sexpr_node(
+    egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+    py: Python,
+    input: &str,
+    ops: Option<&PyDict>,
+) -> PyResult<Id> {
+    fn build(
+        egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+        py: Python,
+        sexp: &Sexp,
+        ops: Option<&PyDict>,
+    ) -> PyResult<Id> {
+        let leaf: PyObject = match sexp {
+            Sexp::List(items) => {
+                let (op, children) = match items.split_first() {
+                    Some((Sexp::Atom(op), children)) => (op, children),
+                    _ => return Err(PyValueError::new_err("Lists must start with an operator")),
+                };
+                let class = ops
+                    .and_then(|ops| ops.get_item(op))
+                    .ok_or_else(|| PyValueError::new_err(format!("Unknown op: {}", op)))?
+                    .downcast::<PyType>()?;
+                egraph
+                    .analysis
+                    .build_limits
+                    .check_arity(class, children.len())?;
+                let children = children
+                    .iter()
+                    .map(|child| build(egraph, py, child, ops))
+                    .collect::<PyResult<Vec<Id>>>()?;
+                return Ok(add_node(egraph, PythonNode::op(class, children)));
+            }
+            Sexp::Quoted(text) => text.into_py(py),
+            Sexp::Atom(atom) => {
+                if let Ok(int) = atom.parse::<i64>() {
+                    int.into_py(py)
+                } else if let Ok(float) = atom.parse::<f64>() {
+                    float.into_py(py)
+                } else {
+                    atom.into_py(py)
+                }
+            }
+        };
+        add_leaf(egraph, leaf.as_ref(py))
+    }
+    let sexp = parse_sexp(input, egraph.analysis.build_limits)?;
+    let id = build(egraph, py, &sexp, ops)?;
+    egraph.analysis.raise_pending()?;
+    Ok(id)
+}
+
+/// Build a term from an egg-style node array of `(op, [child_indices])`
+/// pairs, each child index pointing at an earlier entry, and return the id of
+/// the last one. `op` is a node class, or for a leaf (no children) any leaf
+/// object.
+pub fn build_node_list(
+    egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+    nodes: &PyAny,
+) -> PyResult<Id> {
+    let mut ids: Vec<Id> = Vec::new();
+    for entry in nodes.iter()? {
+        let (op, children): (&PyAny, Vec<usize>) = entry?.extract()?;
+        let index = ids.len();
+        let children = children
+            .into_iter()
+            .map(|child| {
+                ids.get(child).copied().ok_or_else(|| {
+                    PyValueError::new_err(format!(
+                        "Node {} refers to node {}, which doesn't come before it",
+                        index, child
+                    ))
+                })
+            })
+            .collect::<PyResult<Vec<Id>>>()?;
+        let id = if let Ok(class) = op.downcast::<PyType>() {
+            egraph
+                .analysis
+                .build_limits
+                .check_arity(class, children.len())?;
+            add_node(egraph, PythonNode::op(class, children))
+        } else if children.is_empty() {
+            add_leaf(egraph, op)?
+        } else {
+            return Err(PyValueError::new_err(format!(
+                "Node {} has children but its op is not a class: {}",
+                index, op
+            )));
+        };
+        ids.push(id);
+    }
+    egraph.analysis.raise_pending()?;
+    ids.last()
+        .copied()
+        .ok_or_else(|| PyValueError::new_err("Can't build a term from an empty node list"))
+}
+
+/// Add the term `expr`, from another e-graph, interning its leaves under this
+/// one's `key`, and return the id of its root.
+pub fn add_rec_expr(
+    egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+    py: Python,
+    expr: &RecExpr<PythonNode>,
+) -> PyResult<Id> {
+    let mut ids: Vec<Id> = Vec::with_capacity(expr.as_ref().len());
+    for node in expr.as_ref() {
+        let node = if node.is_leaf() {
+            let leaf = egraph.analysis.canonical_leaf(node.class.as_ref(py))?;
+            PythonNode::leaf(leaf.as_ref(py))
+        } else {
+            node.clone().map_children(|child| ids[usize::from(child)])
+        };
+        ids.push(add_node(egraph, node));
+    }
+    egraph.analysis.raise_pending()?;
+    Ok(*ids.last().expect("a RecExpr has a root"))
+}
+
+/// Like egg's `EGraph::add_instantiation`, but adding the pattern's leaves as
+/// [`build_node`] would, under the e-graph's `key`.
+pub fn add_instantiation(
+    egraph: &mut EGraph<PythonNode, PythonAnalysis>,
+    pattern: &PatternAst<PythonNode>,
+    subst: &Subst,
+) -> PyResult<Id> {
+    let py = unsafe { Python::assume_gil_acquired() };
+    let mut ids: Vec<Id> = Vec::with_capacity(pattern.as_ref().len());
+    for node in pattern.as_ref() {
+        let id = match node {
+            ENodeOrVar::Var(var) => subst[*var],
+            ENodeOrVar::ENode(node) if node.is_leaf() => {
+                let leaf = egraph.analysis.canonical_leaf(node.class.as_ref(py))?;
+                egraph.add(PythonNode::leaf(leaf.as_ref(py)))
+            }
+            ENodeOrVar::ENode(node) => {
+                egraph.add(node.clone().map_children(|child| ids[usize::from(child)]))
+            }
+        };
+        ids.push(id);
+    }
+    Ok(*ids.last().expect("a pattern has a root"))
+}
+
+/// Map each class of `old` to the class of `new` holding the same terms.
+///
+/// Works bottom-up from the leaves: a class is matched once one of its enodes,
+/// with its children mapped, is found in `new`. Classes whose terms `new`
+/// doesn't contain are left out.
+pub fn match_classes(
+    old: &EGraph<PythonNode, PythonAnalysis>,
+    new: &EGraph<PythonNode, PythonAnalysis>,
+) -> HashMap<Id, Id> {
+    let mut matched: HashMap<Id, Id> = HashMap::new();
+    let mut did_something = true;
+    while did_something {
+        did_something = false;
+        for class in old.classes() {
+            if matched.contains_key(&class.id) {
+                continue;
+            }
+            let found = class.iter().find_map(|node| {
+                let mut node = node.clone();
+                for child in node.children_mut() {
+                    *child = *matched.get(&old.find(*child))?;
+                }
+                new.lookup(node)
+            });
+            if let Some(id) = found {
+                matched.insert(class.id, id);
+                did_something = true;
+            }
+        }
+    }
+    matched
+}
+
+/// Operator labels interned as small integers, numbered from 0 in the order
+/// they were first seen.
+#[derive(Debug, Clone, Default)]
+pub struct LabelInterner {
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+}
+
+impl LabelInterner {
+    pub fn intern(&mut self, label: &str) -> usize {
+        if let Some(&id) = self.ids.get(label) {
+            return id;
+        }
+        self.names.push(label.to_string());
+        self.ids.insert(label.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    pub fn name(&self, id: usize) -> Option<&str> {
+        self.names.get(id).map(String::as_str)
     }
 }
 
 // TODO(kszucs): proper error handling
-pub fn build_pattern(ast: &mut PatternAst<PythonNode>, tree: &PyAny) -> Id {
+/// Add `tree` to `ast` as a pattern, recording the kinds of its kinded vars
+/// in `kinds`. Each occurrence of a structural var after its first is added
+/// as a var of its own, named with `#2`, `#3` and so on, and recorded in
+/// `structural` as `(copy, var)`. `tree` is `depth` deep in the whole
+/// pattern, which must keep to `limits`.
+pub fn build_pattern(
+    ast: &mut PatternAst<PythonNode>,
+    kinds: &mut Vec<(Var, PyObject)>,
+    structural: &mut Vec<(Var, Var)>,
+    tree: &PyAny,
+    limits: BuildLimits,
+    depth: usize,
+) -> PyResult<Id> {
+    limits.check_depth(depth)?;
+    let mut op = |class: &PyType, children: Vec<&PyAny>| {
+        limits.check_arity(class, children.len())?;
+        let children = children
+            .into_iter()
+            .map(|child| build_pattern(ast, kinds, structural, child, limits, depth + 1))
+            .collect::<PyResult<Vec<Id>>>()?;
+        Ok(ast.add(ENodeOrVar::ENode(PythonNode::op(class, children))))
+    };
     if let Ok(id) = tree.extract::<PyId>() {
         panic!("Ids are unsupported in patterns: {}", id.0)
-    } else if let Ok(var) = tree.extract::<PyVar>() {
-        ast.add(ENodeOrVar::Var(var.0))
+    } else if let Ok(PyVar(var, kind, is_structural)) = tree.extract() {
+        let var = if is_structural && ast.as_ref().contains(&ENodeOrVar::Var(var)) {
+            let n = structural.iter().filter(|(_, of)| *of == var).count() + 2;
+            let copy = format!("{}#{}", var, n).parse().unwrap();
+            structural.push((copy, var));
+            copy
+        } else {
+            var
+        };
+        if let Some(kind) = kind {
+            if !kinds.iter().any(|(v, k)| *v == var && k.is(&kind)) {
+                kinds.push((var, kind));
+            }
+        }
+        Ok(ast.add(ENodeOrVar::Var(var)))
     // check for Sequence first?
     } else if let Ok(args) = tree.getattr("__egg_args__") {
         let args = args.downcast::<PyTuple>().unwrap();
@@ -62,26 +515,17 @@ pub fn build_pattern(ast: &mut PatternAst<PythonNode>, tree: &PyAny) -> Id {
         } else {
             tree.get_type()
         };
-        let enode = PythonNode::op(class, args.iter().map(|child| build_pattern(ast, child)));
-        ast.add(ENodeOrVar::ENode(enode))
+        op(class, args.iter().collect())
     } else if let Ok(args) = tree.getattr("__match_args__") {
         let args = args.downcast::<PyTuple>().unwrap();
-        let class = tree.get_type();
-
-        //let children = args.iter().map(|arg| tree.getattr(arg).unwrap());
-        let enode = PythonNode::op(
-            class,
-            args.iter()
-                .map(|child| build_pattern(ast, tree.getattr(child).unwrap())),
-        );
-        ast.add(ENodeOrVar::ENode(enode))
+        let children = args
+            .iter()
+            .map(|child| tree.getattr(child.downcast::<PyString>()?))
+            .collect::<PyResult<Vec<&PyAny>>>()?;
+        op(tree.get_type(), children)
     } else if let Ok(tuple) = tree.downcast::<PyTuple>() {
-        let enode = PythonNode::op(
-            tree.get_type(),
-            tuple.iter().map(|child| build_pattern(ast, child)),
-        );
-        ast.add(ENodeOrVar::ENode(enode))
+        op(tree.get_type(), tuple.iter().collect())
     } else {
-        ast.add(ENodeOrVar::ENode(PythonNode::leaf(tree)))
+        Ok(ast.add(ENodeOrVar::ENode(PythonNode::leaf(tree))))
     }
 }